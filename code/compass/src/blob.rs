@@ -0,0 +1,190 @@
+use crate::deviation::DEVIATION_ENTRIES;
+
+/*
+    Calibration backup format, all multi-byte values little-endian:
+
+    magic "TNCB" | version u8 | payload length u16 | payload | CRC-32 (IEEE) u32
+
+    The CRC covers everything before it. Version 1 payload:
+
+    name            length u8 + UTF-8 bytes
+    min, max        3 x f32 each
+    offset          count u8 + count x f32 (0 or 3)
+    soft_iron       count u8 + count x f32 (0 or 9)
+    mounting offset f32
+    deviation       count u8 + count x f32 (0 or 24)
+    calibration     mode u8
+    sensor          gain u8, resolution x/y/z u8, filter u8, oversampling u8
+*/
+
+pub const CALIBRATION_BLOB_MAGIC: [u8; 4] = *b"TNCB";
+pub const CALIBRATION_BLOB_VERSION: u8 = 1;
+
+const HEADER_LEN: usize = 7;
+const CRC_LEN: usize = 4;
+
+/// Sensor configuration as raw register codes, kept with a calibration backup.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SensorSettings {
+    pub gain: u8,
+    pub resolution: [u8; 3],
+    pub filter: u8,
+    pub oversampling: u8,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationBlob {
+    pub name: String,
+    pub min: [f32; 3],
+    pub max: [f32; 3],
+    pub offset: Vec<f32>,
+    pub soft_iron: Vec<f32>,
+    pub mounting_offset: f32,
+    pub deviation: Vec<f32>,
+    pub calibration_mode: u8,
+    pub sensor: SensorSettings,
+}
+
+impl CalibrationBlob {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+
+        // Longer names are cut on a character boundary so they still decode.
+        let mut name_len = self.name.len().min(u8::MAX as usize);
+        while !self.name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        payload.push(name_len as u8);
+        payload.extend_from_slice(&self.name.as_bytes()[..name_len]);
+
+        for value in self.min.iter().chain(self.max.iter()) {
+            payload.extend_from_slice(&value.to_le_bytes());
+        }
+
+        push_f32_list(&mut payload, &self.offset);
+        push_f32_list(&mut payload, &self.soft_iron);
+        payload.extend_from_slice(&self.mounting_offset.to_le_bytes());
+        push_f32_list(&mut payload, &self.deviation);
+
+        payload.push(self.calibration_mode);
+        payload.push(self.sensor.gain);
+        payload.extend_from_slice(&self.sensor.resolution);
+        payload.push(self.sensor.filter);
+        payload.push(self.sensor.oversampling);
+
+        let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+        bytes.extend_from_slice(&CALIBRATION_BLOB_MAGIC);
+        bytes.push(CALIBRATION_BLOB_VERSION);
+        bytes.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes.extend_from_slice(&crc32(&bytes).to_le_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        if bytes.len() < HEADER_LEN + CRC_LEN || bytes[0..4] != CALIBRATION_BLOB_MAGIC {
+            return Err(invalid("not a calibration blob"));
+        }
+
+        if bytes[4] != CALIBRATION_BLOB_VERSION {
+            return Err(invalid(&format!("unsupported version {}", bytes[4])));
+        }
+
+        let length = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+        if bytes.len() != HEADER_LEN + length + CRC_LEN {
+            return Err(invalid("wrong length"));
+        }
+
+        let (content, crc) = bytes.split_at(HEADER_LEN + length);
+        if crc32(content) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err(invalid("CRC mismatch"));
+        }
+
+        let mut reader = BlobReader { data: &content[HEADER_LEN..], position: 0 };
+
+        let name_len = reader.u8()? as usize;
+        let name = String::from_utf8(reader.bytes(name_len)?.to_vec()).map_err(|_| invalid("name is not UTF-8"))?;
+        let min = [reader.f32()?, reader.f32()?, reader.f32()?];
+        let max = [reader.f32()?, reader.f32()?, reader.f32()?];
+        let offset = reader.f32_list()?;
+        let soft_iron = reader.f32_list()?;
+        let mounting_offset = reader.f32()?;
+        let deviation = reader.f32_list()?;
+        let calibration_mode = reader.u8()?;
+        let sensor = SensorSettings {
+            gain: reader.u8()?,
+            resolution: [reader.u8()?, reader.u8()?, reader.u8()?],
+            filter: reader.u8()?,
+            oversampling: reader.u8()?,
+        };
+
+        if (!offset.is_empty() && offset.len() != 3) || (!soft_iron.is_empty() && soft_iron.len() != 9) {
+            return Err(invalid("malformed ellipsoid fit"));
+        }
+
+        if !deviation.is_empty() && deviation.len() != DEVIATION_ENTRIES {
+            return Err(invalid("malformed deviation card"));
+        }
+
+        // NaN marks a deviation card entry not measured yet, every other value must be a number.
+        let values = min.iter().chain(max.iter()).chain(offset.iter()).chain(soft_iron.iter()).chain(std::iter::once(&mounting_offset));
+        if values.chain(deviation.iter().filter(|entry| !entry.is_nan())).any(|value| !value.is_finite()) {
+            return Err(invalid("value out of range"));
+        }
+
+        Ok(Self { name, min, max, offset, soft_iron, mounting_offset, deviation, calibration_mode, sensor })
+    }
+}
+
+struct BlobReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BlobReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        if self.position + len > self.data.len() {
+            return Err(invalid("truncated payload"));
+        }
+        let bytes = &self.data[self.position..self.position + len];
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, Box<dyn std::error::Error>> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn f32(&mut self) -> Result<f32, Box<dyn std::error::Error>> {
+        let bytes = self.bytes(4)?;
+        Ok(f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn f32_list(&mut self) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+        let count = self.u8()? as usize;
+        (0..count).map(|_| self.f32()).collect()
+    }
+}
+
+fn push_f32_list(bytes: &mut Vec<u8>, values: &[f32]) {
+    bytes.push(values.len() as u8);
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn invalid(message: &str) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Calibration blob: {}", message)))
+}
+
+/// CRC-32 (IEEE 802.3), the same as zlib so backups can be checked on a PC.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
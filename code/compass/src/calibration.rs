@@ -0,0 +1,682 @@
+use std::time::{Duration, Instant};
+
+use crate::math::{matrix_mul_vector, solve_linear_system, symmetric_eigen, Matrix3, Vector3};
+
+// Minimum number of samples before trying an ellipsoid fit. The model has 9 unknowns,
+// this keeps the normal equations well conditioned.
+pub const MIN_FIT_SAMPLES: usize = 50;
+
+const MAX_CALIBRATION_SAMPLES: usize = 1000;
+const MIN_CALIBRATION_SAMPLE_DISTANCE: f32 = 2.0;
+
+// Calibration progress is reported every time this many new samples are collected.
+const CALIBRATION_PROGRESS_SAMPLES: usize = 20;
+
+// Calibration ends by itself once both targets are reached.
+const CALIBRATION_TARGET_COVERAGE: f32 = 0.9;
+const CALIBRATION_TARGET_SCORE: u8 = 85;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationMode {
+    MinMax,
+    Ellipsoid,
+}
+
+impl From<CalibrationMode> for u8 {
+    fn from(mode: CalibrationMode) -> Self {
+        mode as u8
+    }
+}
+
+impl From<u8> for CalibrationMode {
+    fn from(mode: u8) -> Self {
+        match mode {
+            0x01 => CalibrationMode::Ellipsoid,
+            _ => CalibrationMode::MinMax,
+        }
+    }
+}
+
+/// Result of a least-squares ellipsoid fit. Corrected readings are
+/// `soft_iron * (raw - offset)`, which maps the ellipsoid back to a sphere of
+/// `radius` µT.
+#[derive(Debug, Clone, Copy)]
+pub struct EllipsoidFit {
+    pub offset: Vector3,
+    pub soft_iron: Matrix3,
+    pub radius: f32,
+    pub residual: f32,
+}
+
+impl EllipsoidFit {
+    /// Rebuilds a fit from the persisted offset and row-major matrix.
+    pub fn from_parameters(offset: &[f32], soft_iron: &[f32]) -> Option<Self> {
+        if offset.len() != 3 || soft_iron.len() != 9 {
+            return None;
+        }
+
+        Some(Self {
+            offset: Vector3::new(offset[0], offset[1], offset[2]),
+            soft_iron: [
+                [soft_iron[0], soft_iron[1], soft_iron[2]],
+                [soft_iron[3], soft_iron[4], soft_iron[5]],
+                [soft_iron[6], soft_iron[7], soft_iron[8]],
+            ],
+            radius: 0.0,
+            residual: 0.0,
+        })
+    }
+
+    pub fn offset_parameters(&self) -> Vec<f32> {
+        vec![self.offset.x, self.offset.y, self.offset.z]
+    }
+
+    pub fn soft_iron_parameters(&self) -> Vec<f32> {
+        self.soft_iron.iter().flatten().copied().collect()
+    }
+
+    pub fn apply(&self, raw: Vector3) -> Vector3 {
+        matrix_mul_vector(
+            &self.soft_iron,
+            Vector3::new(raw.x - self.offset.x, raw.y - self.offset.y, raw.z - self.offset.z),
+        )
+    }
+
+    /// Raw offset that removes `residual`, an offset left in the corrected readings:
+    /// `offset + soft_iron⁻¹ · residual`. `None` when the matrix is singular.
+    pub fn raw_offset(&self, residual: Vector3) -> Option<Vector3> {
+        let matrix = self.soft_iron.iter().map(|row| row.iter().map(|value| *value as f64).collect()).collect();
+        let shift = solve_linear_system(matrix, vec![residual.x as f64, residual.y as f64, residual.z as f64])?;
+
+        Some(Vector3::new(
+            self.offset.x + shift[0] as f32,
+            self.offset.y + shift[1] as f32,
+            self.offset.z + shift[2] as f32,
+        ))
+    }
+}
+
+/// Correction a profile applies to the raw field: the ellipsoid fit when it is the
+/// selected mode and one is stored, otherwise the center of the min/max box.
+#[derive(Debug, Clone, Copy)]
+pub enum HardIronCorrection {
+    MinMax(Vector3),
+    Ellipsoid(EllipsoidFit),
+}
+
+impl HardIronCorrection {
+    /// `None` while the profile has neither a fit nor a min/max box.
+    pub fn select(mode: CalibrationMode, fit: Option<EllipsoidFit>, min: Vector3, max: Vector3) -> Option<Self> {
+        if let (CalibrationMode::Ellipsoid, Some(fit)) = (mode, fit) {
+            return Some(HardIronCorrection::Ellipsoid(fit));
+        }
+
+        if max.x < min.x || max.y < min.y || max.z < min.z {
+            return None;
+        }
+
+        Some(HardIronCorrection::MinMax(Vector3::new((max.x + min.x) / 2.0, (max.y + min.y) / 2.0, (max.z + min.z) / 2.0)))
+    }
+
+    /// Hard-iron offset in µT.
+    pub fn offset(&self) -> Vector3 {
+        match self {
+            HardIronCorrection::MinMax(offset) => *offset,
+            HardIronCorrection::Ellipsoid(fit) => fit.offset,
+        }
+    }
+
+    pub fn apply(&self, raw: Vector3) -> Vector3 {
+        match self {
+            HardIronCorrection::MinMax(offset) => Vector3::new(raw.x - offset.x, raw.y - offset.y, raw.z - offset.z),
+            HardIronCorrection::Ellipsoid(fit) => fit.apply(raw),
+        }
+    }
+
+    /// Correction of a reading the hard-iron offset was already removed from, only the
+    /// soft-iron matrix is left to apply.
+    pub fn apply_centered(&self, centered: Vector3) -> Vector3 {
+        match self {
+            HardIronCorrection::MinMax(_) => centered,
+            HardIronCorrection::Ellipsoid(fit) => matrix_mul_vector(&fit.soft_iron, centered),
+        }
+    }
+}
+
+/// Fits the general ellipsoid `ax² + by² + cz² + 2dxy + 2exz + 2fyz + 2gx + 2hy + 2iz = 1`
+/// to the samples and derives the hard-iron offset and soft-iron correction matrix.
+pub fn fit_ellipsoid(samples: &[Vector3]) -> Option<EllipsoidFit> {
+    if samples.len() < MIN_FIT_SAMPLES {
+        return None;
+    }
+
+    // Normal equations (DᵀD)v = Dᵀ1
+    let mut dtd = vec![vec![0.0f64; 9]; 9];
+    let mut dt1 = vec![0.0f64; 9];
+
+    for sample in samples {
+        let (x, y, z) = (sample.x as f64, sample.y as f64, sample.z as f64);
+        let row = [x * x, y * y, z * z, 2.0 * x * y, 2.0 * x * z, 2.0 * y * z, 2.0 * x, 2.0 * y, 2.0 * z];
+        for i in 0..9 {
+            for j in 0..9 {
+                dtd[i][j] += row[i] * row[j];
+            }
+            dt1[i] += row[i];
+        }
+    }
+
+    let v = solve_linear_system(dtd, dt1)?;
+
+    let a = [[v[0], v[3], v[4]], [v[3], v[1], v[5]], [v[4], v[5], v[2]]];
+    let b = [v[6], v[7], v[8]];
+
+    // Center of the ellipsoid: A·c = -b
+    let center = solve_linear_system(a.iter().map(|row| row.to_vec()).collect(), b.iter().map(|value| -value).collect())?;
+
+    // (x - c)ᵀ A (x - c) = 1 + cᵀ A c
+    let mut scale = 1.0;
+    for i in 0..3 {
+        for j in 0..3 {
+            scale += center[i] * a[i][j] * center[j];
+        }
+    }
+    if scale <= 0.0 {
+        return None;
+    }
+
+    let normalized = a.map(|row| row.map(|value| value / scale));
+    let (eigenvalues, eigenvectors) = symmetric_eigen(normalized);
+    if eigenvalues.iter().any(|value| *value <= 0.0) {
+        return None;
+    }
+
+    // Keep the output in µT by mapping to a sphere whose radius is the
+    // geometric mean of the semi-axes.
+    let radius = eigenvalues.iter().map(|value| 1.0 / value.sqrt()).product::<f64>().cbrt();
+
+    let mut soft_iron = [[0.0f32; 3]; 3];
+    for (i, row) in soft_iron.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            let mut sum = 0.0;
+            for k in 0..3 {
+                sum += eigenvectors[i][k] * eigenvalues[k].sqrt() * eigenvectors[j][k];
+            }
+            *value = (sum * radius) as f32;
+        }
+    }
+
+    let mut fit = EllipsoidFit {
+        offset: Vector3::new(center[0] as f32, center[1] as f32, center[2] as f32),
+        soft_iron,
+        radius: radius as f32,
+        residual: 0.0,
+    };
+
+    fit.residual = fit_residual(&fit, samples);
+
+    Some(fit)
+}
+
+/// RMS of the relative distance of the corrected samples to the fitted sphere.
+pub fn fit_residual(fit: &EllipsoidFit, samples: &[Vector3]) -> f32 {
+    if samples.is_empty() || fit.radius <= 0.0 {
+        return 0.0;
+    }
+
+    let sum: f32 = samples
+        .iter()
+        .map(|sample| {
+            let corrected = fit.apply(*sample);
+            let error = corrected.magnitude() / fit.radius - 1.0;
+            error * error
+        })
+        .sum();
+
+    (sum / samples.len() as f32).sqrt()
+}
+
+// Orientation coverage buckets. Elevation bands are split on sin(elevation), so every
+// bucket covers the same area of the sphere.
+const COVERAGE_AZIMUTH_BUCKETS: usize = 8;
+const COVERAGE_ELEVATION_BUCKETS: usize = 4;
+
+// Residual and magnitude spread at which their part of the score drops to zero.
+const SCORE_MAX_RESIDUAL: f32 = 0.1;
+const SCORE_MAX_MAGNITUDE_SPREAD: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CalibrationQuality {
+    pub samples: u32,
+    /// Fraction (0-1) of the orientation buckets that received at least one sample.
+    pub coverage: f32,
+    /// Ellipsoid fit residual, 0 when there is no fit yet.
+    pub residual: f32,
+    /// Standard deviation of the corrected field magnitude relative to its mean.
+    pub magnitude_spread: f32,
+    /// Overall quality, 0-100.
+    pub score: u8,
+    /// Raw samples dropped by the outlier gate.
+    pub rejected: u32,
+}
+
+impl CalibrationQuality {
+    /// Evaluates the samples collected so far. Without a fit the min/max box center
+    /// is used as the hard-iron estimate.
+    pub fn assess(samples: &[Vector3], fit: Option<&EllipsoidFit>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+
+        let corrected: Vec<Vector3> = match fit {
+            Some(fit) => samples.iter().map(|sample| fit.apply(*sample)).collect(),
+            None => {
+                let center = box_center(samples);
+                samples
+                    .iter()
+                    .map(|sample| Vector3::new(sample.x - center.x, sample.y - center.y, sample.z - center.z))
+                    .collect()
+            }
+        };
+
+        let mut buckets = [false; COVERAGE_AZIMUTH_BUCKETS * COVERAGE_ELEVATION_BUCKETS];
+        for sample in corrected.iter() {
+            if let Some(bucket) = orientation_bucket(*sample) {
+                buckets[bucket] = true;
+            }
+        }
+        let coverage = buckets.iter().filter(|hit| **hit).count() as f32 / buckets.len() as f32;
+
+        let magnitudes: Vec<f32> = corrected.iter().map(|sample| sample.magnitude()).collect();
+        let mean = magnitudes.iter().sum::<f32>() / magnitudes.len() as f32;
+        let variance = magnitudes.iter().map(|value| (value - mean) * (value - mean)).sum::<f32>() / magnitudes.len() as f32;
+        let magnitude_spread = if mean > 0.0 { variance.sqrt() / mean } else { 0.0 };
+
+        let residual = fit.map(|fit| fit.residual).unwrap_or(0.0);
+
+        let score = 60.0 * coverage
+            + 20.0 * (1.0 - residual / SCORE_MAX_RESIDUAL).clamp(0.0, 1.0)
+            + 20.0 * (1.0 - magnitude_spread / SCORE_MAX_MAGNITUDE_SPREAD).clamp(0.0, 1.0);
+
+        Self {
+            samples: samples.len() as u32,
+            coverage,
+            residual,
+            magnitude_spread,
+            score: score.round().clamp(0.0, 100.0) as u8,
+            rejected: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum CalibrationStatus {
+    #[default]
+    Idle,
+    Running(CalibrationQuality),
+    Finished(CalibrationQuality),
+    Cancelled,
+}
+
+impl CalibrationStatus {
+    /// BLE payload: state, score, coverage (%), samples (u16), residual (f32),
+    /// magnitude spread (f32), rejected samples (u16). Multi-byte values are little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (state, quality) = match self {
+            CalibrationStatus::Idle => (0x00u8, CalibrationQuality::default()),
+            CalibrationStatus::Running(quality) => (0x01, *quality),
+            CalibrationStatus::Finished(quality) => (0x02, *quality),
+            CalibrationStatus::Cancelled => (0x03, CalibrationQuality::default()),
+        };
+
+        let mut bytes = vec![state, quality.score, (quality.coverage * 100.0).round() as u8];
+        bytes.extend_from_slice(&(quality.samples.min(u16::MAX as u32) as u16).to_le_bytes());
+        bytes.extend_from_slice(&quality.residual.to_le_bytes());
+        bytes.extend_from_slice(&quality.magnitude_spread.to_le_bytes());
+        bytes.extend_from_slice(&(quality.rejected.min(u16::MAX as u32) as u16).to_le_bytes());
+        bytes
+    }
+}
+
+fn box_center(samples: &[Vector3]) -> Vector3 {
+    let mut min = Vector3::new(f32::MAX, f32::MAX, f32::MAX);
+    let mut max = Vector3::new(f32::MIN, f32::MIN, f32::MIN);
+
+    for sample in samples {
+        min = Vector3::new(min.x.min(sample.x), min.y.min(sample.y), min.z.min(sample.z));
+        max = Vector3::new(max.x.max(sample.x), max.y.max(sample.y), max.z.max(sample.z));
+    }
+
+    Vector3::new((max.x + min.x) / 2.0, (max.y + min.y) / 2.0, (max.z + min.z) / 2.0)
+}
+
+fn orientation_bucket(direction: Vector3) -> Option<usize> {
+    let magnitude = direction.magnitude();
+    if magnitude <= 0.0 {
+        return None;
+    }
+
+    let azimuth = direction.y.atan2(direction.x) + std::f32::consts::PI;
+    let azimuth_bucket = ((azimuth / (2.0 * std::f32::consts::PI)) * COVERAGE_AZIMUTH_BUCKETS as f32) as usize;
+
+    let elevation = (direction.z / magnitude + 1.0) / 2.0;
+    let elevation_bucket = (elevation * COVERAGE_ELEVATION_BUCKETS as f32) as usize;
+
+    Some(
+        elevation_bucket.min(COVERAGE_ELEVATION_BUCKETS - 1) * COVERAGE_AZIMUTH_BUCKETS
+            + azimuth_bucket.min(COVERAGE_AZIMUTH_BUCKETS - 1),
+    )
+}
+
+// Calibration outlier gate. A raw sample is rejected when its field magnitude is too far
+// from the median of the last accepted samples, or when it jumps too far from the previous one.
+const OUTLIER_WINDOW: usize = 25;
+const OUTLIER_MIN_WINDOW: usize = 5;
+// Gate in standard deviations, estimated as 1.4826 * MAD.
+const OUTLIER_MAD_GATE: f32 = 4.0;
+// Deviation relative to the median always accepted, a still device has almost no spread.
+const OUTLIER_MIN_DEVIATION: f32 = 0.1;
+// µT between consecutive samples, far more than a fast hand rotation at the calibration rate.
+const OUTLIER_MAX_JUMP: f32 = 30.0;
+// After this many rejections in a row the field is assumed to have really changed.
+const OUTLIER_MAX_CONSECUTIVE: u32 = 10;
+
+#[derive(Debug, Default)]
+pub struct OutlierGate {
+    magnitudes: Vec<f32>,
+    last: Option<Vector3>,
+    consecutive: u32,
+    rejected_magnitude: u32,
+    rejected_jump: u32,
+}
+
+impl OutlierGate {
+    /// Returns false if `sample` looks like a glitch and must not be used.
+    pub fn accept(&mut self, sample: Vector3) -> bool {
+        let magnitude = sample.magnitude();
+
+        let jump = self
+            .last
+            .map(|last| Vector3::new(sample.x - last.x, sample.y - last.y, sample.z - last.z).magnitude() > OUTLIER_MAX_JUMP)
+            .unwrap_or(false);
+
+        let deviates = self.magnitudes.len() >= OUTLIER_MIN_WINDOW && {
+            let median = median(&self.magnitudes);
+            let mad = median_absolute_deviation(&self.magnitudes, median);
+            let gate = (OUTLIER_MAD_GATE * 1.4826 * mad).max(OUTLIER_MIN_DEVIATION * median);
+            (magnitude - median).abs() > gate
+        };
+
+        if (jump || deviates) && self.consecutive < OUTLIER_MAX_CONSECUTIVE {
+            self.consecutive += 1;
+            if jump {
+                self.rejected_jump += 1;
+            } else {
+                self.rejected_magnitude += 1;
+            }
+            return false;
+        }
+
+        // A long run of rejections means the field moved for good, start over from here.
+        if self.consecutive >= OUTLIER_MAX_CONSECUTIVE {
+            log::debug!("Calibration: outlier gate restarted after {} rejections", self.consecutive);
+            self.magnitudes.clear();
+        }
+
+        self.consecutive = 0;
+        self.last = Some(sample);
+        self.magnitudes.push(magnitude);
+        if self.magnitudes.len() > OUTLIER_WINDOW {
+            self.magnitudes.remove(0);
+        }
+        true
+    }
+
+    pub fn rejected(&self) -> u32 {
+        self.rejected_magnitude + self.rejected_jump
+    }
+
+    pub fn rejected_magnitude(&self) -> u32 {
+        self.rejected_magnitude
+    }
+
+    pub fn rejected_jump(&self) -> u32 {
+        self.rejected_jump
+    }
+}
+
+fn median(values: &[f32]) -> f32 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let middle = sorted.len() / 2;
+    if sorted.len() % 2 == 0 {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+fn median_absolute_deviation(values: &[f32], median_value: f32) -> f32 {
+    let deviations: Vec<f32> = values.iter().map(|value| (value - median_value).abs()).collect();
+    median(&deviations)
+}
+
+/// A running calibration. Nothing is written to the stored calibration until the
+/// session is finished, so cancelling it leaves the previous values untouched.
+pub struct CalibrationSession {
+    pub started: Instant,
+    pub timeout: Duration,
+    pub min: Vector3,
+    pub max: Vector3,
+    pub samples: Vec<Vector3>,
+    pub quality: CalibrationQuality,
+    pub outliers: OutlierGate,
+}
+
+impl CalibrationSession {
+    pub fn new(timeout: Duration, min: Vector3, max: Vector3) -> Self {
+        Self {
+            started: Instant::now(),
+            timeout,
+            min,
+            max,
+            samples: Vec::new(),
+            quality: CalibrationQuality::default(),
+            outliers: OutlierGate::default(),
+        }
+    }
+
+    /// Extends the min/max box, returns true if it changed.
+    pub fn update_bounds(&mut self, sample: Vector3) -> bool {
+        let min = Vector3::new(self.min.x.min(sample.x), self.min.y.min(sample.y), self.min.z.min(sample.z));
+        let max = Vector3::new(self.max.x.max(sample.x), self.max.y.max(sample.y), self.max.z.max(sample.z));
+
+        let changed = min.x != self.min.x
+            || min.y != self.min.y
+            || min.z != self.min.z
+            || max.x != self.max.x
+            || max.y != self.max.y
+            || max.z != self.max.z;
+
+        self.min = min;
+        self.max = max;
+        changed
+    }
+
+    /// Keeps a sample for the ellipsoid fit. Samples too close to the previous one
+    /// are dropped, so holding the device still does not bias the fit.
+    pub fn add_sample(&mut self, sample: Vector3) -> bool {
+        if self.samples.len() >= MAX_CALIBRATION_SAMPLES {
+            return false;
+        }
+
+        if let Some(last) = self.samples.last() {
+            let distance = Vector3::new(sample.x - last.x, sample.y - last.y, sample.z - last.z).magnitude();
+            if distance < MIN_CALIBRATION_SAMPLE_DISTANCE {
+                return false;
+            }
+        }
+
+        self.samples.push(sample);
+        true
+    }
+
+    /// True when a new progress report is due.
+    pub fn progress_due(&self) -> bool {
+        !self.samples.is_empty() && self.samples.len() % CALIBRATION_PROGRESS_SAMPLES == 0
+    }
+
+    /// Re-evaluates the quality of the samples collected so far.
+    pub fn assess(&mut self) -> CalibrationQuality {
+        let fit = fit_ellipsoid(&self.samples);
+        self.quality = self.quality(fit.as_ref());
+        self.quality
+    }
+
+    /// Quality of the collected samples for `fit`, including the outlier count.
+    pub fn quality(&self, fit: Option<&EllipsoidFit>) -> CalibrationQuality {
+        CalibrationQuality {
+            rejected: self.outliers.rejected(),
+            ..CalibrationQuality::assess(&self.samples, fit)
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.quality.coverage >= CALIBRATION_TARGET_COVERAGE && self.quality.score >= CALIBRATION_TARGET_SCORE
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed() >= self.timeout
+    }
+}
+
+// Background hard-iron estimator tuning.
+const HARD_IRON_GAIN: f32 = 0.01;
+const HARD_IRON_VARIANCE_ALPHA: f32 = 0.02;
+const HARD_IRON_WARMUP_SAMPLES: u32 = 50;
+const HARD_IRON_MIN_SAMPLES: u32 = 300;
+const HARD_IRON_OUTLIER_GATE: f32 = 3.0;
+const HARD_IRON_MIN_SIGMA: f32 = 0.5;
+// Residuals below this fraction of the radius are never treated as outliers.
+const HARD_IRON_MIN_OUTLIER: f32 = 0.05;
+const HARD_IRON_MIN_COVERAGE: f32 = 0.5;
+// The estimate must explain the field at least this much better than the stored offset.
+const HARD_IRON_COMMIT_RATIO: f32 = 0.7;
+const HARD_IRON_MIN_OFFSET_CHANGE: f32 = 1.0;
+
+/// Slow recursive sphere fit tracking the hard-iron offset while measuring.
+///
+/// Every accepted sample moves the center along the residual `|m - c| - r`. The
+/// residual variance of the estimate is compared with the one of the stored offset,
+/// and an update is only proposed when it is clearly better.
+#[derive(Debug, Clone, Default)]
+pub struct HardIronEstimator {
+    center: Option<Vector3>,
+    radius: f32,
+    variance: f32,
+    stored_radius: f32,
+    stored_variance: f32,
+    warmup: u32,
+    samples: u32,
+    rejected: u32,
+    last: Option<Vector3>,
+    buckets: u32,
+}
+
+impl HardIronEstimator {
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Feeds a raw reading. Returns the new offset when it should be committed.
+    pub fn update(&mut self, sample: Vector3, stored_offset: Vector3) -> Option<Vector3> {
+        if let Some(last) = self.last {
+            let distance = Vector3::new(sample.x - last.x, sample.y - last.y, sample.z - last.z).magnitude();
+            if distance < MIN_CALIBRATION_SAMPLE_DISTANCE {
+                return None;
+            }
+        }
+        self.last = Some(sample);
+
+        let center = *self.center.get_or_insert(stored_offset);
+        let delta = Vector3::new(sample.x - center.x, sample.y - center.y, sample.z - center.z);
+        let distance = delta.magnitude();
+        if distance <= 0.0 {
+            return None;
+        }
+
+        let stored_distance =
+            Vector3::new(sample.x - stored_offset.x, sample.y - stored_offset.y, sample.z - stored_offset.z).magnitude();
+
+        // Radius and residual variance start from the distance statistics over the
+        // warmup samples, the center only moves afterwards.
+        if self.warmup < HARD_IRON_WARMUP_SAMPLES {
+            self.warmup += 1;
+            let previous = self.radius;
+            self.radius += (distance - self.radius) / self.warmup as f32;
+            self.variance += ((distance - previous) * (distance - self.radius) - self.variance) / self.warmup as f32;
+            self.stored_radius += (stored_distance - self.stored_radius) / self.warmup as f32;
+            return None;
+        }
+
+        let residual = distance - self.radius;
+
+        // Outlier gate, a passing magnet or a bad reading must not drag the estimate.
+        let sigma = self.variance.sqrt().max(HARD_IRON_MIN_SIGMA);
+        if residual.abs() > HARD_IRON_OUTLIER_GATE * sigma && residual.abs() > HARD_IRON_MIN_OUTLIER * self.radius {
+            self.rejected += 1;
+            return None;
+        }
+
+        self.center = Some(Vector3::new(
+            center.x + HARD_IRON_GAIN * residual * delta.x / distance,
+            center.y + HARD_IRON_GAIN * residual * delta.y / distance,
+            center.z + HARD_IRON_GAIN * residual * delta.z / distance,
+        ));
+        self.radius += HARD_IRON_GAIN * residual;
+        self.variance += HARD_IRON_VARIANCE_ALPHA * (residual * residual - self.variance);
+
+        let stored_residual = stored_distance - self.stored_radius;
+        self.stored_radius += HARD_IRON_GAIN * stored_residual;
+        self.stored_variance += HARD_IRON_VARIANCE_ALPHA * (stored_residual * stored_residual - self.stored_variance);
+
+        if let Some(bucket) = orientation_bucket(delta) {
+            self.buckets |= 1 << bucket;
+        }
+
+        self.samples += 1;
+
+        self.commit_candidate(stored_offset)
+    }
+
+    fn commit_candidate(&mut self, stored_offset: Vector3) -> Option<Vector3> {
+        let center = self.center?;
+
+        let coverage = self.buckets.count_ones() as f32 / (COVERAGE_AZIMUTH_BUCKETS * COVERAGE_ELEVATION_BUCKETS) as f32;
+        let change =
+            Vector3::new(center.x - stored_offset.x, center.y - stored_offset.y, center.z - stored_offset.z).magnitude();
+
+        if self.samples < HARD_IRON_MIN_SAMPLES
+            || coverage < HARD_IRON_MIN_COVERAGE
+            || change < HARD_IRON_MIN_OFFSET_CHANGE
+            || self.variance >= HARD_IRON_COMMIT_RATIO * self.stored_variance
+        {
+            return None;
+        }
+
+        // Start collecting evidence again against the new offset.
+        self.samples = 0;
+        self.buckets = 0;
+        self.stored_radius = self.radius;
+        self.stored_variance = self.variance;
+
+        Some(center)
+    }
+}
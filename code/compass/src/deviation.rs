@@ -0,0 +1,77 @@
+use crate::math::{angle_difference, normalize_degrees, signed_angle_difference};
+
+// Deviation card resolution, one entry every 15 degrees of compass heading.
+pub const DEVIATION_STEP: f32 = 15.0;
+pub const DEVIATION_ENTRIES: usize = 24;
+
+// A sighting must be taken this close to an entry heading, the card has no room for
+// the deviation in between.
+pub const DEVIATION_TOLERANCE: f32 = 3.0;
+
+// Largest deviation accepted, anything above means the calibration itself is wrong.
+pub const MAX_DEVIATION: f32 = 45.0;
+
+/// Empty deviation card. Entries are in degrees, NaN marks headings not measured yet.
+pub fn empty_deviation_table() -> Vec<f32> {
+    vec![f32::NAN; DEVIATION_ENTRIES]
+}
+
+/// Deviation at `compass` heading, linearly interpolated between the nearest measured
+/// entries on each side. Headings between unmeasured entries take the value of the
+/// closest measured ones, an empty or malformed table gives 0.
+pub fn deviation_at(table: &[f32], compass: f32) -> f32 {
+    if table.len() != DEVIATION_ENTRIES || table.iter().all(|entry| entry.is_nan()) {
+        return 0.0;
+    }
+
+    let position = normalize_degrees(compass) / DEVIATION_STEP;
+    let below = position.floor() as usize % DEVIATION_ENTRIES;
+
+    // Walk around the card to the first measured entry at or below and strictly above.
+    let (lower, lower_distance) = (0..DEVIATION_ENTRIES)
+        .map(|step| ((below + DEVIATION_ENTRIES - step) % DEVIATION_ENTRIES, step))
+        .find(|(index, _)| !table[*index].is_nan())
+        .unwrap();
+    let (upper, upper_distance) = (1..=DEVIATION_ENTRIES)
+        .map(|step| ((below + step) % DEVIATION_ENTRIES, step))
+        .find(|(index, _)| !table[*index].is_nan())
+        .unwrap();
+
+    let fraction = position - position.floor();
+    let start = -(lower_distance as f32);
+    let end = upper_distance as f32;
+    let weight = (fraction - start) / (end - start);
+
+    table[lower] + (table[upper] - table[lower]) * weight
+}
+
+/// Records that the corrected magnetic heading at `compass` heading is `magnetic`.
+/// The deviation is stored in the entry of the card at `compass`, a sighting more than
+/// `DEVIATION_TOLERANCE` away from every entry is rejected.
+pub fn record_deviation(table: &[f32], compass: f32, magnetic: f32) -> Result<Vec<f32>, Box<dyn std::error::Error>> {
+    let deviation = signed_angle_difference(magnetic, compass);
+    if deviation.abs() > MAX_DEVIATION {
+        return Err(invalid(format!("Deviation: {:.1} degrees is out of range", deviation)));
+    }
+
+    let index = (normalize_degrees(compass) / DEVIATION_STEP).round() as usize % DEVIATION_ENTRIES;
+    let entry = index as f32 * DEVIATION_STEP;
+    let distance = angle_difference(compass, entry);
+    if distance > DEVIATION_TOLERANCE {
+        return Err(invalid(format!(
+            "Deviation: compass heading {:.1} is {:.1} degrees from the {:.0} degree entry, sight within {:.1} degrees of it",
+            normalize_degrees(compass),
+            distance,
+            entry,
+            DEVIATION_TOLERANCE
+        )));
+    }
+
+    let mut table = if table.len() == DEVIATION_ENTRIES { table.to_vec() } else { empty_deviation_table() };
+    table[index] = deviation;
+    Ok(table)
+}
+
+fn invalid(message: String) -> Box<dyn std::error::Error> {
+    Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, message))
+}
@@ -0,0 +1,8 @@
+//! Heading math and magnetometer calibration: vectors and angles, the ellipsoid fit and
+//! hard-iron estimator, the deviation card and the calibration backup format. Nothing
+//! here touches the hardware, it runs on a host as well as on the device.
+
+pub mod blob;
+pub mod calibration;
+pub mod deviation;
+pub mod math;
@@ -0,0 +1,176 @@
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Vector3 {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Vector3 {
+    pub fn new(x: f32, y: f32, z: f32) -> Self {
+        Self { x, y, z }
+    }
+
+    pub fn magnitude(&self) -> f32 {
+        (self.x * self.x + self.y * self.y + self.z * self.z).sqrt()
+    }
+}
+
+pub struct LowPassFilter {
+    alpha: f32,
+    state: Option<Vector3>,
+}
+
+impl LowPassFilter {
+    pub fn new(alpha: f32) -> Self {
+        Self { alpha, state: None }
+    }
+
+    pub fn update(&mut self, input: Vector3) -> Vector3 {
+        let filtered = match self.state {
+            Some(prev) => Vector3 {
+                x: self.alpha * input.x + (1.0 - self.alpha) * prev.x,
+                y: self.alpha * input.y + (1.0 - self.alpha) * prev.y,
+                z: self.alpha * input.z + (1.0 - self.alpha) * prev.z,
+            },
+            None => input,
+        };
+
+        self.state = Some(filtered);
+        filtered
+    }
+}
+
+/// Wraps an angle in degrees to the [0, 360) range.
+pub fn normalize_degrees(angle: f32) -> f32 {
+    let wrapped = angle.rem_euclid(360.0);
+    if wrapped >= 360.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+/// Smallest absolute difference between two angles in degrees (0-180).
+pub fn angle_difference(a: f32, b: f32) -> f32 {
+    let diff = normalize_degrees(a - b);
+    if diff > 180.0 {
+        360.0 - diff
+    } else {
+        diff
+    }
+}
+
+/// Signed difference `a - b` in degrees (-180 to 180).
+pub fn signed_angle_difference(a: f32, b: f32) -> f32 {
+    let diff = normalize_degrees(a - b);
+    if diff > 180.0 {
+        diff - 360.0
+    } else {
+        diff
+    }
+}
+
+/// Projects the magnetic field onto the horizontal plane using the pitch and roll
+/// derived from the gravity vector. Both vectors must share the same axis frame.
+/// The returned x/y components can be used directly for the heading; z is the
+/// vertical component.
+pub fn tilt_compensate(mag: Vector3, accel: Vector3) -> Vector3 {
+    let roll = accel.y.atan2(accel.z);
+    let pitch = (-accel.x).atan2(accel.y * roll.sin() + accel.z * roll.cos());
+
+    let (sin_roll, cos_roll) = roll.sin_cos();
+    let (sin_pitch, cos_pitch) = pitch.sin_cos();
+
+    Vector3 {
+        x: mag.x * cos_pitch + mag.y * sin_roll * sin_pitch + mag.z * cos_roll * sin_pitch,
+        y: mag.y * cos_roll - mag.z * sin_roll,
+        z: -mag.x * sin_pitch + mag.y * sin_roll * cos_pitch + mag.z * cos_roll * cos_pitch,
+    }
+}
+
+/// 3x3 matrix stored row-major.
+pub type Matrix3 = [[f32; 3]; 3];
+
+pub fn matrix_mul_vector(m: &Matrix3, v: Vector3) -> Vector3 {
+    Vector3 {
+        x: m[0][0] * v.x + m[0][1] * v.y + m[0][2] * v.z,
+        y: m[1][0] * v.x + m[1][1] * v.y + m[1][2] * v.z,
+        z: m[2][0] * v.x + m[2][1] * v.y + m[2][2] * v.z,
+    }
+}
+
+/// Solves `a * x = b` with Gaussian elimination and partial pivoting.
+/// Returns `None` when the system is singular.
+pub fn solve_linear_system(mut a: Vec<Vec<f64>>, mut b: Vec<f64>) -> Option<Vec<f64>> {
+    let n = b.len();
+
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| a[i][col].abs().total_cmp(&a[j][col].abs()))?;
+        if a[pivot][col].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(col, pivot);
+        b.swap(col, pivot);
+
+        for row in col + 1..n {
+            let factor = a[row][col] / a[col][col];
+            let pivot_row = a[col].clone();
+            for (value, pivot) in a[row][col..].iter_mut().zip(&pivot_row[col..]) {
+                *value -= factor * pivot;
+            }
+            b[row] -= factor * b[col];
+        }
+    }
+
+    let mut x = vec![0.0; n];
+    for row in (0..n).rev() {
+        let sum: f64 = (row + 1..n).map(|k| a[row][k] * x[k]).sum();
+        x[row] = (b[row] - sum) / a[row][row];
+    }
+
+    Some(x)
+}
+
+/// Eigen decomposition of a symmetric 3x3 matrix using Jacobi rotations.
+/// Returns the eigenvalues and the eigenvectors as the columns of the matrix.
+pub fn symmetric_eigen(mut a: [[f64; 3]; 3]) -> ([f64; 3], [[f64; 3]; 3]) {
+    let mut v = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    for _ in 0..50 {
+        let off = a[0][1].abs() + a[0][2].abs() + a[1][2].abs();
+        if off < 1e-15 {
+            break;
+        }
+
+        for (p, q) in [(0, 1), (0, 2), (1, 2)] {
+            if a[p][q].abs() < 1e-18 {
+                continue;
+            }
+
+            let theta = (a[q][q] - a[p][p]) / (2.0 * a[p][q]);
+            let t = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+            let c = 1.0 / (t * t + 1.0).sqrt();
+            let s = t * c;
+
+            for row in a.iter_mut() {
+                let akp = row[p];
+                let akq = row[q];
+                row[p] = c * akp - s * akq;
+                row[q] = s * akp + c * akq;
+            }
+            let (row_p, row_q) = (a[p], a[q]);
+            for (k, (apk, aqk)) in row_p.iter().zip(row_q.iter()).enumerate() {
+                a[p][k] = c * apk - s * aqk;
+                a[q][k] = s * apk + c * aqk;
+            }
+            for row in v.iter_mut() {
+                let vkp = row[p];
+                let vkq = row[q];
+                row[p] = c * vkp - s * vkq;
+                row[q] = s * vkp + c * vkq;
+            }
+        }
+    }
+
+    ([a[0][0], a[1][1], a[2][2]], v)
+}
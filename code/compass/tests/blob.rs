@@ -0,0 +1,80 @@
+use compass::blob::*;
+
+fn blob() -> CalibrationBlob {
+    CalibrationBlob {
+        name: "Boat".to_string(),
+        min: [-40.0, -35.0, -50.0],
+        max: [45.0, 38.0, 52.0],
+        offset: vec![2.5, 1.5, 1.0],
+        soft_iron: vec![1.0, 0.01, 0.0, 0.01, 1.1, 0.0, 0.0, 0.0, 0.95],
+        mounting_offset: 3.5,
+        deviation: vec![0.5; 24],
+        calibration_mode: 1,
+        sensor: SensorSettings { gain: 7, resolution: [0, 0, 1], filter: 5, oversampling: 2 },
+    }
+}
+
+#[test]
+fn crc32_matches_zlib() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+}
+
+#[test]
+fn blob_round_trips() {
+    let bytes = blob().to_bytes();
+    assert_eq!(bytes[0..4], CALIBRATION_BLOB_MAGIC);
+    assert_eq!(CalibrationBlob::from_bytes(&bytes).unwrap(), blob());
+}
+
+#[test]
+fn long_name_is_cut_on_a_character_boundary() {
+    // 128 two-byte characters, byte 255 falls inside the last one that fits.
+    let mut long = blob();
+    long.name = "é".repeat(128);
+
+    let decoded = CalibrationBlob::from_bytes(&long.to_bytes()).unwrap();
+    assert_eq!(decoded.name, "é".repeat(127));
+}
+
+#[test]
+fn corrupted_blob_is_rejected() {
+    let mut bytes = blob().to_bytes();
+    bytes[10] ^= 0x01;
+    assert!(CalibrationBlob::from_bytes(&bytes).unwrap_err().to_string().contains("CRC mismatch"));
+}
+
+#[test]
+fn truncated_blob_is_rejected() {
+    let bytes = blob().to_bytes();
+    assert!(CalibrationBlob::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+    assert!(CalibrationBlob::from_bytes(&bytes[..4]).is_err());
+}
+
+#[test]
+fn newer_version_is_rejected() {
+    let mut bytes = blob().to_bytes();
+    bytes[4] = CALIBRATION_BLOB_VERSION + 1;
+    assert!(CalibrationBlob::from_bytes(&bytes).unwrap_err().to_string().contains("unsupported version"));
+}
+
+#[test]
+fn malformed_deviation_card_is_rejected() {
+    let short = CalibrationBlob { deviation: vec![0.5; 12], ..blob() };
+    assert!(CalibrationBlob::from_bytes(&short.to_bytes()).unwrap_err().to_string().contains("deviation"));
+
+    let unmeasured = CalibrationBlob { deviation: vec![f32::NAN; 24], ..blob() };
+    assert!(CalibrationBlob::from_bytes(&unmeasured.to_bytes()).is_ok());
+}
+
+#[test]
+fn non_finite_values_are_rejected() {
+    let infinite = CalibrationBlob { mounting_offset: f32::INFINITY, ..blob() };
+    assert!(CalibrationBlob::from_bytes(&infinite.to_bytes()).is_err());
+
+    let nan = CalibrationBlob { offset: vec![0.0, f32::NAN, 0.0], ..blob() };
+    assert!(CalibrationBlob::from_bytes(&nan.to_bytes()).is_err());
+
+    let mut deviation = vec![0.5; 24];
+    deviation[3] = f32::INFINITY;
+    assert!(CalibrationBlob::from_bytes(&CalibrationBlob { deviation, ..blob() }.to_bytes()).is_err());
+}
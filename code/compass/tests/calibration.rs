@@ -0,0 +1,189 @@
+use compass::calibration::*;
+use compass::math::{matrix_mul_vector, Matrix3, Vector3};
+
+const FIELD: f32 = 50.0;
+
+/// Points spread evenly over a sphere of `radius`, so every orientation is covered.
+fn sphere(count: usize, radius: f32) -> Vec<Vector3> {
+    let golden = std::f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    (0..count)
+        .map(|i| {
+            let z = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let r = (1.0 - z * z).sqrt();
+            let theta = golden * i as f32;
+            Vector3::new(radius * r * theta.cos(), radius * r * theta.sin(), radius * z)
+        })
+        .collect()
+}
+
+/// Raw readings of a sensor with hard-iron `offset` and soft-iron `distortion`.
+fn distorted(points: &[Vector3], offset: Vector3, distortion: &Matrix3) -> Vec<Vector3> {
+    points
+        .iter()
+        .map(|point| {
+            let v = matrix_mul_vector(distortion, *point);
+            Vector3::new(v.x + offset.x, v.y + offset.y, v.z + offset.z)
+        })
+        .collect()
+}
+
+/// A slow turn in the horizontal plane, 5 degrees between readings.
+fn circle(count: usize) -> Vec<Vector3> {
+    (0..count)
+        .map(|i| {
+            let angle = (5.0 * i as f32).to_radians();
+            Vector3::new(FIELD * angle.cos(), FIELD * angle.sin(), 0.0)
+        })
+        .collect()
+}
+
+fn assert_vector(value: Vector3, expected: Vector3, tolerance: f32) {
+    let error = Vector3::new(value.x - expected.x, value.y - expected.y, value.z - expected.z).magnitude();
+    assert!(error <= tolerance, "{:?} is not {:?} +- {}", value, expected, tolerance);
+}
+
+#[test]
+fn ellipsoid_fit_recovers_offset_and_sphere() {
+    let offset = Vector3::new(12.0, -7.0, 25.0);
+    let distortion = [[1.2, 0.1, 0.0], [0.1, 0.9, 0.05], [0.0, 0.05, 1.05]];
+    let samples = distorted(&sphere(200, FIELD), offset, &distortion);
+
+    let fit = fit_ellipsoid(&samples).unwrap();
+    assert_vector(fit.offset, offset, 0.01);
+
+    for sample in &samples {
+        let corrected = fit.apply(*sample).magnitude();
+        assert!((corrected - fit.radius).abs() < 0.01 * fit.radius, "{} is off the sphere of {}", corrected, fit.radius);
+    }
+    assert!(fit_residual(&fit, &samples) < 0.01);
+}
+
+#[test]
+fn ellipsoid_fit_needs_enough_samples() {
+    assert!(fit_ellipsoid(&sphere(MIN_FIT_SAMPLES - 1, FIELD)).is_none());
+}
+
+#[test]
+fn ellipsoid_fit_round_trips_through_parameters() {
+    let samples = distorted(&sphere(100, FIELD), Vector3::new(3.0, 4.0, 5.0), &[[1.1, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 0.9]]);
+    let fit = fit_ellipsoid(&samples).unwrap();
+    let restored = EllipsoidFit::from_parameters(&fit.offset_parameters(), &fit.soft_iron_parameters()).unwrap();
+
+    assert_vector(restored.apply(samples[0]), fit.apply(samples[0]), 1e-6);
+    assert!(EllipsoidFit::from_parameters(&[1.0, 2.0], &fit.soft_iron_parameters()).is_none());
+}
+
+#[test]
+fn correction_falls_back_to_the_min_max_box() {
+    let min = Vector3::new(-40.0, -30.0, -50.0);
+    let max = Vector3::new(60.0, 50.0, 30.0);
+    let fit = fit_ellipsoid(&sphere(100, FIELD));
+
+    let correction = HardIronCorrection::select(CalibrationMode::MinMax, fit, min, max).unwrap();
+    assert_vector(correction.offset(), Vector3::new(10.0, 10.0, -10.0), 1e-6);
+    assert_vector(correction.apply(Vector3::new(10.0, 60.0, -10.0)), Vector3::new(0.0, 50.0, 0.0), 1e-6);
+
+    let correction = HardIronCorrection::select(CalibrationMode::Ellipsoid, None, min, max).unwrap();
+    assert!(matches!(correction, HardIronCorrection::MinMax(_)));
+
+    assert!(HardIronCorrection::select(CalibrationMode::MinMax, fit, max, min).is_none());
+}
+
+#[test]
+fn centered_correction_matches_the_full_one() {
+    let distortion = [[1.2, 0.1, 0.0], [0.1, 0.9, 0.05], [0.0, 0.05, 1.05]];
+    let offset = Vector3::new(12.0, -7.0, 25.0);
+    let samples = distorted(&sphere(200, FIELD), offset, &distortion);
+    let fit = fit_ellipsoid(&samples).unwrap();
+    let correction = HardIronCorrection::select(CalibrationMode::Ellipsoid, Some(fit), offset, offset).unwrap();
+
+    // What a sensor that already subtracts the offset hands over.
+    let centered = Vector3::new(samples[7].x - fit.offset.x, samples[7].y - fit.offset.y, samples[7].z - fit.offset.z);
+    assert_vector(correction.apply_centered(centered), correction.apply(samples[7]), 1e-4);
+}
+
+#[test]
+fn outlier_gate_rejects_glitches() {
+    let mut gate = OutlierGate::default();
+    for sample in circle(10) {
+        assert!(gate.accept(sample));
+    }
+
+    // A reading far from the field magnitude, and one jumping across the sphere.
+    let last = circle(10)[9];
+    assert!(!gate.accept(Vector3::new(1.4 * last.x, 1.4 * last.y, 0.0)));
+    assert!(!gate.accept(Vector3::new(-last.x, -last.y, -last.z)));
+
+    assert_eq!(gate.rejected_magnitude(), 1);
+    assert_eq!(gate.rejected_jump(), 1);
+}
+
+#[test]
+fn outlier_gate_follows_a_lasting_change() {
+    let mut gate = OutlierGate::default();
+    for sample in circle(10) {
+        gate.accept(sample);
+    }
+
+    let moved = Vector3::new(0.0, 0.0, 2.0 * FIELD);
+    let accepted = (0..20).filter(|_| gate.accept(moved)).count();
+    assert!(accepted > 0, "the gate never accepted the new field");
+    assert!(gate.accept(moved));
+}
+
+#[test]
+fn hard_iron_estimator_tracks_a_moved_offset() {
+    let offset = Vector3::new(8.0, -6.0, 4.0);
+    let samples = distorted(&sphere(400, FIELD), offset, &[[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    // Committed offsets are stored, as the sensor does, and the next ones refine them.
+    let mut estimator = HardIronEstimator::default();
+    let mut stored = Vector3::new(0.0, 0.0, 0.0);
+    let mut commits = 0;
+    for sample in samples.iter().cycle().take(20000) {
+        if let Some(committed) = estimator.update(*sample, stored) {
+            stored = committed;
+            commits += 1;
+        }
+    }
+
+    assert!(commits > 0, "no offset committed");
+    assert_vector(stored, offset, 1.0);
+    assert!((estimator.radius() - FIELD).abs() < 1.0);
+}
+
+#[test]
+fn hard_iron_estimator_tracks_the_offset_behind_an_ellipsoid_fit() {
+    let distortion = [[1.2, 0.1, 0.0], [0.1, 0.9, 0.05], [0.0, 0.05, 1.05]];
+    let points = sphere(400, FIELD);
+    let fit = fit_ellipsoid(&distorted(&points, Vector3::new(12.0, -7.0, 25.0), &distortion)).unwrap();
+
+    // The hard iron moves after the calibration, the soft iron does not.
+    let moved = Vector3::new(18.0, -11.0, 22.0);
+    let samples = distorted(&points, moved, &distortion);
+
+    // The estimator sees the corrected field, where the offset left is a residual. As on
+    // the sensor, a committed offset moves the fit and the estimator starts over.
+    let mut fit = fit;
+    let mut estimator = HardIronEstimator::default();
+    let mut commits = 0;
+    for sample in samples.iter().cycle().take(20000) {
+        if let Some(residual) = estimator.update(fit.apply(*sample), Vector3::new(0.0, 0.0, 0.0)) {
+            fit.offset = fit.raw_offset(residual).unwrap();
+            estimator.reset();
+            commits += 1;
+        }
+    }
+
+    assert!(commits > 0, "no offset committed");
+    assert_vector(fit.offset, moved, 1.0);
+}
+
+#[test]
+fn hard_iron_estimator_keeps_a_good_offset() {
+    let offset = Vector3::new(8.0, -6.0, 4.0);
+    let samples = distorted(&sphere(400, FIELD), offset, &[[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]]);
+
+    let mut estimator = HardIronEstimator::default();
+    assert!(samples.iter().cycle().take(4000).all(|sample| estimator.update(*sample, offset).is_none()));
+}
@@ -0,0 +1,47 @@
+use compass::deviation::*;
+
+fn assert_close(value: f32, expected: f32) {
+    assert!((value - expected).abs() < 1e-4, "{} is not {}", value, expected);
+}
+
+#[test]
+fn empty_card_has_no_deviation() {
+    assert_eq!(deviation_at(&empty_deviation_table(), 123.0), 0.0);
+    assert_eq!(deviation_at(&[], 123.0), 0.0);
+}
+
+#[test]
+fn deviation_is_interpolated_between_entries() {
+    let table = record_deviation(&empty_deviation_table(), 0.0, 2.0).unwrap();
+    let table = record_deviation(&table, 30.0, 26.0).unwrap();
+
+    assert_close(deviation_at(&table, 0.0), 2.0);
+    assert_close(deviation_at(&table, 15.0), -1.0);
+    assert_close(deviation_at(&table, 30.0), -4.0);
+}
+
+#[test]
+fn deviation_wraps_around_north() {
+    let table = record_deviation(&empty_deviation_table(), 345.0, 349.0).unwrap();
+    let table = record_deviation(&table, 15.0, 13.0).unwrap();
+
+    assert_close(deviation_at(&table, 0.0), 1.0);
+    assert_close(deviation_at(&table, 180.0), 1.0);
+}
+
+#[test]
+fn sighting_near_an_entry_is_recorded() {
+    let table = record_deviation(&empty_deviation_table(), 358.0, 1.0).unwrap();
+    assert_close(table[0], 3.0);
+}
+
+#[test]
+fn sighting_between_entries_is_rejected() {
+    let error = record_deviation(&empty_deviation_table(), 22.0, 25.0).unwrap_err();
+    assert!(error.to_string().contains("15 degree entry"), "{}", error);
+}
+
+#[test]
+fn excessive_deviation_is_rejected() {
+    assert!(record_deviation(&empty_deviation_table(), 90.0, 90.0 + MAX_DEVIATION + 1.0).is_err());
+}
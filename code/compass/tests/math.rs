@@ -0,0 +1,53 @@
+use compass::math::*;
+
+fn assert_close(value: f64, expected: f64, tolerance: f64) {
+    assert!((value - expected).abs() <= tolerance, "{} is not {} +- {}", value, expected, tolerance);
+}
+
+#[test]
+fn angles_wrap_around_north() {
+    assert_eq!(normalize_degrees(-90.0), 270.0);
+    assert_eq!(normalize_degrees(720.0), 0.0);
+    assert_eq!(angle_difference(350.0, 10.0), 20.0);
+    assert_eq!(signed_angle_difference(350.0, 10.0), -20.0);
+    assert_eq!(signed_angle_difference(10.0, 350.0), 20.0);
+}
+
+#[test]
+fn level_device_is_not_tilt_compensated() {
+    let mag = Vector3::new(20.0, -5.0, -40.0);
+    let level = tilt_compensate(mag, Vector3::new(0.0, 0.0, 1.0));
+    assert_close(level.x as f64, 20.0, 1e-4);
+    assert_close(level.y as f64, -5.0, 1e-4);
+}
+
+#[test]
+fn linear_system_is_solved() {
+    let a = vec![vec![2.0, 1.0, -1.0], vec![-3.0, -1.0, 2.0], vec![-2.0, 1.0, 2.0]];
+    let x = solve_linear_system(a, vec![8.0, -11.0, -3.0]).unwrap();
+    assert_close(x[0], 2.0, 1e-9);
+    assert_close(x[1], 3.0, 1e-9);
+    assert_close(x[2], -1.0, 1e-9);
+}
+
+#[test]
+fn singular_system_is_rejected() {
+    let a = vec![vec![1.0, 2.0], vec![2.0, 4.0]];
+    assert!(solve_linear_system(a, vec![1.0, 2.0]).is_none());
+}
+
+#[test]
+fn symmetric_eigen_decomposes() {
+    let a = [[4.0, 1.0, 0.5], [1.0, 3.0, 0.2], [0.5, 0.2, 1.0]];
+    let (values, vectors) = symmetric_eigen(a);
+
+    for (column, value) in values.iter().enumerate() {
+        let v = [vectors[0][column], vectors[1][column], vectors[2][column]];
+        assert_close(v.iter().map(|c| c * c).sum::<f64>(), 1.0, 1e-9);
+        for row in 0..3 {
+            let av: f64 = (0..3).map(|k| a[row][k] * v[k]).sum();
+            assert_close(av, value * v[row], 1e-9);
+        }
+    }
+    assert_close(values.iter().sum::<f64>(), 8.0, 1e-9);
+}
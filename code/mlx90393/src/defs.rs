@@ -0,0 +1,489 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum MLX90393REG {
+    CONF1 = 0x00,
+    CONF2 = 0x01,
+    CONF3 = 0x02,
+    SENS_TC = 0x03,
+    OFFSET_X = 0x04,
+    OFFSET_Y = 0x05,
+    OFFSET_Z = 0x06,
+    WOXY_THRESHOLD = 0x07,
+    WOZ_THRESHOLD = 0x08,
+    WOT_THRESHOLD = 0x09,
+    TREF = 0x24,    // Temperature reference, factory calibrated, read only.
+}
+
+impl MLX90393REG {
+    /// The user registers, in address order.
+    pub const ALL: [MLX90393REG; 10] = [
+        MLX90393REG::CONF1,
+        MLX90393REG::CONF2,
+        MLX90393REG::CONF3,
+        MLX90393REG::SENS_TC,
+        MLX90393REG::OFFSET_X,
+        MLX90393REG::OFFSET_Y,
+        MLX90393REG::OFFSET_Z,
+        MLX90393REG::WOXY_THRESHOLD,
+        MLX90393REG::WOZ_THRESHOLD,
+        MLX90393REG::WOT_THRESHOLD,
+    ];
+}
+
+impl From<MLX90393REG> for u8 {
+    fn from(reg: MLX90393REG) -> Self {
+        reg as u8
+    }
+}
+
+/// A bitfield of a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MLX90393Field {
+    pub register: MLX90393REG,
+    pub shift: u8,
+    pub width: u8,
+}
+
+impl MLX90393Field {
+    // CONF1
+    pub const BIST: Self = Self::new(MLX90393REG::CONF1, 8, 1);
+    pub const Z_SERIES: Self = Self::new(MLX90393REG::CONF1, 7, 1);
+    pub const GAIN_SEL: Self = Self::new(MLX90393REG::CONF1, 4, 3);
+    pub const HALLCONF: Self = Self::new(MLX90393REG::CONF1, 0, 4);
+    // CONF2
+    pub const TRIG_INT: Self = Self::new(MLX90393REG::CONF2, 15, 1);
+    pub const COMM_MODE: Self = Self::new(MLX90393REG::CONF2, 13, 2);
+    pub const WOC_DIFF: Self = Self::new(MLX90393REG::CONF2, 12, 1);
+    pub const EXT_TRIG: Self = Self::new(MLX90393REG::CONF2, 11, 1);
+    pub const TCMP_EN: Self = Self::new(MLX90393REG::CONF2, 10, 1);
+    pub const BURST_SEL: Self = Self::new(MLX90393REG::CONF2, 6, 4);
+    pub const BURST_DATA_RATE: Self = Self::new(MLX90393REG::CONF2, 0, 6);
+    // CONF3
+    pub const OSR2: Self = Self::new(MLX90393REG::CONF3, 11, 2);
+    pub const RES_Z: Self = Self::new(MLX90393REG::CONF3, 9, 2);
+    pub const RES_Y: Self = Self::new(MLX90393REG::CONF3, 7, 2);
+    pub const RES_X: Self = Self::new(MLX90393REG::CONF3, 5, 2);
+    pub const DIG_FILT: Self = Self::new(MLX90393REG::CONF3, 2, 3);
+    pub const OSR: Self = Self::new(MLX90393REG::CONF3, 0, 2);
+    // SENS_TC, temperature compensation coefficients above and below 35 °C.
+    pub const SENS_TC_HT: Self = Self::new(MLX90393REG::SENS_TC, 8, 8);
+    pub const SENS_TC_LT: Self = Self::new(MLX90393REG::SENS_TC, 0, 8);
+    // Full width registers.
+    pub const OFFSET_X: Self = Self::new(MLX90393REG::OFFSET_X, 0, 16);
+    pub const OFFSET_Y: Self = Self::new(MLX90393REG::OFFSET_Y, 0, 16);
+    pub const OFFSET_Z: Self = Self::new(MLX90393REG::OFFSET_Z, 0, 16);
+    pub const WOXY_THRESHOLD: Self = Self::new(MLX90393REG::WOXY_THRESHOLD, 0, 16);
+    pub const WOZ_THRESHOLD: Self = Self::new(MLX90393REG::WOZ_THRESHOLD, 0, 16);
+    pub const WOT_THRESHOLD: Self = Self::new(MLX90393REG::WOT_THRESHOLD, 0, 16);
+    pub const TREF: Self = Self::new(MLX90393REG::TREF, 0, 16);
+
+    pub const ALL: [(&'static str, MLX90393Field); 26] = [
+        ("BIST", Self::BIST),
+        ("Z_SERIES", Self::Z_SERIES),
+        ("GAIN_SEL", Self::GAIN_SEL),
+        ("HALLCONF", Self::HALLCONF),
+        ("TRIG_INT", Self::TRIG_INT),
+        ("COMM_MODE", Self::COMM_MODE),
+        ("WOC_DIFF", Self::WOC_DIFF),
+        ("EXT_TRIG", Self::EXT_TRIG),
+        ("TCMP_EN", Self::TCMP_EN),
+        ("BURST_SEL", Self::BURST_SEL),
+        ("BURST_DATA_RATE", Self::BURST_DATA_RATE),
+        ("OSR2", Self::OSR2),
+        ("RES_Z", Self::RES_Z),
+        ("RES_Y", Self::RES_Y),
+        ("RES_X", Self::RES_X),
+        ("DIG_FILT", Self::DIG_FILT),
+        ("OSR", Self::OSR),
+        ("SENS_TC_HT", Self::SENS_TC_HT),
+        ("SENS_TC_LT", Self::SENS_TC_LT),
+        ("OFFSET_X", Self::OFFSET_X),
+        ("OFFSET_Y", Self::OFFSET_Y),
+        ("OFFSET_Z", Self::OFFSET_Z),
+        ("WOXY_THRESHOLD", Self::WOXY_THRESHOLD),
+        ("WOZ_THRESHOLD", Self::WOZ_THRESHOLD),
+        ("WOT_THRESHOLD", Self::WOT_THRESHOLD),
+        ("TREF", Self::TREF),
+    ];
+
+    pub const fn new(register: MLX90393REG, shift: u8, width: u8) -> Self {
+        Self { register, shift, width }
+    }
+
+    /// Resolution field of a magnetic axis.
+    pub fn resolution(axis: MLX90393AXIS) -> Option<Self> {
+        match axis {
+            MLX90393AXIS::X => Some(Self::RES_X),
+            MLX90393AXIS::Y => Some(Self::RES_Y),
+            MLX90393AXIS::Z => Some(Self::RES_Z),
+            _ => None,
+        }
+    }
+
+    pub const fn mask(&self) -> u16 {
+        (((1u32 << self.width) - 1) << self.shift) as u16
+    }
+
+    /// Largest value that fits in the field.
+    pub const fn max(&self) -> u16 {
+        ((1u32 << self.width) - 1) as u16
+    }
+
+    pub fn extract(&self, register_value: u16) -> u16 {
+        (register_value & self.mask()) >> self.shift
+    }
+
+    pub fn insert(&self, register_value: u16, value: u16) -> u16 {
+        (register_value & !self.mask()) | ((value << self.shift) & self.mask())
+    }
+}
+
+/// Copy of all the user registers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MLX90393Registers {
+    pub values: [u16; 10],
+}
+
+impl MLX90393Registers {
+    pub fn get(&self, register: MLX90393REG) -> Option<u16> {
+        self.values.get(register as usize).copied()
+    }
+
+    pub fn field(&self, field: MLX90393Field) -> Option<u16> {
+        self.get(field.register).map(|value| field.extract(value))
+    }
+}
+
+impl std::fmt::Display for MLX90393Registers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for register in MLX90393REG::ALL {
+            write!(f, "{:?} (0x{:02X}) = 0x{:04X}", register, register as u8, self.values[register as usize])?;
+            for (name, field) in MLX90393Field::ALL.iter().filter(|(_, field)| field.register == register && field.width < 16) {
+                write!(f, " {}={}", name, self.field(*field).unwrap_or(0))?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393CMD {
+    SB = 0x10,  // Start burst mode.
+    SW = 0x20,  // Start wakeup on change mode.
+    SM = 0x30,  // Start single-meas mode.
+    RM = 0x40,  // Read measurement.
+    RR = 0x50,  // Read register.
+    WR = 0x60,  // Write register.
+    EX = 0x80,  // Exit mode.
+    HR = 0xD0,  // Memory recall.
+    HS = 0x70,  // Memory store.
+    RT = 0xF0,  // Reset.
+    NOP = 0x00, // NOP.
+}
+
+impl From<MLX90393CMD> for u8 {
+    fn from(cmd: MLX90393CMD) -> Self {
+        cmd as u8
+    }
+}
+
+impl From<u8> for MLX90393CMD {
+    fn from(cmd: u8) -> Self {
+        match cmd {
+            0x10 => MLX90393CMD::SB,
+            0x20 => MLX90393CMD::SW,
+            0x30 => MLX90393CMD::SM,
+            0x40 => MLX90393CMD::RM,
+            0x50 => MLX90393CMD::RR,
+            0x60 => MLX90393CMD::WR,
+            0x80 => MLX90393CMD::EX,
+            0xD0 => MLX90393CMD::HR,
+            0x70 => MLX90393CMD::HS,
+            0xF0 => MLX90393CMD::RT,
+            0x00 => MLX90393CMD::NOP,
+            _ => panic!("Invalid MLX90393CMD"),
+        }
+    }
+}
+
+/// Status byte, the first byte of every command response.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MLX90393Status(pub u8);
+
+impl MLX90393Status {
+    pub const BURST_MODE: u8 = 0x80;
+    pub const WOC_MODE: u8 = 0x40;
+    pub const SM_MODE: u8 = 0x20;
+    pub const ERROR: u8 = 0x10;
+    pub const SED: u8 = 0x08;
+    pub const RS: u8 = 0x04;
+    pub const D: u8 = 0x03;
+
+    pub fn burst_mode(&self) -> bool {
+        self.0 & Self::BURST_MODE != 0
+    }
+
+    pub fn woc_mode(&self) -> bool {
+        self.0 & Self::WOC_MODE != 0
+    }
+
+    pub fn sm_mode(&self) -> bool {
+        self.0 & Self::SM_MODE != 0
+    }
+
+    /// The command was rejected or the measurement data is not valid.
+    pub fn error(&self) -> bool {
+        self.0 & Self::ERROR != 0
+    }
+
+    /// Single error detection, a bit error in the non-volatile memory was detected.
+    pub fn sed(&self) -> bool {
+        self.0 & Self::SED != 0
+    }
+
+    /// Set on the first response after a reset, power-up or brown-out.
+    pub fn reset(&self) -> bool {
+        self.0 & Self::RS != 0
+    }
+
+    /// Data bytes following the status in an RR or RM response, 2 * D + 2.
+    pub fn response_length(&self) -> usize {
+        2 * (self.0 & Self::D) as usize + 2
+    }
+}
+
+impl std::fmt::Display for MLX90393Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "0x{:02X}", self.0)?;
+        for (flag, name) in [
+            (self.burst_mode(), "BURST"),
+            (self.woc_mode(), "WOC"),
+            (self.sm_mode(), "SM"),
+            (self.error(), "ERROR"),
+            (self.sed(), "SED"),
+            (self.reset(), "RS"),
+        ] {
+            if flag {
+                write!(f, " {}", name)?;
+            }
+        }
+        write!(f, " D={}", self.0 & Self::D)
+    }
+}
+
+
+/// Only the two configurations with a documented sensitivity are supported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(non_camel_case_types)]
+pub enum MLX90393HALLCONF {
+    HALLCONF_0 = 0x00,
+    HALLCONF_C = 0x0C,
+}
+
+impl From<MLX90393HALLCONF> for u8 {
+    fn from(hallconf: MLX90393HALLCONF) -> Self {
+        hallconf as u8
+    }
+}
+
+/// Fails with the unsupported value.
+impl TryFrom<u8> for MLX90393HALLCONF {
+    type Error = u8;
+
+    fn try_from(hallconf: u8) -> Result<Self, Self::Error> {
+        match hallconf {
+            0x00 => Ok(MLX90393HALLCONF::HALLCONF_0),
+            0x0C => Ok(MLX90393HALLCONF::HALLCONF_C),
+            _ => Err(hallconf),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393GAIN {
+    GAIN5X = (0x00),
+    GAIN4X = (0x01),
+    GAIN3X = (0x02),
+    GAIN2_5X = (0x03),
+    GAIN2X = (0x04),
+    GAIN1_67X = (0x05),
+    GAIN1_33X = (0x06),
+    GAIN1X = (0x07),
+}
+
+impl From<MLX90393GAIN> for u8 {
+    fn from(gain: MLX90393GAIN) -> Self {
+        gain as u8
+    }
+}
+
+impl From<u8> for MLX90393GAIN {
+    fn from(gain: u8) -> Self {
+        match gain {
+            0x00 => MLX90393GAIN::GAIN5X,
+            0x01 => MLX90393GAIN::GAIN4X,
+            0x02 => MLX90393GAIN::GAIN3X,
+            0x03 => MLX90393GAIN::GAIN2_5X,
+            0x04 => MLX90393GAIN::GAIN2X,
+            0x05 => MLX90393GAIN::GAIN1_67X,
+            0x06 => MLX90393GAIN::GAIN1_33X,
+            0x07 => MLX90393GAIN::GAIN1X,
+            _ => panic!("Invalid MLX90393GAIN"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393RESOLUTION {
+    RES16 = (0x00),
+    RES17 = (0x01),
+    RES18 = (0x02),
+    RES19 = (0x03),
+}
+
+impl From<MLX90393RESOLUTION> for u8 {
+    fn from(resolution: MLX90393RESOLUTION) -> Self {
+        resolution as u8
+    }
+}
+
+impl From<u8> for MLX90393RESOLUTION {
+    fn from(resolution: u8) -> Self {
+        match resolution {
+            0x00 => MLX90393RESOLUTION::RES16,
+            0x01 => MLX90393RESOLUTION::RES17,
+            0x02 => MLX90393RESOLUTION::RES18,
+            0x03 => MLX90393RESOLUTION::RES19,
+            _ => panic!("Invalid MLX90393RESOLUTION"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393AXIS {
+    T = 0x01,
+    X = 0x02,
+    Y = 0x04,
+    Z = 0x08,
+    ALL = 0x0F
+}
+
+impl From<MLX90393AXIS> for u8 {
+    fn from(axis: MLX90393AXIS) -> Self {
+        axis as u8
+    }
+}
+
+impl From<u8> for MLX90393AXIS {
+    fn from(axis: u8) -> Self {
+        match axis {
+            0x01 => MLX90393AXIS::T,
+            0x02 => MLX90393AXIS::X,
+            0x04 => MLX90393AXIS::Y,
+            0x08 => MLX90393AXIS::Z,
+            0x0F => MLX90393AXIS::ALL,
+            _ => panic!("Invalid MLX90393AXIS"),
+        }
+    }
+}
+
+/// Set of axes converted by a measurement, in the zyxt bit order of the commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MLX90393Axes(u8);
+
+impl MLX90393Axes {
+    pub const ALL: Self = Self(0x0F);
+    pub const XYZ: Self = Self(0x0E);
+
+    pub fn new(axes: &[MLX90393AXIS]) -> Self {
+        Self(axes.iter().fold(0, |bits, axis| bits | *axis as u8))
+    }
+
+    pub fn from_bits(bits: u8) -> Self {
+        Self(bits & 0x0F)
+    }
+
+    pub fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn contains(&self, axis: MLX90393AXIS) -> bool {
+        self.0 & axis as u8 == axis as u8
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    /// Number of 16-bit words in a measurement response.
+    pub fn count(&self) -> usize {
+        self.0.count_ones() as usize
+    }
+}
+
+impl Default for MLX90393Axes {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393FILTER {
+    FILTER0,
+    FILTER1,
+    FILTER2,
+    FILTER3,
+    FILTER4,
+    FILTER5,
+    FILTER6,
+    FILTER7,
+}
+
+impl From<MLX90393FILTER> for u8 {
+    fn from(filter: MLX90393FILTER) -> Self {
+        filter as u8
+    }
+}
+
+impl From<u8> for MLX90393FILTER {
+    fn from(filter: u8) -> Self {
+        match filter {
+            0x00 => MLX90393FILTER::FILTER0,
+            0x01 => MLX90393FILTER::FILTER1,
+            0x02 => MLX90393FILTER::FILTER2,
+            0x03 => MLX90393FILTER::FILTER3,
+            0x04 => MLX90393FILTER::FILTER4,
+            0x05 => MLX90393FILTER::FILTER5,
+            0x06 => MLX90393FILTER::FILTER6,
+            0x07 => MLX90393FILTER::FILTER7,
+            _ => panic!("Invalid MLX90393FILTER"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MLX90393OVERSAMPLING {
+    OSR0,
+    OSR1,
+    OSR2,
+    OSR3,
+}
+
+impl From<MLX90393OVERSAMPLING> for u8 {
+    fn from(oversampling: MLX90393OVERSAMPLING) -> Self {
+        oversampling as u8
+    }
+}
+
+impl From<u8> for MLX90393OVERSAMPLING {
+    fn from(oversampling: u8) -> Self {
+        match oversampling {
+            0x00 => MLX90393OVERSAMPLING::OSR0,
+            0x01 => MLX90393OVERSAMPLING::OSR1,
+            0x02 => MLX90393OVERSAMPLING::OSR2,
+            0x03 => MLX90393OVERSAMPLING::OSR3,
+            _ => panic!("Invalid MLX90393OVERSAMPLING"),
+        }
+    }
+}
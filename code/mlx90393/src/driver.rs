@@ -0,0 +1,984 @@
+use std::time::{Duration, Instant};
+
+use embedded_hal::delay::DelayNs;
+
+use crate::defs::*;
+use crate::error::MLX90393Error;
+use crate::interface::MLX90393Interface;
+
+
+// µT per LSB as (xy, z), indexed [HALLCONF][RES][GAIN_SEL].
+const GAIN_RES_CONVERSION: [[[(f32, f32); 8]; 4]; 2] = [
+    // HALLCONF - 0xC
+    [
+        [
+            (0.751, 1.210),
+            (0.601, 0.968),
+            (0.451, 0.726),
+            (0.376, 0.605),
+            (0.300, 0.484),
+            (0.250, 0.403),
+            (0.200, 0.323),
+            (0.150, 0.242),
+        ],
+        [
+            (1.502, 2.420),
+            (1.202, 1.936),
+            (0.901, 1.452),
+            (0.751, 1.210),
+            (0.601, 0.968),
+            (0.501, 0.807),
+            (0.401, 0.645),
+            (0.300, 0.484),
+        ],
+        [
+            (3.004, 4.840),
+            (2.403, 3.872),
+            (1.803, 2.904),
+            (1.502, 2.420),
+            (1.202, 1.936),
+            (1.001, 1.613),
+            (0.801, 1.291),
+            (0.601, 0.968),
+        ],
+        [
+            (6.009, 9.680),
+            (4.840, 7.744),
+            (3.605, 5.808),
+            (3.004, 4.840),
+            (2.403, 3.872),
+            (2.003, 3.227),
+            (1.602, 2.581),
+            (1.202, 1.936),
+        ],
+    ],
+    // HALLCONF - 0x0
+    [
+        [
+            (0.787, 1.267),
+            (0.629, 1.014),
+            (0.472, 0.760),
+            (0.393, 0.634),
+            (0.315, 0.507),
+            (0.262, 0.422),
+            (0.210, 0.338),
+            (0.157, 0.253),
+        ],
+        [
+            (1.573, 2.534),
+            (1.258, 2.027),
+            (0.944, 1.521),
+            (0.787, 1.267),
+            (0.629, 1.014),
+            (0.524, 0.845),
+            (0.419, 0.676),
+            (0.315, 0.507),
+        ],
+        [
+            (3.146, 5.068),
+            (2.517, 4.055),
+            (1.888, 3.041),
+            (1.573, 2.534),
+            (1.258, 2.027),
+            (1.049, 1.689),
+            (0.839, 1.352),
+            (0.629, 1.014),
+        ],
+        [
+            (6.292, 10.137),
+            (5.034, 8.109),
+            (3.775, 6.082),
+            (3.146, 5.068),
+            (2.517, 4.055),
+            (2.097, 3.379),
+            (1.678, 2.703),
+            (1.258, 2.027),
+        ],
+    ],
+];
+
+// Temperature: T = 35 + (TRAW - TREF) / 45.2 °C.
+const TEMPERATURE_REFERENCE: f32 = 35.0;
+const TEMPERATURE_SENSITIVITY: f32 = 45.2;
+
+// BURST_DATA_RATE step.
+const BURST_DATA_RATE_STEP_MS: u64 = 20;
+
+// HS and HR keep the chip busy for a while after the status.
+const MEMORY_STORE_TIME_MS: u32 = 15;
+const MEMORY_RECALL_TIME_MS: u32 = 2;
+
+// Self-test: readings averaged with the coil off and on, and the time the coil
+// field takes to settle.
+const SELF_TEST_SAMPLES: usize = 4;
+const SELF_TEST_SETTLE_MS: u32 = 10;
+// Accepted |delta| per axis in µT, (min, max) for X, Y and Z. The coil field is strongest
+// along Z but reaches every Hall plate, so a channel that does not move is dead. The
+// limits are wide enough for any gain and HALLCONF.
+const SELF_TEST_LIMITS: [(f32, f32); 3] = [(10.0, 200.0), (10.0, 200.0), (40.0, 1000.0)];
+
+// Bus errors are retried with a pause doubling from TRANSFER_BACKOFF_MS, the bus is
+// recovered before the last attempt.
+const TRANSFER_RETRIES: u32 = 3;
+const TRANSFER_BACKOFF_MS: u32 = 2;
+
+// Conversion timing, µs: standby to active, then per axis and end of conversion.
+const TIME_STANDBY_US: u64 = 264;
+const TIME_ACTIVE_US: u64 = 432;
+const TIME_CONVERSION_END_US: u64 = 102;
+// The internal oscillator may run slow, wait a bit longer than the typical time.
+const CONVERSION_MARGIN_PERCENT: u64 = 10;
+
+type DriverResult<T, IF> = Result<T, MLX90393Error<<IF as MLX90393Interface>::Error>>;
+
+/// Magnetic field in µT and die temperature in °C. Axes that were not converted are `None`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MLX90393Measurement {
+    pub x: Option<f32>,
+    pub y: Option<f32>,
+    pub z: Option<f32>,
+    pub temperature: Option<f32>,
+}
+
+/// Change of the field when the BIST coil is switched on, µT per axis.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MLX90393SelfTest {
+    pub passed: bool,
+    pub delta: [f32; 3],
+}
+
+/// Bus and chip failures of one sensor since boot.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MLX90393Health {
+    /// Commands that failed after all retries or were rejected by the chip.
+    pub errors: u32,
+    /// Transfers repeated after a bus error.
+    pub retries: u32,
+    /// Bus recoveries by clocking out SCL.
+    pub recoveries: u32,
+    /// Resets and reconfigurations after repeated failures.
+    pub resets: u32,
+    /// Failed measurements in a row, back to 0 after a good one.
+    pub consecutive_failures: u32,
+}
+
+/// Command and register layer of the MLX90393. The settings that drive the conversion
+/// to µT are cached, they are read from the chip the first time they are needed.
+pub struct MLX90393Driver<IF, D> {
+    interface: IF,
+    delay: D,
+    current_hallconf: Option<MLX90393HALLCONF>,
+    current_gain: Option<MLX90393GAIN>,
+    // The whole CONF3, it holds the three resolutions.
+    current_resolution: Option<u16>,
+    current_filter: Option<MLX90393FILTER>,
+    current_oversampling: Option<MLX90393OVERSAMPLING>,
+    current_temperature_oversampling: Option<MLX90393OVERSAMPLING>,
+    temperature_reference: Option<u16>,
+    current_temperature_compensation: Option<bool>,
+    // Wake-on-change thresholds (xy µT, z µT, t °C) written to the chip.
+    wakeup_thresholds: Option<(f32, f32, f32)>,
+    // Axes converted while measuring, and the ones of the running mode.
+    axes: MLX90393Axes,
+    mode_axes: MLX90393Axes,
+    // End of the conversion started by the last single measurement.
+    conversion_ready: Option<Instant>,
+    // OFFSET_X/Y/Z as held by the chip, in LSB.
+    chip_offset: Option<[i16; 3]>,
+    health: MLX90393Health,
+}
+
+impl<IF: MLX90393Interface, D: DelayNs> MLX90393Driver<IF, D> {
+    pub fn new(interface: IF, delay: D) -> Self {
+        Self {
+            interface,
+            delay,
+            current_hallconf: None,
+            current_gain: None,
+            current_resolution: None,
+            current_filter: None,
+            current_oversampling: None,
+            current_temperature_oversampling: None,
+            temperature_reference: None,
+            current_temperature_compensation: None,
+            wakeup_thresholds: None,
+            axes: MLX90393Axes::ALL,
+            mode_axes: MLX90393Axes::ALL,
+            conversion_ready: None,
+            chip_offset: None,
+            health: MLX90393Health::default(),
+        }
+    }
+
+    pub fn interface(&mut self) -> &mut IF {
+        &mut self.interface
+    }
+
+    pub fn health(&self) -> MLX90393Health {
+        self.health
+    }
+
+    /// The caller keeps `resets` and `consecutive_failures`, it is the one deciding to reset.
+    pub fn health_mut(&mut self) -> &mut MLX90393Health {
+        &mut self.health
+    }
+
+    /// `interface.transfer`, retried on bus errors. A slave holding SDA low fails every
+    /// retry, so the bus is recovered before the last one. The command may have landed
+    /// with only the response lost, so only the commands that can run twice are sent
+    /// again: a mode start is checked with a NOP first, HS is never repeated.
+    fn transfer(&mut self, command: MLX90393CMD, tx_buf: &[u8], rx_buf: &mut [u8]) -> DriverResult<(), IF> {
+        let mut backoff = TRANSFER_BACKOFF_MS;
+        let mut attempt = 0;
+
+        loop {
+            match self.interface.transfer(tx_buf, rx_buf) {
+                Ok(()) => return Ok(()),
+                Err(MLX90393Error::Bus(e)) if attempt < TRANSFER_RETRIES && command != MLX90393CMD::HS => {
+                    attempt += 1;
+                    self.health.retries += 1;
+                    log::debug!("Magnetometer: {:?} {:?}, retry {} in {} ms", command, e, attempt, backoff);
+
+                    self.delay.delay_ms(backoff);
+                    backoff *= 2;
+
+                    if attempt == TRANSFER_RETRIES {
+                        self.health.recoveries += 1;
+                        if let Err(e) = self.interface.recover() {
+                            log::warn!("Magnetometer: bus recovery failed: {:?}", e);
+                        }
+                    }
+
+                    // A second start of a running mode is answered with ERROR.
+                    if let Some(status) = self.mode_started(command) {
+                        rx_buf[0] = status.0;
+                        return Ok(());
+                    }
+                }
+                Err(MLX90393Error::Bus(e)) => {
+                    self.health.errors += 1;
+                    return Err(MLX90393Error::Bus(e));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Status of a NOP when `command` starts a mode and the chip already runs it.
+    fn mode_started(&mut self, command: MLX90393CMD) -> Option<MLX90393Status> {
+        let running: fn(&MLX90393Status) -> bool = match command {
+            MLX90393CMD::SB => MLX90393Status::burst_mode,
+            MLX90393CMD::SW => MLX90393Status::woc_mode,
+            MLX90393CMD::SM => MLX90393Status::sm_mode,
+            _ => return None,
+        };
+
+        let mut rx_buf: [u8; 1] = [0; 1];
+        self.interface.transfer(&[MLX90393CMD::NOP as u8], &mut rx_buf).ok()?;
+
+        let status = MLX90393Status(rx_buf[0]);
+        (!status.error() && running(&status)).then_some(status)
+    }
+
+    /// `transfer` and status check, the status byte is the first of `rx_buf`.
+    fn command(&mut self, command: MLX90393CMD, tx_buf: &[u8], rx_buf: &mut [u8]) -> DriverResult<MLX90393Status, IF> {
+        self.transfer(command, tx_buf, rx_buf)?;
+
+        let status = MLX90393Status(rx_buf[0]);
+        if status.error() {
+            self.health.errors += 1;
+            return Err(MLX90393Error::Chip { command, status });
+        }
+
+        if status.sed() {
+            log::warn!("Magnetometer: memory bit error detected, status {}", status);
+        }
+
+        if status.reset() {
+            log::debug!("Magnetometer: reset flag, status {}", status);
+        }
+
+        Ok(status)
+    }
+
+    /// NOP, the chip only answers with its status.
+    pub fn nop(&mut self) -> DriverResult<MLX90393Status, IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::NOP as u8];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::NOP, &tx_buf, &mut rx_buf)
+    }
+
+    pub fn read_register(&mut self, register: MLX90393REG) -> DriverResult<u16, IF> {
+        let tx_buf: [u8; 2] = [MLX90393CMD::RR.into(), (register as u8) << 2];
+        let mut rx_buf: [u8; 3] = [0; 3];
+
+        self.command(MLX90393CMD::RR, &tx_buf, &mut rx_buf)?;
+
+        let ret = (rx_buf[1] as u16) << 8 | rx_buf[2] as u16;
+
+        Ok(ret)
+    }
+
+    pub fn write_register(&mut self, register: MLX90393REG, value: u16) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 4] = [MLX90393CMD::WR.into(), ((value >> 8) & 0xFF) as u8, (value & 0xFF) as u8, (register as u8) << 2];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::WR, &tx_buf, &mut rx_buf)?;
+
+        Ok(())
+    }
+
+    /// Read-modify-write of a register, returns the written value.
+    pub fn modify_register(&mut self, register: MLX90393REG, modify: impl FnOnce(u16) -> u16) -> DriverResult<u16, IF> {
+        let value = modify(self.read_register(register)?);
+        self.write_register(register, value)?;
+        Ok(value)
+    }
+
+    pub fn read_field(&mut self, field: MLX90393Field) -> DriverResult<u16, IF> {
+        Ok(field.extract(self.read_register(field.register)?))
+    }
+
+    /// Updates one field, leaving the rest of the register untouched. Returns the written
+    /// register value.
+    pub fn write_field(&mut self, field: MLX90393Field, value: u16) -> DriverResult<u16, IF> {
+        if value > field.max() {
+            return Err(MLX90393Error::invalid_config(format!("value {} does not fit in {:?}", value, field)));
+        }
+
+        self.modify_register(field.register, |register| field.insert(register, value))
+    }
+
+    pub fn read_registers(&mut self) -> DriverResult<MLX90393Registers, IF> {
+        let mut registers = MLX90393Registers::default();
+        for register in MLX90393REG::ALL {
+            registers.values[register as usize] = self.read_register(register)?;
+        }
+        Ok(registers)
+    }
+
+    pub fn read_measurement(&mut self) -> DriverResult<MLX90393Measurement, IF> {
+        // A single measurement must be complete before it is read.
+        if let Some(ready) = self.conversion_ready.take() {
+            let now = Instant::now();
+            if ready > now {
+                self.delay.delay_us((ready - now).as_micros() as u32);
+            }
+        }
+
+        let axes = self.mode_axes;
+        let tx_buf: [u8; 1] = [MLX90393CMD::RM as u8 | axes.bits()];
+        let mut rx_buf: [u8; 9] = [0; 9];
+        // Status byte followed by one word per converted axis.
+        let len = 1 + 2 * axes.count();
+
+        let status = self.command(MLX90393CMD::RM, &tx_buf, &mut rx_buf[..len])?;
+
+        if status.response_length() != len - 1 {
+            return Err(MLX90393Error::UnexpectedMode { command: MLX90393CMD::RM, status });
+        }
+
+        // Words come in T, X, Y, Z order, skipping the axes not converted.
+        let mut words = rx_buf[1..len].chunks(2).map(|word| (word[0] as u16) << 8 | word[1] as u16);
+        let mut next = |axis: MLX90393AXIS| if axes.contains(axis) { words.next() } else { None };
+        let t = next(MLX90393AXIS::T);
+        let x = next(MLX90393AXIS::X);
+        let y = next(MLX90393AXIS::Y);
+        let z = next(MLX90393AXIS::Z);
+
+        let compensated = self.get_temperature_compensation()?;
+        let mut measurement = MLX90393Measurement::default();
+
+        for (raw, axis, value) in [
+            (x, MLX90393AXIS::X, &mut measurement.x),
+            (y, MLX90393AXIS::Y, &mut measurement.y),
+            (z, MLX90393AXIS::Z, &mut measurement.z),
+        ] {
+            if let Some(raw) = raw {
+                let resolution = self.get_resolution(axis)?;
+                *value = Some(decode_axis(raw, resolution, compensated) * self.sensitivity(axis)?);
+            }
+        }
+
+        if let Some(t) = t {
+            let temperature_reference = self.get_temperature_reference()?;
+            measurement.temperature = Some(TEMPERATURE_REFERENCE + (t as f32 - temperature_reference as f32) / TEMPERATURE_SENSITIVITY);
+        }
+
+        Ok(measurement)
+    }
+
+    /// Selects the axes converted while measuring, used from the next mode start.
+    /// BURST_SEL is kept in step for bursts started without an axis selection.
+    pub fn set_axes(&mut self, axes: MLX90393Axes) -> DriverResult<(), IF> {
+        if axes.is_empty() {
+            return Err(MLX90393Error::invalid_config("at least one axis must be selected"));
+        }
+
+        self.write_field(MLX90393Field::BURST_SEL, axes.bits() as u16)?;
+        self.axes = axes;
+        Ok(())
+    }
+
+    pub fn get_axes(&self) -> MLX90393Axes {
+        self.axes
+    }
+
+    /// Axes of the running mode, they may differ from `get_axes` until the mode is restarted.
+    pub fn mode_axes(&self) -> MLX90393Axes {
+        self.mode_axes
+    }
+
+    /// Interval between burst and wake-on-change conversions, in steps of 20 ms.
+    /// Zero converts continuously.
+    pub fn set_burst_data_rate(&mut self, interval: Duration) -> DriverResult<(), IF> {
+        let steps = (interval.as_millis() as u64).div_ceil(BURST_DATA_RATE_STEP_MS);
+        if steps > MLX90393Field::BURST_DATA_RATE.max() as u64 {
+            return Err(MLX90393Error::invalid_config(format!("burst interval {:?} too long", interval)));
+        }
+
+        self.write_field(MLX90393Field::BURST_DATA_RATE, steps as u16)?;
+        Ok(())
+    }
+
+    pub fn get_burst_data_rate(&mut self) -> DriverResult<Duration, IF> {
+        let steps = self.read_field(MLX90393Field::BURST_DATA_RATE)?;
+        Ok(Duration::from_millis(steps as u64 * BURST_DATA_RATE_STEP_MS))
+    }
+
+    /// µT per LSB of a magnetic axis at the current gain and resolution.
+    pub fn sensitivity(&mut self, axis: MLX90393AXIS) -> DriverResult<f32, IF> {
+        let hallconf = self.get_hallconf()?;
+        let gain = self.get_gain()?;
+        let resolution = self.get_resolution(axis)?;
+        Ok(axis_sensitivity(hallconf, resolution, gain, axis))
+    }
+
+    /// Share of the full scale used by the largest magnetic axis of `measurement`.
+    pub fn range_usage(&mut self, measurement: &MLX90393Measurement) -> DriverResult<f32, IF> {
+        let mut usage = 0.0f32;
+
+        for (value, axis) in [
+            (measurement.x, MLX90393AXIS::X),
+            (measurement.y, MLX90393AXIS::Y),
+            (measurement.z, MLX90393AXIS::Z),
+        ] {
+            if let Some(value) = value {
+                let full_scale = full_scale(self.get_resolution(axis)?) as f32;
+                usage = usage.max(value.abs() / (full_scale * self.sensitivity(axis)?));
+            }
+        }
+
+        Ok(usage)
+    }
+
+    /// Thresholds last written by `set_wakeup_thresholds`, `None` once a gain, resolution
+    /// or HALLCONF change made them stale.
+    pub fn wakeup_thresholds(&self) -> Option<(f32, f32, f32)> {
+        self.wakeup_thresholds
+    }
+
+    /// Writes the WOXY, WOZ and WOT thresholds, converted to LSB at the current gain and
+    /// resolution. A change larger than a threshold raises the interrupt in wake-on-change mode.
+    pub fn set_wakeup_thresholds(&mut self, xy: f32, z: f32, t: f32) -> DriverResult<(), IF> {
+        // X and Y share a threshold, use the finer sensitivity so neither axis wakes below `xy`.
+        let xy_sensitivity = self.sensitivity(MLX90393AXIS::X)?.min(self.sensitivity(MLX90393AXIS::Y)?);
+        let z_sensitivity = self.sensitivity(MLX90393AXIS::Z)?;
+
+        let to_lsb = |value: f32, sensitivity: f32| (value / sensitivity).round().clamp(1.0, u16::MAX as f32) as u16;
+
+        self.write_register(MLX90393REG::WOXY_THRESHOLD, to_lsb(xy, xy_sensitivity))?;
+        self.write_register(MLX90393REG::WOZ_THRESHOLD, to_lsb(z, z_sensitivity))?;
+        self.write_register(MLX90393REG::WOT_THRESHOLD, to_lsb(t, 1.0 / TEMPERATURE_SENSITIVITY))?;
+
+        self.wakeup_thresholds = Some((xy, z, t));
+
+        log::debug!("Magnetometer: Wake-up thresholds {} uT xy, {} uT z, {} C", xy, z, t);
+        Ok(())
+    }
+
+    /// TREF, the raw temperature at 35 °C. It never changes so it is read only once.
+    pub fn get_temperature_reference(&mut self) -> DriverResult<u16, IF> {
+        if let Some(reference) = self.temperature_reference {
+            return Ok(reference);
+        }
+
+        let reference = self.read_register(MLX90393REG::TREF)?;
+        self.temperature_reference = Some(reference);
+        Ok(reference)
+    }
+
+    /// Enables TCMP_EN. The chip then compensates the magnetic axes with the SENS_TC
+    /// coefficients, and the outputs switch to the unsigned format.
+    pub fn set_temperature_compensation(&mut self, enabled: bool) -> DriverResult<(), IF> {
+        self.write_field(MLX90393Field::TCMP_EN, enabled as u16)?;
+
+        self.current_temperature_compensation = Some(enabled);
+        Ok(())
+    }
+
+    pub fn get_temperature_compensation(&mut self) -> DriverResult<bool, IF> {
+        if let Some(enabled) = self.current_temperature_compensation {
+            return Ok(enabled);
+        }
+
+        let enabled = self.read_field(MLX90393Field::TCMP_EN)? != 0;
+        self.current_temperature_compensation = Some(enabled);
+        Ok(enabled)
+    }
+
+    /// Hall plate spinning configuration, it changes the sensitivity and the timing.
+    pub fn set_hallconf(&mut self, new_hallconf: MLX90393HALLCONF) -> DriverResult<(), IF> {
+        self.write_field(MLX90393Field::HALLCONF, new_hallconf as u16)?;
+
+        self.current_hallconf = Some(new_hallconf);
+        self.wakeup_thresholds = None;
+
+        Ok(())
+    }
+
+    pub fn get_hallconf(&mut self) -> DriverResult<MLX90393HALLCONF, IF> {
+        if let Some(hallconf) = self.current_hallconf {
+            return Ok(hallconf);
+        }
+
+        let hallconf = decode_hallconf(self.read_field(MLX90393Field::HALLCONF)?)?;
+        self.current_hallconf = Some(hallconf);
+        Ok(hallconf)
+    }
+
+    pub fn set_gain(&mut self, new_gain: MLX90393GAIN) -> DriverResult<(), IF> {
+        self.write_field(MLX90393Field::GAIN_SEL, new_gain as u16)?;
+
+        self.current_gain = Some(new_gain);
+        // Thresholds are written in LSB, they must follow the sensitivity.
+        self.wakeup_thresholds = None;
+
+        Ok(())
+    }
+
+    pub fn get_gain(&mut self) -> DriverResult<MLX90393GAIN, IF> {
+        if let Some(gain) = self.current_gain {
+            return Ok(gain);
+        }
+
+        let gain = MLX90393GAIN::from(self.read_field(MLX90393Field::GAIN_SEL)? as u8);
+        self.current_gain = Some(gain);
+        Ok(gain)
+    }
+
+    pub fn set_resolution(&mut self, axis: MLX90393AXIS, new_resolution: MLX90393RESOLUTION) -> DriverResult<(), IF> {
+        let field = match MLX90393Field::resolution(axis) {
+            Some(field) => field,
+            None => return Err(MLX90393Error::invalid_config("set_resolution failed, only X, Y or Z allowed here.")),
+        };
+
+        let resolution = self.write_field(field, new_resolution as u16)?;
+
+        self.current_resolution = Some(resolution);
+        self.wakeup_thresholds = None;
+
+        Ok(())
+    }
+
+    pub fn get_resolution(&mut self, axis: MLX90393AXIS) -> DriverResult<MLX90393RESOLUTION, IF> {
+        let field = match MLX90393Field::resolution(axis) {
+            Some(field) => field,
+            None => return Err(MLX90393Error::invalid_config("get_resolution failed, only X, Y or Z allowed here.")),
+        };
+
+        let resolution = match self.current_resolution {
+            Some(conf3) => conf3,
+            None => {
+                let conf3 = self.read_register(MLX90393REG::CONF3)?;
+                self.current_resolution = Some(conf3);
+                conf3
+            }
+        };
+
+        Ok(MLX90393RESOLUTION::from(field.extract(resolution) as u8))
+    }
+
+    pub fn set_filter(&mut self, new_filter: MLX90393FILTER) -> DriverResult<(), IF> {
+        let oversampling = self.get_oversampling()?;
+        check_filter_oversampling(new_filter, oversampling)?;
+
+        let conf3 = self.write_field(MLX90393Field::DIG_FILT, new_filter as u16)?;
+
+        self.current_filter = Some(new_filter);
+        self.update_cached_conf3(conf3);
+        Ok(())
+    }
+
+    pub fn get_filter(&mut self) -> DriverResult<MLX90393FILTER, IF> {
+        if let Some(filter) = self.current_filter {
+            return Ok(filter);
+        }
+
+        let filter = MLX90393FILTER::from(self.read_field(MLX90393Field::DIG_FILT)? as u8);
+        self.current_filter = Some(filter);
+        Ok(filter)
+    }
+
+    pub fn set_oversampling(&mut self, new_oversampling: MLX90393OVERSAMPLING) -> DriverResult<(), IF> {
+        let filter = self.get_filter()?;
+        check_filter_oversampling(filter, new_oversampling)?;
+
+        let conf3 = self.write_field(MLX90393Field::OSR, new_oversampling as u16)?;
+
+        self.current_oversampling = Some(new_oversampling);
+        self.update_cached_conf3(conf3);
+        Ok(())
+    }
+
+    pub fn get_oversampling(&mut self) -> DriverResult<MLX90393OVERSAMPLING, IF> {
+        if let Some(oversampling) = self.current_oversampling {
+            return Ok(oversampling);
+        }
+
+        let oversampling = MLX90393OVERSAMPLING::from(self.read_field(MLX90393Field::OSR)? as u8);
+        self.current_oversampling = Some(oversampling);
+        Ok(oversampling)
+    }
+
+    /// OSR2, oversampling of the temperature conversion.
+    pub fn set_temperature_oversampling(&mut self, new_oversampling: MLX90393OVERSAMPLING) -> DriverResult<(), IF> {
+        let conf3 = self.write_field(MLX90393Field::OSR2, new_oversampling as u16)?;
+
+        self.current_temperature_oversampling = Some(new_oversampling);
+        self.update_cached_conf3(conf3);
+        Ok(())
+    }
+
+    pub fn get_temperature_oversampling(&mut self) -> DriverResult<MLX90393OVERSAMPLING, IF> {
+        if let Some(oversampling) = self.current_temperature_oversampling {
+            return Ok(oversampling);
+        }
+
+        let oversampling = MLX90393OVERSAMPLING::from(self.read_field(MLX90393Field::OSR2)? as u8);
+        self.current_temperature_oversampling = Some(oversampling);
+        Ok(oversampling)
+    }
+
+    /// Time a measurement of `axes` takes, from the datasheet:
+    /// TCONVM = 67 + 64 * 2^OSR * (2 + 2^DIG_FILT) µs per magnetic axis,
+    /// TCONVT = 67 + 192 * 2^OSR2 µs for the temperature.
+    pub fn conversion_time(&mut self, axes: MLX90393Axes) -> DriverResult<Duration, IF> {
+        let oversampling = self.get_oversampling()? as u32;
+        let filter = self.get_filter()? as u32;
+
+        // HALLCONF 0x0 spins the plates through twice as many phases.
+        let phases = match self.get_hallconf()? {
+            MLX90393HALLCONF::HALLCONF_C => 1,
+            MLX90393HALLCONF::HALLCONF_0 => 2,
+        };
+
+        let magnetic = 67 + phases * 64 * 2u64.pow(oversampling) * (2 + 2u64.pow(filter));
+        let magnetic_axes = axes.count() as u64 - axes.contains(MLX90393AXIS::T) as u64;
+
+        let mut time = TIME_STANDBY_US + TIME_ACTIVE_US + magnetic_axes * magnetic + TIME_CONVERSION_END_US;
+        if axes.contains(MLX90393AXIS::T) {
+            let temperature_oversampling = self.get_temperature_oversampling()? as u32;
+            time += 67 + 192 * 2u64.pow(temperature_oversampling);
+        }
+
+        Ok(Duration::from_micros(time * (100 + CONVERSION_MARGIN_PERCENT) / 100))
+    }
+
+    // The resolution cache holds the whole CONF3, keep it in step with the other CONF3 writes.
+    fn update_cached_conf3(&mut self, conf3: u16) {
+        if self.current_resolution.is_some() {
+            self.current_resolution = Some(conf3);
+        }
+    }
+
+    pub fn set_trigger_interval(&mut self, state: bool) -> DriverResult<(), IF> {
+        self.write_field(MLX90393Field::TRIG_INT, state as u16)?;
+
+        Ok(())
+    }
+
+    pub fn start_single_measurement(&mut self) -> DriverResult<(), IF> {
+        let axes = self.axes;
+        self.start_single_measurement_axes(axes)
+    }
+
+    /// Same as `start_single_measurement` with an explicit axis selection.
+    pub fn start_single_measurement_axes(&mut self, axes: MLX90393Axes) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::SM as u8 | axes.bits()];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        let status = self.command(MLX90393CMD::SM, &tx_buf, &mut rx_buf)?;
+
+        if !status.sm_mode() {
+            return Err(MLX90393Error::UnexpectedMode { command: MLX90393CMD::SM, status });
+        }
+
+        self.mode_axes = axes;
+        self.conversion_ready = Some(Instant::now() + self.conversion_time(axes)?);
+
+        Ok(())
+    }
+
+    pub fn start_burst_measurement(&mut self) -> DriverResult<(), IF> {
+        let axes = self.axes;
+        self.start_burst_measurement_axes(axes)
+    }
+
+    /// Same as `start_burst_measurement` with an explicit axis selection.
+    pub fn start_burst_measurement_axes(&mut self, axes: MLX90393Axes) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::SB as u8 | axes.bits()];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        let status = self.command(MLX90393CMD::SB, &tx_buf, &mut rx_buf)?;
+
+        if !status.burst_mode() {
+            return Err(MLX90393Error::UnexpectedMode { command: MLX90393CMD::SB, status });
+        }
+
+        self.mode_axes = axes;
+        self.conversion_ready = None;
+
+        Ok(())
+    }
+
+    /// WOC_DIFF (CONF2 bit 12): compare every wake-on-change measurement with the previous
+    /// one instead of the first one.
+    pub fn set_wakeup_comparator(&mut self, comparator: bool) -> DriverResult<(), IF> {
+        self.write_field(MLX90393Field::WOC_DIFF, comparator as u16)?;
+        Ok(())
+    }
+
+    pub fn start_wakeup_measurement(&mut self) -> DriverResult<(), IF> {
+        let axes = self.axes;
+        self.start_wakeup_measurement_axes(axes)
+    }
+
+    /// Same as `start_wakeup_measurement` with an explicit axis selection.
+    pub fn start_wakeup_measurement_axes(&mut self, axes: MLX90393Axes) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::SW as u8 | axes.bits()];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        let status = self.command(MLX90393CMD::SW, &tx_buf, &mut rx_buf)?;
+
+        if !status.woc_mode() {
+            return Err(MLX90393Error::UnexpectedMode { command: MLX90393CMD::SW, status });
+        }
+
+        self.mode_axes = axes;
+        self.conversion_ready = None;
+
+        Ok(())
+    }
+
+    pub fn exit_mode(&mut self) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::EX as u8];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::EX, &tx_buf, &mut rx_buf)?;
+        self.conversion_ready = None;
+
+        Ok(())
+    }
+
+    /// RT: the chip starts over from the stored registers, the cached settings are dropped.
+    pub fn reset(&mut self) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::RT as u8];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::RT, &tx_buf, &mut rx_buf)?;
+
+        self.clear_cache();
+
+        Ok(())
+    }
+
+    /// Fills the cached settings from a register snapshot.
+    pub fn load_registers(&mut self, registers: &MLX90393Registers) -> DriverResult<(), IF> {
+        let field = |field: MLX90393Field| registers.field(field).unwrap_or(0);
+
+        self.current_hallconf = Some(decode_hallconf(field(MLX90393Field::HALLCONF))?);
+        self.current_gain = Some(MLX90393GAIN::from(field(MLX90393Field::GAIN_SEL) as u8));
+        self.current_resolution = registers.get(MLX90393REG::CONF3);
+        self.current_filter = Some(MLX90393FILTER::from(field(MLX90393Field::DIG_FILT) as u8));
+        self.current_oversampling = Some(MLX90393OVERSAMPLING::from(field(MLX90393Field::OSR) as u8));
+        self.current_temperature_oversampling = Some(MLX90393OVERSAMPLING::from(field(MLX90393Field::OSR2) as u8));
+        self.current_temperature_compensation = Some(field(MLX90393Field::TCMP_EN) != 0);
+        self.chip_offset = Some([
+            field(MLX90393Field::OFFSET_X) as i16,
+            field(MLX90393Field::OFFSET_Y) as i16,
+            field(MLX90393Field::OFFSET_Z) as i16,
+        ]);
+        self.wakeup_thresholds = None;
+
+        Ok(())
+    }
+
+    /// Offsets are two's complement, subtracted from the compensated output.
+    pub fn write_offsets(&mut self, offset: [i16; 3]) -> DriverResult<(), IF> {
+        self.write_register(MLX90393REG::OFFSET_X, offset[0] as u16)?;
+        self.write_register(MLX90393REG::OFFSET_Y, offset[1] as u16)?;
+        self.write_register(MLX90393REG::OFFSET_Z, offset[2] as u16)?;
+
+        self.chip_offset = Some(offset);
+        Ok(())
+    }
+
+    pub fn read_offsets(&mut self) -> DriverResult<[i16; 3], IF> {
+        let offset = [
+            self.read_register(MLX90393REG::OFFSET_X)? as i16,
+            self.read_register(MLX90393REG::OFFSET_Y)? as i16,
+            self.read_register(MLX90393REG::OFFSET_Z)? as i16,
+        ];
+
+        self.chip_offset = Some(offset);
+        Ok(offset)
+    }
+
+    /// OFFSET_X/Y/Z as last written or read, `None` when not known.
+    pub fn chip_offset(&self) -> Option<[i16; 3]> {
+        self.chip_offset
+    }
+
+    /// Field the chip subtracts from its output, in µT. Zero without TCMP_EN.
+    pub fn applied_offset(&mut self) -> DriverResult<[f32; 3], IF> {
+        let compensated = self.get_temperature_compensation()?;
+        let offset = match self.chip_offset {
+            Some(offset) if compensated => offset,
+            _ => return Ok([0.0; 3]),
+        };
+
+        Ok([
+            offset[0] as f32 * self.sensitivity(MLX90393AXIS::X)?,
+            offset[1] as f32 * self.sensitivity(MLX90393AXIS::Y)?,
+            offset[2] as f32 * self.sensitivity(MLX90393AXIS::Z)?,
+        ])
+    }
+
+    /// Built-in self-test: the BIST coil adds a known field and the change on every axis
+    /// must be within `SELF_TEST_LIMITS`. The chip must be idle.
+    pub fn self_test(&mut self) -> DriverResult<MLX90393SelfTest, IF> {
+        let off = self.average_field(SELF_TEST_SAMPLES)?;
+
+        self.write_field(MLX90393Field::BIST, 1)?;
+        self.delay.delay_ms(SELF_TEST_SETTLE_MS);
+        let on = self.average_field(SELF_TEST_SAMPLES);
+        // The coil is switched off whatever the measurement did.
+        self.write_field(MLX90393Field::BIST, 0)?;
+        let on = on?;
+
+        let delta = [on[0] - off[0], on[1] - off[1], on[2] - off[2]];
+        let passed = delta
+            .iter()
+            .zip(SELF_TEST_LIMITS.iter())
+            .all(|(value, (min, max))| (*min..=*max).contains(&value.abs()));
+
+        Ok(MLX90393SelfTest { passed, delta })
+    }
+
+    // Mean of single XYZ measurements.
+    fn average_field(&mut self, samples: usize) -> DriverResult<[f32; 3], IF> {
+        let mut sum = [0.0f32; 3];
+
+        for _ in 0..samples {
+            self.start_single_measurement_axes(MLX90393Axes::XYZ)?;
+            let measurement = self.read_measurement()?;
+            sum[0] += measurement.x.unwrap_or(0.0);
+            sum[1] += measurement.y.unwrap_or(0.0);
+            sum[2] += measurement.z.unwrap_or(0.0);
+        }
+
+        let samples = samples as f32;
+        Ok([sum[0] / samples, sum[1] / samples, sum[2] / samples])
+    }
+
+    /// HS: copies the volatile registers to the non-volatile memory. The memory takes a
+    /// limited number of writes, store only what changed.
+    pub fn memory_store(&mut self) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::HS as u8];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::HS, &tx_buf, &mut rx_buf)?;
+        self.delay.delay_ms(MEMORY_STORE_TIME_MS);
+
+        Ok(())
+    }
+
+    /// HR: reloads the volatile registers from the non-volatile memory. The cached
+    /// settings are dropped, they are read again when needed.
+    pub fn memory_recall(&mut self) -> DriverResult<(), IF> {
+        let tx_buf: [u8; 1] = [MLX90393CMD::HR as u8];
+        let mut rx_buf: [u8; 1] = [0; 1];
+
+        self.command(MLX90393CMD::HR, &tx_buf, &mut rx_buf)?;
+        self.delay.delay_ms(MEMORY_RECALL_TIME_MS);
+
+        self.clear_cache();
+
+        Ok(())
+    }
+
+    // Settings that follow the registers, TREF is factory programmed and stays.
+    fn clear_cache(&mut self) {
+        self.current_hallconf = None;
+        self.current_gain = None;
+        self.current_resolution = None;
+        self.current_filter = None;
+        self.current_oversampling = None;
+        self.current_temperature_oversampling = None;
+        self.current_temperature_compensation = None;
+        self.chip_offset = None;
+        self.wakeup_thresholds = None;
+        self.conversion_ready = None;
+    }
+}
+
+/// The datasheet forbids the shortest conversions: OSR 0 with DIG_FILT 0 or 1, and
+/// OSR 1 with DIG_FILT 0.
+pub fn check_filter_oversampling<E>(filter: MLX90393FILTER, oversampling: MLX90393OVERSAMPLING) -> Result<(), MLX90393Error<E>> {
+    let forbidden = matches!(
+        (oversampling, filter),
+        (MLX90393OVERSAMPLING::OSR0, MLX90393FILTER::FILTER0 | MLX90393FILTER::FILTER1) | (MLX90393OVERSAMPLING::OSR1, MLX90393FILTER::FILTER0)
+    );
+
+    if forbidden {
+        return Err(MLX90393Error::invalid_config(format!("{:?} with {:?} is not allowed", oversampling, filter)));
+    }
+
+    Ok(())
+}
+
+/// µT per LSB of `axis` for a configuration, from the datasheet sensitivity table.
+pub fn axis_sensitivity(hallconf: MLX90393HALLCONF, resolution: MLX90393RESOLUTION, gain: MLX90393GAIN, axis: MLX90393AXIS) -> f32 {
+    let table = match hallconf {
+        MLX90393HALLCONF::HALLCONF_C => 0,
+        MLX90393HALLCONF::HALLCONF_0 => 1,
+    };
+    let (xy, z) = GAIN_RES_CONVERSION[table][resolution as usize][gain as usize];
+    if axis == MLX90393AXIS::Z { z } else { xy }
+}
+
+/// Largest output magnitude in LSB. RES19 spans half the codes of the other resolutions
+/// around its zero.
+pub fn full_scale(resolution: MLX90393RESOLUTION) -> i32 {
+    match resolution {
+        MLX90393RESOLUTION::RES19 => 16383,
+        _ => 32767,
+    }
+}
+
+/// Raw axis value relative to zero field. RES16 and RES17 are signed, RES18 and RES19
+/// are unsigned around 0x8000 and 0x4000. Temperature compensation makes every
+/// resolution unsigned.
+fn decode_axis(raw: u16, resolution: MLX90393RESOLUTION, compensated: bool) -> f32 {
+    match (resolution, compensated) {
+        (MLX90393RESOLUTION::RES19, _) => raw as f32 - 16384.0,
+        (MLX90393RESOLUTION::RES18, _) | (_, true) => raw as f32 - 32768.0,
+        _ => raw as i16 as f32,
+    }
+}
+
+fn decode_hallconf<E>(hallconf: u16) -> Result<MLX90393HALLCONF, MLX90393Error<E>> {
+    MLX90393HALLCONF::try_from(hallconf as u8).map_err(|hallconf| MLX90393Error::invalid_config(format!("unsupported HALLCONF 0x{:X}", hallconf)))
+}
@@ -0,0 +1,349 @@
+use std::sync::{Arc, Mutex};
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+use crate::defs::*;
+use crate::driver::{axis_sensitivity, full_scale};
+
+// Registers after a power-up with an erased memory: GAIN_SEL 7 and HALLCONF 0xC.
+const POWER_UP_REGISTERS: [u16; 10] = [0x007C, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+// A typical factory TREF.
+const DEFAULT_TREF: u16 = 0xB668;
+
+// Field added by the BIST coil, µT. Mostly along Z.
+const DEFAULT_BIST_FIELD: [f32; 3] = [30.0, 30.0, 120.0];
+
+const TEMPERATURE_SENSITIVITY: f32 = 45.2;
+
+/// Measurement mode of the emulated chip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmulatorMode {
+    Idle,
+    Single,
+    Burst,
+    WakeOnChange,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorError(pub ErrorKind);
+
+impl embedded_hal::i2c::Error for EmulatorError {
+    fn kind(&self) -> ErrorKind {
+        self.0
+    }
+}
+
+/// MLX90393 on an I2C bus, at register level: commands, status byte, modes, the
+/// volatile and non-volatile registers, and measurements converted from a field set
+/// by the test. Clones share the same chip, keep one to drive it while the driver
+/// owns another.
+#[derive(Clone)]
+pub struct MLX90393Emulator {
+    state: Arc<Mutex<EmulatorState>>,
+}
+
+struct EmulatorState {
+    address: u8,
+    registers: [u16; 10],
+    memory: [u16; 10],
+    tref: u16,
+    mode: EmulatorMode,
+    mode_axes: u8,
+    // Set by RT, reported in the next status and cleared.
+    reset_flag: bool,
+    response: Vec<u8>,
+    field: [f32; 3],
+    temperature: f32,
+    bist_field: [f32; 3],
+    nacks: u32,
+    lost_responses: u32,
+    rejected_commands: u32,
+    stores: u32,
+    register_writes: u32,
+}
+
+impl MLX90393Emulator {
+    pub fn new(address: u8) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(EmulatorState {
+                address,
+                registers: POWER_UP_REGISTERS,
+                memory: POWER_UP_REGISTERS,
+                tref: DEFAULT_TREF,
+                mode: EmulatorMode::Idle,
+                mode_axes: 0,
+                reset_flag: true,
+                response: Vec::new(),
+                field: [0.0; 3],
+                temperature: 35.0,
+                bist_field: DEFAULT_BIST_FIELD,
+                nacks: 0,
+                lost_responses: 0,
+                rejected_commands: 0,
+                stores: 0,
+                register_writes: 0,
+            })),
+        }
+    }
+
+    /// Field seen by the sensor, µT.
+    pub fn set_field(&self, x: f32, y: f32, z: f32) {
+        self.state.lock().unwrap().field = [x, y, z];
+    }
+
+    /// Die temperature, °C.
+    pub fn set_temperature(&self, temperature: f32) {
+        self.state.lock().unwrap().temperature = temperature;
+    }
+
+    /// Field added while BIST is set, zero emulates a broken coil.
+    pub fn set_bist_field(&self, x: f32, y: f32, z: f32) {
+        self.state.lock().unwrap().bist_field = [x, y, z];
+    }
+
+    /// The next `count` transfers are not acknowledged.
+    pub fn nack_next(&self, count: u32) {
+        self.state.lock().unwrap().nacks = count;
+    }
+
+    /// The next `count` commands are executed but their response is not acknowledged.
+    pub fn lose_next_response(&self, count: u32) {
+        self.state.lock().unwrap().lost_responses = count;
+    }
+
+    /// The next `count` commands are answered with the ERROR flag.
+    pub fn reject_next(&self, count: u32) {
+        self.state.lock().unwrap().rejected_commands = count;
+    }
+
+    /// Power cycle: the registers come back from the non-volatile memory.
+    pub fn power_cycle(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.registers = state.memory;
+        state.mode = EmulatorMode::Idle;
+        state.reset_flag = true;
+    }
+
+    pub fn register(&self, register: MLX90393REG) -> u16 {
+        self.state.lock().unwrap().registers[register as usize]
+    }
+
+    pub fn stored_register(&self, register: MLX90393REG) -> u16 {
+        self.state.lock().unwrap().memory[register as usize]
+    }
+
+    pub fn mode(&self) -> EmulatorMode {
+        self.state.lock().unwrap().mode
+    }
+
+    /// HS commands received, each one wears the non-volatile memory.
+    pub fn stores(&self) -> u32 {
+        self.state.lock().unwrap().stores
+    }
+
+    /// WR commands received.
+    pub fn register_writes(&self) -> u32 {
+        self.state.lock().unwrap().register_writes
+    }
+}
+
+impl EmulatorState {
+    fn execute(&mut self, command: &[u8]) -> Vec<u8> {
+        let Some(&first) = command.first() else {
+            return vec![self.status(MLX90393Status::ERROR)];
+        };
+
+        if self.rejected_commands > 0 {
+            self.rejected_commands -= 1;
+            return vec![self.status(MLX90393Status::ERROR)];
+        }
+
+        let axes = first & 0x0F;
+
+        match (first & 0xF0, command.len()) {
+            (0x10, 1) => self.start_mode(EmulatorMode::Burst, axes),
+            (0x20, 1) => self.start_mode(EmulatorMode::WakeOnChange, axes),
+            (0x30, 1) => self.start_mode(EmulatorMode::Single, axes),
+            (0x40, 1) => self.read_measurement(axes),
+            (0x50, 2) => self.read_register(command[1] >> 2),
+            (0x60, 4) => self.write_register(command[3] >> 2, (command[1] as u16) << 8 | command[2] as u16),
+            (0x80, 1) => {
+                self.mode = EmulatorMode::Idle;
+                vec![self.status(0)]
+            }
+            (0x70, 1) => {
+                self.memory = self.registers;
+                self.stores += 1;
+                vec![self.status(0)]
+            }
+            (0xD0, 1) => {
+                self.registers = self.memory;
+                vec![self.status(0)]
+            }
+            (0xF0, 1) => {
+                self.registers = self.memory;
+                self.mode = EmulatorMode::Idle;
+                let status = self.status(0);
+                self.reset_flag = true;
+                vec![status]
+            }
+            (0x00, 1) => vec![self.status(0)],
+            _ => vec![self.status(MLX90393Status::ERROR)],
+        }
+    }
+
+    // Mode bits of the current mode, the reset flag and `flags`.
+    fn status(&mut self, flags: u8) -> u8 {
+        let mode = match self.mode {
+            EmulatorMode::Idle => 0,
+            EmulatorMode::Single => MLX90393Status::SM_MODE,
+            EmulatorMode::Burst => MLX90393Status::BURST_MODE,
+            EmulatorMode::WakeOnChange => MLX90393Status::WOC_MODE,
+        };
+        let reset = if self.reset_flag { MLX90393Status::RS } else { 0 };
+        self.reset_flag = false;
+        mode | reset | flags
+    }
+
+    fn start_mode(&mut self, mode: EmulatorMode, axes: u8) -> Vec<u8> {
+        if self.mode != EmulatorMode::Idle {
+            return vec![self.status(MLX90393Status::ERROR)];
+        }
+
+        // Without an axis selection bursts convert the BURST_SEL axes.
+        let burst_sel = MLX90393Field::BURST_SEL.extract(self.registers[MLX90393REG::CONF2 as usize]) as u8;
+        self.mode_axes = if axes == 0 && mode != EmulatorMode::Single { burst_sel } else { axes };
+        self.mode = mode;
+        vec![self.status(0)]
+    }
+
+    fn read_measurement(&mut self, axes: u8) -> Vec<u8> {
+        // Only what the running mode converted can be read.
+        if self.mode == EmulatorMode::Idle || axes == 0 || axes & !self.mode_axes != 0 {
+            return vec![self.status(MLX90393Status::ERROR)];
+        }
+
+        let words: Vec<u16> = [MLX90393AXIS::T, MLX90393AXIS::X, MLX90393AXIS::Y, MLX90393AXIS::Z]
+            .into_iter()
+            .filter(|axis| axes & *axis as u8 != 0)
+            .map(|axis| self.convert(axis))
+            .collect();
+
+        let status = self.status((words.len() - 1) as u8);
+        // A single measurement is read once, the chip then goes back to idle.
+        if self.mode == EmulatorMode::Single {
+            self.mode = EmulatorMode::Idle;
+        }
+
+        let mut response = vec![status];
+        for word in words {
+            response.extend_from_slice(&word.to_be_bytes());
+        }
+        response
+    }
+
+    // Output code of an axis with the current configuration.
+    fn convert(&self, axis: MLX90393AXIS) -> u16 {
+        if axis == MLX90393AXIS::T {
+            let code = self.tref as f32 + (self.temperature - 35.0) * TEMPERATURE_SENSITIVITY;
+            return code.round().clamp(0.0, u16::MAX as f32) as u16;
+        }
+
+        let field = |field: MLX90393Field| field.extract(self.registers[field.register as usize]);
+        let index = match axis {
+            MLX90393AXIS::X => 0,
+            MLX90393AXIS::Y => 1,
+            _ => 2,
+        };
+
+        let hallconf = MLX90393HALLCONF::try_from(field(MLX90393Field::HALLCONF) as u8).unwrap_or(MLX90393HALLCONF::HALLCONF_C);
+        let gain = MLX90393GAIN::from(field(MLX90393Field::GAIN_SEL) as u8);
+        let resolution = MLX90393RESOLUTION::from(field(MLX90393Field::resolution(axis).unwrap()) as u8);
+        let compensated = field(MLX90393Field::TCMP_EN) != 0;
+        let bist = if field(MLX90393Field::BIST) != 0 { self.bist_field[index] } else { 0.0 };
+
+        let mut lsb = ((self.field[index] + bist) / axis_sensitivity(hallconf, resolution, gain, axis)).round() as i32;
+        if compensated {
+            lsb -= self.registers[MLX90393REG::OFFSET_X as usize + index] as i16 as i32;
+        }
+
+        let limit = full_scale(resolution);
+        let lsb = lsb.clamp(-limit, limit);
+
+        match (resolution, compensated) {
+            (MLX90393RESOLUTION::RES19, _) => (lsb + 16384) as u16,
+            (MLX90393RESOLUTION::RES18, _) | (_, true) => (lsb + 32768) as u16,
+            _ => lsb as i16 as u16,
+        }
+    }
+
+    fn read_register(&mut self, address: u8) -> Vec<u8> {
+        let value = match address {
+            address if (address as usize) < self.registers.len() => self.registers[address as usize],
+            address if address == MLX90393REG::TREF as u8 => self.tref,
+            _ => return vec![self.status(MLX90393Status::ERROR)],
+        };
+
+        let mut response = vec![self.status(0)];
+        response.extend_from_slice(&value.to_be_bytes());
+        response
+    }
+
+    fn write_register(&mut self, address: u8, value: u16) -> Vec<u8> {
+        if address as usize >= self.registers.len() {
+            return vec![self.status(MLX90393Status::ERROR)];
+        }
+
+        self.registers[address as usize] = value;
+        self.register_writes += 1;
+        vec![self.status(0)]
+    }
+}
+
+impl ErrorType for MLX90393Emulator {
+    type Error = EmulatorError;
+}
+
+impl I2c for MLX90393Emulator {
+    fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Self::Error> {
+        let mut state = self.state.lock().unwrap();
+
+        if address != state.address {
+            return Err(EmulatorError(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)));
+        }
+
+        if state.nacks > 0 {
+            state.nacks -= 1;
+            return Err(EmulatorError(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)));
+        }
+
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => state.response = state.execute(bytes),
+                Operation::Read(_) if state.lost_responses > 0 => {
+                    state.lost_responses -= 1;
+                    return Err(EmulatorError(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Data)));
+                }
+                Operation::Read(buffer) => {
+                    // Past the response the chip clocks out zeros.
+                    let response = std::mem::take(&mut state.response);
+                    for (index, byte) in buffer.iter_mut().enumerate() {
+                        *byte = response.get(index).copied().unwrap_or(0);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Delay that returns at once, the emulator has no conversion time.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoDelay;
+
+impl DelayNs for NoDelay {
+    fn delay_ns(&mut self, _ns: u32) {}
+}
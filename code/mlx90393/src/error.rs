@@ -0,0 +1,59 @@
+use std::fmt;
+
+use crate::defs::{MLX90393CMD, MLX90393Status};
+
+/// `E` is the error of the bus the chip is wired to.
+#[derive(Debug)]
+pub enum MLX90393Error<E> {
+    /// The bus transfer itself failed.
+    Bus(E),
+    /// The chip answered with the ERROR flag set.
+    Chip { command: MLX90393CMD, status: MLX90393Status },
+    /// The chip did not enter the requested mode, or the response does not match the command.
+    UnexpectedMode { command: MLX90393CMD, status: MLX90393Status },
+    /// A setting out of range or not allowed by the datasheet, nothing was sent.
+    InvalidConfig(String),
+}
+
+impl<E> MLX90393Error<E> {
+    pub fn invalid_config(message: impl Into<String>) -> Self {
+        MLX90393Error::InvalidConfig(message.into())
+    }
+
+    /// Converts the bus error, the other variants are kept.
+    pub fn map_bus<F>(self, map: impl FnOnce(E) -> F) -> MLX90393Error<F> {
+        match self {
+            MLX90393Error::Bus(e) => MLX90393Error::Bus(map(e)),
+            MLX90393Error::Chip { command, status } => MLX90393Error::Chip { command, status },
+            MLX90393Error::UnexpectedMode { command, status } => MLX90393Error::UnexpectedMode { command, status },
+            MLX90393Error::InvalidConfig(message) => MLX90393Error::InvalidConfig(message),
+        }
+    }
+
+    /// Status byte of the failed command, when the chip answered.
+    pub fn status(&self) -> Option<MLX90393Status> {
+        match self {
+            MLX90393Error::Chip { status, .. } | MLX90393Error::UnexpectedMode { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for MLX90393Error<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MLX90393Error::Bus(e) => write!(f, "MLX90393: bus error: {:?}", e),
+            MLX90393Error::Chip { command, status } => write!(f, "MLX90393: {:?} failed, status: {}", command, status),
+            MLX90393Error::UnexpectedMode { command, status } => write!(f, "MLX90393: {:?} unexpected response, status: {}", command, status),
+            MLX90393Error::InvalidConfig(message) => write!(f, "MLX90393: {}", message),
+        }
+    }
+}
+
+impl<E: fmt::Debug> std::error::Error for MLX90393Error<E> {}
+
+impl<E> From<E> for MLX90393Error<E> {
+    fn from(e: E) -> Self {
+        MLX90393Error::Bus(e)
+    }
+}
@@ -0,0 +1,104 @@
+use std::fmt::Debug;
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+use embedded_hal::spi::{Operation, SpiDevice};
+
+use crate::error::MLX90393Error;
+
+// The status byte is ready right after a command, give the chip a moment anyway.
+const I2C_COMMAND_DELAY_US: u32 = 200;
+
+// Longest command (WR) plus longest response (RM of T, X, Y and Z).
+const SPI_FRAME_LEN: usize = 4 + 9;
+
+/// Command layer of the MLX90393: sends a command and reads its response, the status
+/// byte first. Only `MLX90393Error::Bus` is worth retrying, the other errors come from
+/// the request itself.
+pub trait MLX90393Interface {
+    type Error: Debug;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error<Self::Error>>;
+
+    /// Brings a stuck bus back to idle, nothing to do where the bus cannot hang.
+    fn recover(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<T: MLX90393Interface + ?Sized> MLX90393Interface for Box<T> {
+    type Error = T::Error;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error<Self::Error>> {
+        (**self).transfer(tx_buf, rx_buf)
+    }
+
+    fn recover(&mut self) -> Result<(), Self::Error> {
+        (**self).recover()
+    }
+}
+
+/// The command is written, then the response read in a second transfer. On a shared bus,
+/// build it over the locked bus (`&mut` of the driver) for each command so no other
+/// device gets between the write and the read.
+pub struct I2cInterface<I2C, D> {
+    i2c: I2C,
+    address: u8,
+    delay: D,
+}
+
+impl<I2C: I2c, D: DelayNs> I2cInterface<I2C, D> {
+    pub fn new(i2c: I2C, address: u8, delay: D) -> Self {
+        Self { i2c, address, delay }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn i2c(&mut self) -> &mut I2C {
+        &mut self.i2c
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> MLX90393Interface for I2cInterface<I2C, D> {
+    type Error = I2C::Error;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error<Self::Error>> {
+        self.i2c.write(self.address, tx_buf)?;
+        self.delay.delay_us(I2C_COMMAND_DELAY_US);
+        self.i2c.read(self.address, rx_buf)?;
+        Ok(())
+    }
+}
+
+/// SPI mode 3. The response is clocked out right after the command, in the same frame.
+pub struct SpiInterface<SPI> {
+    spi: SPI,
+}
+
+impl<SPI: SpiDevice> SpiInterface<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Self { spi }
+    }
+}
+
+impl<SPI: SpiDevice> MLX90393Interface for SpiInterface<SPI> {
+    type Error = SPI::Error;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error<Self::Error>> {
+        let len = tx_buf.len() + rx_buf.len();
+        if len > SPI_FRAME_LEN {
+            return Err(MLX90393Error::invalid_config(format!("SPI frame of {} bytes too long", len)));
+        }
+
+        let mut write = [0u8; SPI_FRAME_LEN];
+        let mut read = [0u8; SPI_FRAME_LEN];
+        write[..tx_buf.len()].copy_from_slice(tx_buf);
+
+        self.spi.transaction(&mut [Operation::Transfer(&mut read[..len], &write[..len])])?;
+
+        rx_buf.copy_from_slice(&read[tx_buf.len()..len]);
+        Ok(())
+    }
+}
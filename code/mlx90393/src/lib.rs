@@ -0,0 +1,15 @@
+//! Melexis MLX90393 magnetometer driver over the `embedded-hal` I2C, SPI and delay
+//! traits. It only needs `std`, the chip can be replaced by the register level
+//! emulator in `emulator` to run the driver on a host.
+
+pub mod defs;
+pub mod driver;
+pub mod emulator;
+pub mod error;
+pub mod interface;
+pub mod range;
+
+pub use driver::{check_filter_oversampling, MLX90393Driver, MLX90393Health, MLX90393Measurement, MLX90393SelfTest};
+pub use error::MLX90393Error;
+pub use interface::{I2cInterface, MLX90393Interface, SpiInterface};
+pub use range::MLX90393AutoRange;
@@ -0,0 +1,57 @@
+use crate::defs::MLX90393GAIN;
+
+// Share of the full scale used by the largest axis. Above RANGE_HIGH the range gets
+// wider, below RANGE_LOW finer. Neighbouring gains differ by 1.33x at most, so a step
+// never lands beyond the other threshold.
+const RANGE_HIGH: f32 = 0.8;
+const RANGE_LOW: f32 = 0.1;
+// Readings clipping at full scale change the range at once.
+const RANGE_SATURATED: f32 = 0.98;
+// Consecutive readings beyond a threshold before the gain is changed.
+const RANGE_CONFIRM: u32 = 3;
+
+/// Automatic gain ranging. Every reading votes for a wider or a finer range from the
+/// share of the full scale it uses (`MLX90393Driver::range_usage`), the gain moves one
+/// step once the votes agree.
+#[derive(Debug, Clone, Default)]
+pub struct MLX90393AutoRange {
+    // Readings in a row voting for a wider (+) or finer (-) range.
+    votes: i32,
+}
+
+impl MLX90393AutoRange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Feeds the range usage of a reading taken at `gain`. Returns the gain to switch to,
+    /// the votes start over from there.
+    pub fn update(&mut self, usage: f32, gain: MLX90393GAIN) -> Option<MLX90393GAIN> {
+        let gain = gain as u8;
+
+        // Lower GAIN_SEL codes have a coarser LSB and a wider range.
+        let vote = if usage > RANGE_HIGH && gain > MLX90393GAIN::GAIN5X as u8 {
+            1
+        } else if usage < RANGE_LOW && gain < MLX90393GAIN::GAIN1X as u8 {
+            -1
+        } else {
+            0
+        };
+
+        if vote == 0 || self.votes.signum() == -vote {
+            self.votes = 0;
+        }
+        self.votes += vote;
+
+        if self.votes.unsigned_abs() < RANGE_CONFIRM && !(vote > 0 && usage >= RANGE_SATURATED) {
+            return None;
+        }
+        self.votes = 0;
+
+        Some(MLX90393GAIN::from((gain as i32 - vote) as u8))
+    }
+
+    pub fn reset(&mut self) {
+        self.votes = 0;
+    }
+}
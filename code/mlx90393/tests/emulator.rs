@@ -0,0 +1,507 @@
+use std::time::Duration;
+
+use mlx90393::defs::*;
+use mlx90393::emulator::{EmulatorMode, MLX90393Emulator, NoDelay};
+use mlx90393::{I2cInterface, MLX90393AutoRange, MLX90393Driver, MLX90393Error};
+
+const ADDRESS: u8 = 0x0C;
+
+type Driver = MLX90393Driver<I2cInterface<MLX90393Emulator, NoDelay>, NoDelay>;
+
+fn setup() -> (Driver, MLX90393Emulator) {
+    let chip = MLX90393Emulator::new(ADDRESS);
+    let driver = MLX90393Driver::new(I2cInterface::new(chip.clone(), ADDRESS, NoDelay), NoDelay);
+    (driver, chip)
+}
+
+fn assert_close(value: Option<f32>, expected: f32, tolerance: f32) {
+    let value = value.expect("axis not converted");
+    assert!((value - expected).abs() <= tolerance, "{} is not {} +- {}", value, expected, tolerance);
+}
+
+fn measure(driver: &mut Driver) -> mlx90393::MLX90393Measurement {
+    driver.start_single_measurement_axes(MLX90393Axes::ALL).unwrap();
+    driver.read_measurement().unwrap()
+}
+
+#[test]
+fn register_roundtrip() {
+    let (mut driver, chip) = setup();
+
+    driver.write_register(MLX90393REG::WOXY_THRESHOLD, 0x1234).unwrap();
+    assert_eq!(driver.read_register(MLX90393REG::WOXY_THRESHOLD).unwrap(), 0x1234);
+    assert_eq!(chip.register(MLX90393REG::WOXY_THRESHOLD), 0x1234);
+}
+
+#[test]
+fn write_field_keeps_the_rest_of_the_register() {
+    let (mut driver, chip) = setup();
+
+    driver.set_gain(MLX90393GAIN::GAIN2X).unwrap();
+
+    assert_eq!(driver.read_field(MLX90393Field::GAIN_SEL).unwrap(), MLX90393GAIN::GAIN2X as u16);
+    assert_eq!(MLX90393Field::HALLCONF.extract(chip.register(MLX90393REG::CONF1)), 0x0C);
+}
+
+#[test]
+fn write_field_rejects_values_too_wide() {
+    let (mut driver, chip) = setup();
+
+    let result = driver.write_field(MLX90393Field::GAIN_SEL, 8);
+
+    assert!(matches!(result, Err(MLX90393Error::InvalidConfig(_))));
+    assert_eq!(chip.register_writes(), 0);
+}
+
+#[test]
+fn measurement_in_microtesla() {
+    let (mut driver, chip) = setup();
+    chip.set_field(15.0, -30.0, 48.4);
+
+    let measurement = measure(&mut driver);
+
+    // GAIN1X, RES16, HALLCONF 0xC: 0.150 µT/LSB on X/Y and 0.242 µT/LSB on Z.
+    assert_close(measurement.x, 15.0, 0.15);
+    assert_close(measurement.y, -30.0, 0.15);
+    assert_close(measurement.z, 48.4, 0.242);
+    assert_close(measurement.temperature, 35.0, 0.1);
+    assert_eq!(chip.mode(), EmulatorMode::Idle);
+}
+
+#[test]
+fn measurement_follows_gain_and_resolution() {
+    let (mut driver, chip) = setup();
+    chip.set_field(-250.0, 120.0, 400.0);
+
+    for gain in [MLX90393GAIN::GAIN5X, MLX90393GAIN::GAIN2_5X, MLX90393GAIN::GAIN1X] {
+        for resolution in [MLX90393RESOLUTION::RES16, MLX90393RESOLUTION::RES17, MLX90393RESOLUTION::RES18, MLX90393RESOLUTION::RES19] {
+            driver.set_gain(gain).unwrap();
+            for axis in [MLX90393AXIS::X, MLX90393AXIS::Y, MLX90393AXIS::Z] {
+                driver.set_resolution(axis, resolution).unwrap();
+            }
+
+            let measurement = measure(&mut driver);
+            let xy = driver.sensitivity(MLX90393AXIS::X).unwrap();
+            let z = driver.sensitivity(MLX90393AXIS::Z).unwrap();
+
+            assert_close(measurement.x, -250.0, xy);
+            assert_close(measurement.y, 120.0, xy);
+            assert_close(measurement.z, 400.0, z);
+        }
+    }
+}
+
+#[test]
+fn measurement_with_temperature_compensation_and_offsets() {
+    let (mut driver, chip) = setup();
+    chip.set_field(40.0, -10.0, 30.0);
+
+    driver.set_temperature_compensation(true).unwrap();
+    driver.write_offsets([100, -100, 0]).unwrap();
+
+    let measurement = measure(&mut driver);
+    let offset = driver.applied_offset().unwrap();
+
+    // The chip subtracts the offsets, adding them back gives the field.
+    assert_close(measurement.x.map(|x| x + offset[0]), 40.0, 0.15);
+    assert_close(measurement.y.map(|y| y + offset[1]), -10.0, 0.15);
+    assert_close(measurement.z, 30.0, 0.242);
+    assert_close(Some(offset[0]), 15.0, 0.001);
+}
+
+#[test]
+fn temperature() {
+    let (mut driver, chip) = setup();
+    chip.set_temperature(-12.5);
+
+    assert_close(measure(&mut driver).temperature, -12.5, 0.05);
+}
+
+#[test]
+fn saturated_axis_uses_the_full_scale() {
+    let (mut driver, chip) = setup();
+    chip.set_field(10000.0, 0.0, 0.0);
+
+    let measurement = measure(&mut driver);
+
+    assert!(driver.range_usage(&measurement).unwrap() >= 0.999);
+}
+
+#[test]
+fn axis_selection() {
+    let (mut driver, chip) = setup();
+    chip.set_field(1.5, 3.0, 4.84);
+
+    driver.set_axes(MLX90393Axes::new(&[MLX90393AXIS::X, MLX90393AXIS::Y])).unwrap();
+    driver.start_single_measurement().unwrap();
+    let measurement = driver.read_measurement().unwrap();
+
+    assert_close(measurement.x, 1.5, 0.15);
+    assert_close(measurement.y, 3.0, 0.15);
+    assert!(measurement.z.is_none());
+    assert!(measurement.temperature.is_none());
+}
+
+#[test]
+fn burst_mode_keeps_measuring() {
+    let (mut driver, chip) = setup();
+
+    driver.start_burst_measurement_axes(MLX90393Axes::XYZ).unwrap();
+    assert_eq!(chip.mode(), EmulatorMode::Burst);
+
+    chip.set_field(10.0, 0.0, 0.0);
+    assert_close(driver.read_measurement().unwrap().x, 10.0, 0.15);
+    chip.set_field(20.0, 0.0, 0.0);
+    assert_close(driver.read_measurement().unwrap().x, 20.0, 0.15);
+
+    driver.exit_mode().unwrap();
+    assert_eq!(chip.mode(), EmulatorMode::Idle);
+}
+
+#[test]
+fn starting_a_mode_twice_is_a_chip_error() {
+    let (mut driver, _chip) = setup();
+
+    driver.start_wakeup_measurement().unwrap();
+    let result = driver.start_burst_measurement();
+
+    match result {
+        Err(MLX90393Error::Chip { command, status }) => {
+            assert_eq!(command, MLX90393CMD::SB);
+            assert!(status.error());
+            assert!(status.woc_mode());
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert_eq!(driver.health().errors, 1);
+}
+
+#[test]
+fn reading_without_a_measurement_is_a_chip_error() {
+    let (mut driver, _chip) = setup();
+
+    assert!(matches!(driver.read_measurement(), Err(MLX90393Error::Chip { command: MLX90393CMD::RM, .. })));
+}
+
+#[test]
+fn rejected_command() {
+    let (mut driver, chip) = setup();
+    chip.reject_next(1);
+
+    let error = driver.read_register(MLX90393REG::CONF1).unwrap_err();
+
+    assert!(error.status().unwrap().error());
+    assert!(driver.read_register(MLX90393REG::CONF1).is_ok());
+}
+
+#[test]
+fn reset_flag_after_power_up() {
+    let (mut driver, chip) = setup();
+
+    assert!(driver.nop().unwrap().reset());
+    assert!(!driver.nop().unwrap().reset());
+
+    chip.power_cycle();
+    assert!(driver.nop().unwrap().reset());
+}
+
+#[test]
+fn bus_errors_are_retried() {
+    let (mut driver, chip) = setup();
+    chip.nack_next(2);
+
+    assert_eq!(driver.read_register(MLX90393REG::CONF1).unwrap(), 0x007C);
+
+    let health = driver.health();
+    assert_eq!(health.retries, 2);
+    assert_eq!(health.recoveries, 0);
+    assert_eq!(health.errors, 0);
+}
+
+#[test]
+fn bus_error_after_the_last_retry() {
+    let (mut driver, chip) = setup();
+    chip.nack_next(10);
+
+    assert!(matches!(driver.read_register(MLX90393REG::CONF1), Err(MLX90393Error::Bus(_))));
+
+    let health = driver.health();
+    assert_eq!(health.retries, 3);
+    assert_eq!(health.recoveries, 1);
+    assert_eq!(health.errors, 1);
+}
+
+#[test]
+fn lost_mode_start_response_is_not_sent_again() {
+    let (mut driver, chip) = setup();
+    chip.lose_next_response(1);
+
+    driver.start_burst_measurement_axes(MLX90393Axes::XYZ).unwrap();
+
+    assert_eq!(chip.mode(), EmulatorMode::Burst);
+    assert_eq!(driver.mode_axes(), MLX90393Axes::XYZ);
+    assert_eq!(driver.health().retries, 1);
+    assert_eq!(driver.health().errors, 0);
+}
+
+#[test]
+fn mode_start_that_did_not_land_is_sent_again() {
+    let (mut driver, chip) = setup();
+    chip.nack_next(1);
+
+    driver.start_wakeup_measurement().unwrap();
+
+    assert_eq!(chip.mode(), EmulatorMode::WakeOnChange);
+}
+
+#[test]
+fn lost_register_read_response_is_retried() {
+    let (mut driver, chip) = setup();
+    chip.lose_next_response(2);
+
+    assert_eq!(driver.read_register(MLX90393REG::CONF1).unwrap(), 0x007C);
+    assert_eq!(driver.health().retries, 2);
+}
+
+#[test]
+fn memory_store_is_never_repeated() {
+    let (mut driver, chip) = setup();
+    chip.lose_next_response(1);
+
+    assert!(matches!(driver.memory_store(), Err(MLX90393Error::Bus(_))));
+    assert_eq!(chip.stores(), 1);
+    assert_eq!(driver.health().retries, 0);
+    assert_eq!(driver.health().errors, 1);
+}
+
+#[test]
+fn wrong_address_does_not_answer() {
+    let chip = MLX90393Emulator::new(ADDRESS);
+    let mut driver = MLX90393Driver::new(I2cInterface::new(chip, 0x0D, NoDelay), NoDelay);
+
+    assert!(matches!(driver.nop(), Err(MLX90393Error::Bus(_))));
+}
+
+#[test]
+fn forbidden_filter_and_oversampling() {
+    let (mut driver, chip) = setup();
+
+    driver.set_oversampling(MLX90393OVERSAMPLING::OSR0).unwrap_err();
+    driver.set_filter(MLX90393FILTER::FILTER2).unwrap();
+    driver.set_oversampling(MLX90393OVERSAMPLING::OSR0).unwrap();
+    let writes = chip.register_writes();
+
+    let result = driver.set_filter(MLX90393FILTER::FILTER1);
+
+    assert!(matches!(result, Err(MLX90393Error::InvalidConfig(_))));
+    assert_eq!(chip.register_writes(), writes);
+    assert_eq!(driver.get_filter().unwrap(), MLX90393FILTER::FILTER2);
+}
+
+#[test]
+fn memory_store_and_recall() {
+    let (mut driver, chip) = setup();
+
+    driver.set_gain(MLX90393GAIN::GAIN3X).unwrap();
+    driver.memory_store().unwrap();
+    driver.set_gain(MLX90393GAIN::GAIN4X).unwrap();
+
+    driver.memory_recall().unwrap();
+
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN3X);
+    assert_eq!(chip.stores(), 1);
+
+    chip.power_cycle();
+    assert_eq!(MLX90393Field::GAIN_SEL.extract(chip.register(MLX90393REG::CONF1)), MLX90393GAIN::GAIN3X as u16);
+}
+
+#[test]
+fn reset_drops_the_cached_settings() {
+    let (mut driver, _chip) = setup();
+
+    driver.set_resolution(MLX90393AXIS::X, MLX90393RESOLUTION::RES19).unwrap();
+    driver.reset().unwrap();
+
+    assert_eq!(driver.get_resolution(MLX90393AXIS::X).unwrap(), MLX90393RESOLUTION::RES16);
+}
+
+#[test]
+fn load_registers() {
+    let (mut driver, _chip) = setup();
+
+    let mut registers = driver.read_registers().unwrap();
+    registers.values[MLX90393REG::CONF3 as usize] = MLX90393Field::RES_Z.insert(0, MLX90393RESOLUTION::RES18 as u16);
+    registers.values[MLX90393REG::CONF1 as usize] = MLX90393Field::HALLCONF.insert(registers.values[0], 0x03);
+
+    assert!(matches!(driver.load_registers(&registers), Err(MLX90393Error::InvalidConfig(_))));
+
+    registers.values[MLX90393REG::CONF1 as usize] = 0x0000;
+    driver.load_registers(&registers).unwrap();
+
+    assert_eq!(driver.get_hallconf().unwrap(), MLX90393HALLCONF::HALLCONF_0);
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN5X);
+    assert_eq!(driver.get_resolution(MLX90393AXIS::Z).unwrap(), MLX90393RESOLUTION::RES18);
+}
+
+#[test]
+fn wakeup_thresholds_in_lsb() {
+    let (mut driver, chip) = setup();
+
+    driver.set_wakeup_thresholds(3.0, 2.42, 1.0).unwrap();
+
+    assert_eq!(chip.register(MLX90393REG::WOXY_THRESHOLD), 20);
+    assert_eq!(chip.register(MLX90393REG::WOZ_THRESHOLD), 10);
+    assert_eq!(chip.register(MLX90393REG::WOT_THRESHOLD), 45);
+    assert_eq!(driver.wakeup_thresholds(), Some((3.0, 2.42, 1.0)));
+
+    // Written in LSB, a gain change makes them stale.
+    driver.set_gain(MLX90393GAIN::GAIN5X).unwrap();
+    assert_eq!(driver.wakeup_thresholds(), None);
+}
+
+#[test]
+fn wakeup_comparator_bit() {
+    let (mut driver, chip) = setup();
+    let conf2 = chip.register(MLX90393REG::CONF2);
+
+    driver.set_wakeup_comparator(true).unwrap();
+    assert_eq!(chip.register(MLX90393REG::CONF2), conf2 | 1 << 12);
+
+    driver.set_wakeup_comparator(false).unwrap();
+    assert_eq!(chip.register(MLX90393REG::CONF2), conf2 & !(1 << 12));
+}
+
+#[test]
+fn conversion_time() {
+    let (mut driver, _chip) = setup();
+
+    driver.set_filter(MLX90393FILTER::FILTER5).unwrap();
+    driver.set_oversampling(MLX90393OVERSAMPLING::OSR3).unwrap();
+
+    // 3 * (67 + 64 * 8 * 34) + 264 + 432 + 102 µs, plus 10%.
+    assert_eq!(driver.conversion_time(MLX90393Axes::XYZ).unwrap(), Duration::from_micros(58545));
+
+    driver.set_hallconf(MLX90393HALLCONF::HALLCONF_0).unwrap();
+    assert_eq!(driver.conversion_time(MLX90393Axes::new(&[MLX90393AXIS::Z])).unwrap(), Duration::from_micros(39249));
+}
+
+#[test]
+fn burst_data_rate() {
+    let (mut driver, _chip) = setup();
+
+    driver.set_burst_data_rate(Duration::from_millis(50)).unwrap();
+
+    assert_eq!(driver.get_burst_data_rate().unwrap(), Duration::from_millis(60));
+    assert!(driver.set_burst_data_rate(Duration::from_secs(2)).is_err());
+}
+
+#[test]
+fn self_test() {
+    let (mut driver, chip) = setup();
+    chip.set_field(20.0, -5.0, 40.0);
+
+    let result = driver.self_test().unwrap();
+
+    assert!(result.passed);
+    assert!((result.delta[2] - 120.0).abs() < 0.5);
+    assert_eq!(MLX90393Field::BIST.extract(chip.register(MLX90393REG::CONF1)), 0);
+}
+
+#[test]
+fn self_test_with_a_broken_coil() {
+    let (mut driver, chip) = setup();
+    chip.set_bist_field(0.0, 0.0, 0.0);
+
+    assert!(!driver.self_test().unwrap().passed);
+}
+
+#[test]
+fn self_test_with_a_dead_channel() {
+    let (mut driver, chip) = setup();
+    chip.set_bist_field(30.0, 0.0, 120.0);
+
+    let result = driver.self_test().unwrap();
+
+    assert!(!result.passed);
+    assert!((result.delta[0] - 30.0).abs() < 0.5);
+}
+
+#[test]
+fn mode_axes_follow_the_running_mode() {
+    let (mut driver, _chip) = setup();
+
+    driver.start_burst_measurement_axes(MLX90393Axes::XYZ).unwrap();
+    driver.set_axes(MLX90393Axes::ALL).unwrap();
+
+    assert_eq!(driver.get_axes(), MLX90393Axes::ALL);
+    assert_eq!(driver.mode_axes(), MLX90393Axes::XYZ);
+}
+
+// Measures `count` times, stepping the gain as the auto-ranging asks. Returns the last reading.
+fn measure_ranged(driver: &mut Driver, range: &mut MLX90393AutoRange, count: usize) -> mlx90393::MLX90393Measurement {
+    let mut measurement = measure(driver);
+    for _ in 1..count {
+        let usage = driver.range_usage(&measurement).unwrap();
+        if let Some(gain) = range.update(usage, driver.get_gain().unwrap()) {
+            driver.set_gain(gain).unwrap();
+        }
+        measurement = measure(driver);
+    }
+    measurement
+}
+
+#[test]
+fn auto_range_widens_near_full_scale() {
+    let (mut driver, chip) = setup();
+    let mut range = MLX90393AutoRange::new();
+    driver.set_gain(MLX90393GAIN::GAIN1X).unwrap();
+    chip.set_field(4500.0, 0.0, 0.0);
+
+    // Two readings are not enough to change the range.
+    measure_ranged(&mut driver, &mut range, 3);
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN1X);
+
+    let measurement = measure_ranged(&mut driver, &mut range, 10);
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN1_33X);
+    assert_close(measurement.x, 4500.0, 1.0);
+}
+
+#[test]
+fn auto_range_steps_at_once_when_saturated() {
+    let (mut driver, chip) = setup();
+    let mut range = MLX90393AutoRange::new();
+    driver.set_gain(MLX90393GAIN::GAIN1X).unwrap();
+    chip.set_field(10000.0, 0.0, 0.0);
+
+    measure_ranged(&mut driver, &mut range, 2);
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN1_33X);
+}
+
+#[test]
+fn auto_range_refines_small_fields() {
+    let (mut driver, chip) = setup();
+    let mut range = MLX90393AutoRange::new();
+    driver.set_gain(MLX90393GAIN::GAIN5X).unwrap();
+    chip.set_field(50.0, -20.0, 30.0);
+
+    let measurement = measure_ranged(&mut driver, &mut range, 40);
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN1X);
+    assert_close(measurement.x, 50.0, 0.2);
+    assert_close(measurement.y, -20.0, 0.2);
+}
+
+#[test]
+fn auto_range_ignores_a_single_outlier() {
+    let (mut driver, chip) = setup();
+    let mut range = MLX90393AutoRange::new();
+    driver.set_gain(MLX90393GAIN::GAIN1X).unwrap();
+
+    chip.set_field(4500.0, 0.0, 0.0);
+    measure_ranged(&mut driver, &mut range, 3);
+    chip.set_field(1000.0, 0.0, 0.0);
+    measure_ranged(&mut driver, &mut range, 2);
+    chip.set_field(4500.0, 0.0, 0.0);
+    measure_ranged(&mut driver, &mut range, 3);
+
+    assert_eq!(driver.get_gain().unwrap(), MLX90393GAIN::GAIN1X);
+}
@@ -0,0 +1,48 @@
+use std::convert::Infallible;
+
+use embedded_hal::spi::{ErrorType, Operation, SpiDevice};
+use mlx90393::{MLX90393Error, MLX90393Interface, SpiInterface};
+
+// Answers every byte with its complement, counts the frames.
+#[derive(Default)]
+struct LoopbackSpi {
+    frames: usize,
+}
+
+impl ErrorType for LoopbackSpi {
+    type Error = Infallible;
+}
+
+impl SpiDevice for LoopbackSpi {
+    fn transaction(&mut self, operations: &mut [Operation<'_, u8>]) -> Result<(), Infallible> {
+        for operation in operations {
+            if let Operation::Transfer(read, write) = operation {
+                for (read, write) in read.iter_mut().zip(write.iter()) {
+                    *read = !write;
+                }
+            }
+        }
+        self.frames += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn spi_response_follows_the_command() {
+    let mut interface = SpiInterface::new(LoopbackSpi::default());
+    let mut rx_buf = [0u8; 3];
+
+    interface.transfer(&[0x50, 0x00], &mut rx_buf).unwrap();
+
+    assert_eq!(rx_buf, [0xFF; 3]);
+}
+
+#[test]
+fn spi_frame_too_long() {
+    let mut interface = SpiInterface::new(LoopbackSpi::default());
+    let mut rx_buf = [0u8; 10];
+
+    let result = interface.transfer(&[0x00; 4], &mut rx_buf);
+
+    assert!(matches!(result, Err(MLX90393Error::InvalidConfig(_))));
+}
@@ -0,0 +1,14 @@
+use std::sync::{Arc, Mutex};
+
+use compass::math::Vector3;
+
+pub mod adxl345;
+
+pub type AccelerometerPtr = Arc<Mutex<dyn Accelerometer + Send>>;
+
+#[allow(unused)]
+pub trait Accelerometer {
+    /// Acceleration in g. The axes must be aligned with the magnetometer axes,
+    /// reading +1g on Z when the board is level.
+    fn read_acceleration(&self) -> Result<Vector3, Box<dyn std::error::Error>>;
+}
@@ -0,0 +1,86 @@
+use crate::accelerometer::Accelerometer;
+use compass::math::Vector3;
+use crate::i2c_bus::I2cDevice;
+use crate::Endable;
+
+const ADXL345_DEVICE_ID: u8 = 0xE5;
+
+// Full resolution mode keeps a constant 3.9 mg/LSB scale on every range.
+const ADXL345_SCALE: f32 = 0.0039;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ADXL345REG {
+    DEVID = 0x00,
+    BW_RATE = 0x2C,
+    POWER_CTL = 0x2D,
+    DATA_FORMAT = 0x31,
+    DATAX0 = 0x32,
+}
+
+impl From<ADXL345REG> for u8 {
+    fn from(reg: ADXL345REG) -> Self {
+        reg as u8
+    }
+}
+
+pub struct ADXL345 {
+    device: I2cDevice,
+}
+
+impl ADXL345 {
+    #[allow(dead_code)]
+    pub fn new(device: I2cDevice) -> Result<Self, Box<dyn std::error::Error>> {
+        let me = Self { device };
+
+        let device_id = me.read_register(ADXL345REG::DEVID)?;
+        if device_id != ADXL345_DEVICE_ID {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("ADXL345: unexpected device id: {:#04x}", device_id))));
+        }
+
+        // 100Hz output data rate
+        me.write_register(ADXL345REG::BW_RATE, 0x0A)?;
+        // Full resolution, +-2g
+        me.write_register(ADXL345REG::DATA_FORMAT, 0x08)?;
+        // Measurement mode
+        me.write_register(ADXL345REG::POWER_CTL, 0x08)?;
+
+        log::debug!("ADXL345: Measurement started");
+
+        Ok(me)
+    }
+
+    pub fn read_register(&self, register: ADXL345REG) -> Result<u8, Box<dyn std::error::Error>> {
+        let mut rx_buf: [u8; 1] = [0; 1];
+        self.device.write_read(&[register.into()], &mut rx_buf)?;
+        Ok(rx_buf[0])
+    }
+
+    pub fn write_register(&self, register: ADXL345REG, value: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.device.write(&[register.into(), value])?;
+        Ok(())
+    }
+}
+
+impl Accelerometer for ADXL345 {
+    fn read_acceleration(&self) -> Result<Vector3, Box<dyn std::error::Error>> {
+        let mut rx_buf: [u8; 6] = [0; 6];
+        self.device.write_read(&[ADXL345REG::DATAX0.into()], &mut rx_buf)?;
+
+        Ok(Vector3 {
+            x: i16::from_le_bytes([rx_buf[0], rx_buf[1]]) as f32 * ADXL345_SCALE,
+            y: i16::from_le_bytes([rx_buf[2], rx_buf[3]]) as f32 * ADXL345_SCALE,
+            z: i16::from_le_bytes([rx_buf[4], rx_buf[5]]) as f32 * ADXL345_SCALE,
+        })
+    }
+}
+
+impl Endable for ADXL345 {
+    fn end(&self) {
+        // Back to standby mode
+        if let Err(e) = self.write_register(ADXL345REG::POWER_CTL, 0x00) {
+            log::error!("Error stopping ADXL345: {}", e);
+        }
+        log::debug!("ADXL345: end");
+    }
+}
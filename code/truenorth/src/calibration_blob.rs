@@ -0,0 +1,46 @@
+use compass::blob::{CalibrationBlob, SensorSettings};
+use compass::calibration::CalibrationMode;
+
+use crate::{CalibrationProfile, TrueNorthParameters};
+
+/// Snapshot of the active profile.
+pub fn export_profile(parameters: &TrueNorthParameters, sensor: SensorSettings) -> CalibrationBlob {
+    let profile = parameters.profile();
+
+    CalibrationBlob {
+        name: profile.name.lock().unwrap().get().clone(),
+        min: [*profile.min_x.lock().unwrap().get(), *profile.min_y.lock().unwrap().get(), *profile.min_z.lock().unwrap().get()],
+        max: [*profile.max_x.lock().unwrap().get(), *profile.max_y.lock().unwrap().get(), *profile.max_z.lock().unwrap().get()],
+        offset: profile.offset.lock().unwrap().get().clone(),
+        soft_iron: profile.soft_iron.lock().unwrap().get().clone(),
+        mounting_offset: *profile.mounting_offset.lock().unwrap().get(),
+        deviation: profile.deviation.lock().unwrap().get().clone(),
+        calibration_mode: *parameters.calibration_mode.lock().unwrap().get(),
+        sensor,
+    }
+}
+
+/// Writes the blob into the active profile. A blob taken with other sensor settings is
+/// still imported, the values are in µT, but it is worth knowing about.
+pub fn import_profile(blob: &CalibrationBlob, parameters: &TrueNorthParameters, sensor: SensorSettings) -> Result<(), Box<dyn std::error::Error>> {
+    if blob.sensor != sensor {
+        log::warn!("Calibration blob: sensor settings differ, blob {:?}, sensor {:?}", blob.sensor, sensor);
+    }
+
+    let profile: &CalibrationProfile = parameters.profile();
+
+    profile.name.lock().unwrap().set(blob.name.clone())?;
+    profile.min_x.lock().unwrap().set(blob.min[0])?;
+    profile.min_y.lock().unwrap().set(blob.min[1])?;
+    profile.min_z.lock().unwrap().set(blob.min[2])?;
+    profile.max_x.lock().unwrap().set(blob.max[0])?;
+    profile.max_y.lock().unwrap().set(blob.max[1])?;
+    profile.max_z.lock().unwrap().set(blob.max[2])?;
+    profile.offset.lock().unwrap().set(blob.offset.clone())?;
+    profile.soft_iron.lock().unwrap().set(blob.soft_iron.clone())?;
+    profile.mounting_offset.lock().unwrap().set(blob.mounting_offset)?;
+    profile.deviation.lock().unwrap().set(blob.deviation.clone())?;
+    parameters.calibration_mode.lock().unwrap().set(CalibrationMode::from(blob.calibration_mode).into())?;
+
+    Ok(())
+}
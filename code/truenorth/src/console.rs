@@ -0,0 +1,73 @@
+use std::io::BufRead;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+/// Commands typed on the serial console, one per line:
+///
+/// `export`         prints the active calibration as `calibration <hex>`
+/// `import <hex>`   loads a calibration printed by `export`
+pub enum ConsoleCommand {
+    ExportCalibration,
+    ImportCalibration(Vec<u8>),
+}
+
+pub fn setup_console() -> Result<Receiver<ConsoleCommand>, Box<dyn std::error::Error>> {
+    let (sender, receiver) = mpsc::channel::<ConsoleCommand>();
+
+    thread::Builder::new().stack_size(8192).spawn(move || {
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            // The console is non-blocking, an empty read or WouldBlock just means no input yet.
+            match stdin.lock().read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    thread::sleep(std::time::Duration::from_millis(100));
+                    continue;
+                }
+                Ok(_) => {}
+            }
+
+            let mut words = line.split_whitespace();
+            let command = match (words.next(), words.next()) {
+                (Some("export"), None) => ConsoleCommand::ExportCalibration,
+                (Some("import"), Some(hex)) => match decode_hex(hex) {
+                    Some(data) => ConsoleCommand::ImportCalibration(data),
+                    None => {
+                        println!("error invalid hex");
+                        continue;
+                    }
+                },
+                (None, _) => continue,
+                _ => {
+                    println!("error unknown command");
+                    continue;
+                }
+            };
+
+            if sender.send(command).is_err() {
+                break;
+            }
+        }
+
+        log::info!("Console: thread ended");
+    })?;
+
+    Ok(receiver)
+}
+
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| hex.get(index..index + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+        .collect()
+}
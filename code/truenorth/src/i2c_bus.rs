@@ -0,0 +1,132 @@
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::{thread, time::Duration};
+
+use esp_idf_svc::hal::delay::BLOCK;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver, I2cError, I2C0};
+use esp_idf_sys::{esp, EspError};
+
+type SharedI2cDriver = Arc<Mutex<I2cDriver<'static>>>;
+
+// Half period of the recovery clock, 100 kHz.
+const RECOVERY_HALF_PERIOD: Duration = Duration::from_micros(5);
+
+// A slave stuck mid-byte releases SDA within 9 clocks.
+const RECOVERY_CLOCKS: usize = 9;
+
+/// Owner of the I2C peripheral. Any number of devices share it through handles bound
+/// to their address, the driver is locked for one transaction at a time.
+#[derive(Clone)]
+pub struct I2cBus {
+    driver: SharedI2cDriver,
+    sda: i32,
+    scl: i32,
+}
+
+impl I2cBus {
+    pub fn new(i2c: I2C0, sda: AnyIOPin, scl: AnyIOPin, config: &I2cConfig) -> Result<Self, EspError> {
+        let (sda_pin, scl_pin) = (sda.pin(), scl.pin());
+        let driver = I2cDriver::new(i2c, sda, scl, config)?;
+        Ok(Self { driver: Arc::new(Mutex::new(driver)), sda: sda_pin, scl: scl_pin })
+    }
+
+    pub fn device(&self, address: u8) -> I2cDevice {
+        I2cDevice { bus: self.clone(), address }
+    }
+
+    /// Frees a bus held by a slave that lost track of the transfer: SCL is clocked by
+    /// hand until SDA is released, then a STOP is sent and the pins are handed back to
+    /// the controller.
+    pub fn recover(&self) -> Result<(), EspError> {
+        let driver = self.driver.lock().unwrap();
+
+        unsafe {
+            esp!(esp_idf_sys::gpio_reset_pin(self.sda))?;
+            esp!(esp_idf_sys::gpio_reset_pin(self.scl))?;
+            esp!(esp_idf_sys::gpio_set_direction(self.sda, esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD))?;
+            esp!(esp_idf_sys::gpio_set_direction(self.scl, esp_idf_sys::gpio_mode_t_GPIO_MODE_INPUT_OUTPUT_OD))?;
+            esp!(esp_idf_sys::gpio_set_level(self.sda, 1))?;
+            esp!(esp_idf_sys::gpio_set_level(self.scl, 1))?;
+
+            let mut clocks = 0;
+            while esp_idf_sys::gpio_get_level(self.sda) == 0 && clocks < RECOVERY_CLOCKS {
+                esp!(esp_idf_sys::gpio_set_level(self.scl, 0))?;
+                thread::sleep(RECOVERY_HALF_PERIOD);
+                esp!(esp_idf_sys::gpio_set_level(self.scl, 1))?;
+                thread::sleep(RECOVERY_HALF_PERIOD);
+                clocks += 1;
+            }
+
+            // STOP: SDA rises while SCL is high.
+            esp!(esp_idf_sys::gpio_set_level(self.scl, 0))?;
+            esp!(esp_idf_sys::gpio_set_level(self.sda, 0))?;
+            thread::sleep(RECOVERY_HALF_PERIOD);
+            esp!(esp_idf_sys::gpio_set_level(self.scl, 1))?;
+            thread::sleep(RECOVERY_HALF_PERIOD);
+            esp!(esp_idf_sys::gpio_set_level(self.sda, 1))?;
+            thread::sleep(RECOVERY_HALF_PERIOD);
+
+            let released = esp_idf_sys::gpio_get_level(self.sda) != 0;
+            esp!(esp_idf_sys::i2c_set_pin(driver.port(), self.sda, self.scl, true, true, esp_idf_sys::i2c_mode_t_I2C_MODE_MASTER))?;
+
+            log::warn!("I2C: Bus recovery after {} clocks, SDA {}", clocks, if released { "released" } else { "still low" });
+        }
+
+        Ok(())
+    }
+
+    /// Addresses in `addresses` for which `probe` succeeds, in ascending order.
+    pub fn scan<F>(&self, addresses: RangeInclusive<u8>, mut probe: F) -> Vec<u8>
+    where
+        F: FnMut(&I2cDevice) -> bool,
+    {
+        addresses.filter(|address| probe(&self.device(*address))).collect()
+    }
+}
+
+/// A device on an `I2cBus`.
+#[derive(Clone)]
+pub struct I2cDevice {
+    bus: I2cBus,
+    address: u8,
+}
+
+impl I2cDevice {
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn write(&self, bytes: &[u8]) -> Result<(), EspError> {
+        self.lock().write(self.address, bytes, BLOCK)
+    }
+
+    pub fn read(&self, buffer: &mut [u8]) -> Result<(), EspError> {
+        self.lock().read(self.address, buffer, BLOCK)
+    }
+
+    pub fn write_read(&self, bytes: &[u8], buffer: &mut [u8]) -> Result<(), EspError> {
+        self.lock().write_read(self.address, bytes, buffer, BLOCK)
+    }
+
+    /// Holds the bus for several transfers in a row, no other device gets in between.
+    pub fn lock(&self) -> MutexGuard<'_, I2cDriver<'static>> {
+        self.bus.driver.lock().unwrap()
+    }
+
+    pub fn recover_bus(&self) -> Result<(), EspError> {
+        self.bus.recover()
+    }
+}
+
+impl embedded_hal::i2c::ErrorType for I2cDevice {
+    type Error = I2cError;
+}
+
+/// Every transaction holds the bus until it ends, `address` must be the device one.
+impl embedded_hal::i2c::I2c for I2cDevice {
+    fn transaction(&mut self, address: u8, operations: &mut [embedded_hal::i2c::Operation<'_>]) -> Result<(), Self::Error> {
+        debug_assert_eq!(address, self.address);
+        embedded_hal::i2c::I2c::transaction(&mut *self.lock(), address, operations)
+    }
+}
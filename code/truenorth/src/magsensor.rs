@@ -1,24 +1,109 @@
-use crate::math::Vector3;
+use crate::accelerometer::AccelerometerPtr;
+use compass::calibration::CalibrationQuality;
+use compass::math::{Matrix3, Vector3};
 use std::time::Duration;
 
+pub use compass::blob::SensorSettings;
+use compass::blob::CalibrationBlob;
+
 pub mod mlx90393;
-pub mod mlx90393_defs;
+pub mod mlx90393_error;
 pub mod mlx90393_inner;
+pub mod mlx90393_transport;
 
 pub type MagSensorHandlerPtr = Box<dyn Fn(MagSensorEvent) -> () + Send>;
 
+/// Heading in degrees (0-360). `compass` is the sensor heading with the mounting offset
+/// applied, `magnetic` is relative to magnetic north after the deviation correction and
+/// `true_heading` has the configured declination applied. `degraded` is set while the
+/// last self-test failed or could not run, the heading should not be trusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Heading {
+    pub compass: f32,
+    pub magnetic: f32,
+    pub true_heading: f32,
+    pub degraded: bool,
+}
+
+/// Field change in µT measured while the self-test coil was on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SelfTestResult {
+    pub passed: bool,
+    pub delta: Vector3,
+}
+
+/// Self-test outcome reported over BLE. A failed test leaves the sensor degraded, the
+/// headings keep coming but should not be trusted. A test that could not run is failed
+/// with a zero delta.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SelfTestStatus {
+    #[default]
+    NotRun,
+    Passed(Vector3),
+    Failed(Vector3),
+}
+
+impl From<SelfTestResult> for SelfTestStatus {
+    fn from(result: SelfTestResult) -> Self {
+        if result.passed {
+            SelfTestStatus::Passed(result.delta)
+        } else {
+            SelfTestStatus::Failed(result.delta)
+        }
+    }
+}
+
+impl SelfTestStatus {
+    /// BLE payload: state (0 not run, 1 passed, 2 failed) and the delta x, y, z as
+    /// little-endian f32.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (state, delta) = match self {
+            SelfTestStatus::NotRun => (0x00u8, Vector3::new(0.0, 0.0, 0.0)),
+            SelfTestStatus::Passed(delta) => (0x01, *delta),
+            SelfTestStatus::Failed(delta) => (0x02, *delta),
+        };
+
+        let mut bytes = vec![state];
+        for value in [delta.x, delta.y, delta.z] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum MagSensorEvent {
     RawChanged(Vector3),
     CalibratedChanged((f32, f32), (f32, f32), (f32, f32)),
-    HeadingChanged(i32),
+    EllipsoidChanged(Vector3, Matrix3),
+    CalibrationProgress(CalibrationQuality),
+    CalibrationFinished(CalibrationQuality),
+    CalibrationCancelled,
+    HardIronUpdated(Vector3),
+    HeadingChanged(Heading),
+    /// Sensor die temperature in °C.
+    TemperatureChanged(f32),
+    SelfTestFinished(SelfTestResult),
+    /// Gain code after an automatic range change and the new X/Y sensitivity in µT/LSB.
+    RangeChanged(u8, f32),
 }
 
 #[allow(unused)]
 pub trait MagSensor {
     fn start(&self) -> Result<(), Box<dyn std::error::Error>>;
+    /// Starts calibrating in the background. It ends by itself once the coverage
+    /// target is reached or after `timeout`, and can be cancelled meanwhile.
     fn calibrate(&self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>>;
+    fn cancel_calibration(&self) -> Result<(), Box<dyn std::error::Error>>;
     fn add_handler(&self, handler: MagSensorHandlerPtr) -> Result<(), Box<dyn std::error::Error>>;
+    fn set_accelerometer(&self, accelerometer: AccelerometerPtr) -> Result<(), Box<dyn std::error::Error>>;
+    /// Adds a deviation card entry: the true heading right now is `true_heading`.
+    fn record_deviation(&self, true_heading: f32) -> Result<(), Box<dyn std::error::Error>>;
+    fn settings(&self) -> Result<SensorSettings, Box<dyn std::error::Error>>;
+    /// Writes a calibration backup into the active profile. Refused while calibrating.
+    fn import_calibration(&self, blob: &CalibrationBlob) -> Result<(), Box<dyn std::error::Error>>;
+    /// Runs the built-in self-test, the result is also sent as `SelfTestFinished`.
+    fn self_test(&self) -> Result<SelfTestResult, Box<dyn std::error::Error>>;
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
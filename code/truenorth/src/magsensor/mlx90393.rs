@@ -6,20 +6,24 @@ use std::{
     time::{Duration, Instant},
 };
 
+use esp_idf_svc::hal::delay::Delay;
 use esp_idf_svc::hal::task::notification::Notification;
-use esp_idf_svc::hal::{
-    gpio::{AnyIOPin, InterruptType, PinDriver, Pull},
-    i2c::{I2c, I2cDriver},
-    peripheral::Peripheral,
-    units::Hertz,
-};
+use esp_idf_svc::hal::gpio::{AnyIOPin, InterruptType, PinDriver, Pull};
+use esp_idf_sys::EspError;
+use mlx90393::defs::*;
+use mlx90393::{check_filter_oversampling, MLX90393Driver, MLX90393Health, MLX90393Measurement};
+use compass::blob::CalibrationBlob;
+use compass::calibration::HardIronCorrection;
+use compass::deviation::deviation_at;
+use compass::math::{angle_difference, normalize_degrees, tilt_compensate, LowPassFilter, Vector3};
 
 use super::MagSensorHandlerPtr;
-use crate::magsensor::mlx90393_defs::*;
+use crate::magsensor::mlx90393_error::MLX90393Error;
 use crate::magsensor::mlx90393_inner::{MLX90393Inner, MLX90393Internal};
-use crate::math::{LowPassFilter, Vector3};
+use crate::magsensor::mlx90393_transport::MLX90393Bus;
+use crate::accelerometer::AccelerometerPtr;
 use crate::{
-    magsensor::{MagSensor, MagSensorEvent, MagSensorState},
+    magsensor::{Heading, MagSensor, MagSensorEvent, MagSensorState, SelfTestResult, SensorSettings},
     Endable, TrueNorthParameters,
 };
 
@@ -29,31 +33,47 @@ const MEASUREMENT_SAMPLES: usize = 100;
 const CALIBRATION_SAMPLE_TIME: u128 = 10;
 const MEASUREMENT_SAMPLE_TIME: u128 = 1000;
 
+// Start-up time after the RT command.
+const RESET_TIME: Duration = Duration::from_millis(10);
+
+// Failed measurements in a row before the sensor is reset and configured again.
+const RESET_AFTER_FAILURES: u32 = 5;
+
+const HEADING_CHANGE_THRESHOLD: f32 = 2.0;
+const TEMPERATURE_CHANGE_THRESHOLD: f32 = 0.5;
+
 pub struct MLX90393Config {
-    slave_address: u8,
-    sda: AnyIOPin,
-    scl: AnyIOPin,
-    int: AnyIOPin,
+    bus: Option<MLX90393Bus>,
+    int: Option<AnyIOPin>,
     parameters: Arc<TrueNorthParameters>,
+    temperature_compensation: bool,
+    hallconf: MLX90393HALLCONF,
 }
 
 impl MLX90393Config {
     pub fn new(
         parameters: Arc<TrueNorthParameters>,
-        slave_address: u8,
-        sda: AnyIOPin,
-        scl: AnyIOPin,
+        bus: MLX90393Bus,
         int: AnyIOPin,
     ) -> Arc<Mutex<Self>> {
         let me = Self {
             parameters,
-            slave_address,
-            sda,
-            scl,
-            int,
+            bus: Some(bus),
+            int: Some(int),
+            temperature_compensation: false,
+            hallconf: MLX90393HALLCONF::HALLCONF_C,
         };
         Arc::new(Mutex::new(me))
     }
+
+    pub fn set_hallconf(&mut self, hallconf: MLX90393HALLCONF) {
+        self.hallconf = hallconf;
+    }
+
+    /// Uses the on-chip temperature compensation (TCMP_EN).
+    pub fn set_temperature_compensation(&mut self, enabled: bool) {
+        self.temperature_compensation = enabled;
+    }
 }
 
 pub struct MLX90393 {
@@ -62,37 +82,40 @@ pub struct MLX90393 {
 
 impl MLX90393 {
     #[allow(dead_code)]
-    pub fn new(
-        i2c: impl Peripheral<P = impl I2c> + 'static,
-        config: Arc<Mutex<MLX90393Config>>,
-    ) -> Result<Self, Box<dyn std::error::Error>> {
-        let i2c = Self::init_i2c(i2c, config.clone())?;
-
+    pub fn new(config: Arc<Mutex<MLX90393Config>>) -> Result<Self, Box<dyn std::error::Error>> {
         let mut config = config.lock().unwrap();
 
+        // The bus drivers own their pins, a configuration is good for one sensor only.
+        let (bus, int) = match (config.bus.take(), config.int.take()) {
+            (Some(bus), Some(int)) => (bus, int),
+            _ => return Err(Box::new(MLX90393Error::invalid_config("bus already in use"))),
+        };
+        let transport = bus.into_transport()?;
+
         let me = Self {
             inner: Arc::new(Mutex::new(MLX90393Inner {
-                i2c: Some(i2c),
-                int: unsafe { config.int.clone_unchecked() },
-                slave_address: config.slave_address,
+                driver: MLX90393Driver::new(transport, Delay::new_default()),
                 parameters: config.parameters.clone(),
-                internal: MLX90393Internal::default(),
+                accelerometer: None,
+                internal: MLX90393Internal {
+                    temperature_compensation: config.temperature_compensation,
+                    hallconf: config.hallconf,
+                    ..MLX90393Internal::default()
+                },
             })),
         };
 
-        me.init()?;
+        me.init(int)?;
 
         Ok(me)
     }
 
-    fn init(&self) -> Result<(), Box<dyn std::error::Error>> {
+    fn init(&self, int: AnyIOPin) -> Result<(), Box<dyn std::error::Error>> {
         let shared_self = self.inner.clone();
 
-        self.configure()?;
+        Self::configure(&self.inner)?;
 
         thread::Builder::new().spawn(move || {
-            let int = unsafe { shared_self.lock().unwrap().int.clone_unchecked() };
-
             let mut interrupt_pin = {
                 if let Ok(iopin) = PinDriver::input(int) {
                     iopin
@@ -124,10 +147,13 @@ impl MLX90393 {
             //me.lock().unwrap().start_burst_measurement()?;
 
             let mut value = LowPassFilter::new(0.5);
+            let mut acceleration = LowPassFilter::new(0.5);
             let mut pool = vec![];
             let mut avg = Vector3::new(0.0, 0.0, 0.0);
 
-            let mut measure_event = MagSensorEvent::HeadingChanged(0);
+            let mut measure_event = MagSensorEvent::HeadingChanged(Heading::default());
+            let mut last_temperature = None;
+            let mut last_magnetic = Vector3::new(0.0, 0.0, 0.0);
 
             let mut current_time = Instant::now();
 
@@ -143,6 +169,27 @@ impl MLX90393 {
                     }
                 }
 
+                {
+                    let mut lock_me = shared_self.lock().unwrap();
+
+                    // Calibration must end even if the device stops producing samples.
+                    if lock_me.internal.state == MagSensorState::Calibrating
+                        && lock_me.calibration_expired()
+                    {
+                        log::warn!("Magnetometer: Calibration timeout");
+                        if let Err(e) = lock_me.finish_calibration() {
+                            log::error!("Error finishing calibration: {}", e);
+                        }
+                    }
+
+                    // New wake-up thresholds and offsets are only written outside a measurement mode.
+                    if lock_me.internal.state == MagSensorState::Measuring
+                        && (lock_me.restart_pending() || lock_me.wakeup_thresholds_changed() || lock_me.offsets_changed())
+                    {
+                        lock_me.restart_measuring();
+                    }
+                }
+
                 //log::debug!("monitor thread...");
 
                 if let Err(e) = interrupt_pin.enable_interrupt() {
@@ -152,12 +199,40 @@ impl MLX90393 {
                 if let Some(_ret) = notification.wait(100) {
                     let mut lock_me = shared_self.lock().unwrap();
 
-                    match lock_me.read_measurement() {
+                    match lock_me.driver.read_measurement() {
                         Ok(measurement) => {
+                            lock_me.driver.health_mut().consecutive_failures = 0;
+
                             //log::debug!("Measurement: {:?}", measurement);
-                            let x = measurement[0];
-                            let y = measurement[1];
-                            let z = measurement[2];
+                            // The chip subtracts the programmed offsets, adding them back keeps the
+                            // calibration working on the uncorrected field.
+                            let applied = lock_me.applied_offset().unwrap_or(Vector3::new(0.0, 0.0, 0.0));
+
+                            // Axes left out of the selection keep their last value.
+                            let x = measurement.x.map(|x| x + applied.x).unwrap_or(last_magnetic.x);
+                            let y = measurement.y.map(|y| y + applied.y).unwrap_or(last_magnetic.y);
+                            let z = measurement.z.map(|z| z + applied.z).unwrap_or(last_magnetic.z);
+
+                            // Already converted at the current gain, a range change applies to
+                            // the next reading.
+                            if let Err(e) = lock_me.auto_range(&measurement) {
+                                log::error!("Error changing range: {}", e);
+                            }
+                            last_magnetic = Vector3 { x, y, z };
+
+                            if let Some(temperature) = measurement.temperature {
+                                if last_temperature
+                                    .map(|last: f32| (temperature - last).abs() > TEMPERATURE_CHANGE_THRESHOLD)
+                                    .unwrap_or(true)
+                                {
+                                    last_temperature = Some(temperature);
+                                    if let Err(e) = lock_me.send_event(MagSensorEvent::TemperatureChanged(temperature)) {
+                                        log::error!("Error sending event: {}", e);
+                                    }
+                                }
+                            }
+
+                            let mut sampled = false;
 
                             if current_time.elapsed().as_millis()
                                 > if lock_me.internal.state == MagSensorState::Calibrating {
@@ -166,41 +241,47 @@ impl MLX90393 {
                                     MEASUREMENT_SAMPLE_TIME
                                 }
                             {
-                                pool.push(value.update(Vector3 { x, y, z }));
                                 current_time = Instant::now();
 
-                                if pool.len() > MEASUREMENT_SAMPLES {
-                                    pool.remove(0);
-                                }
-
-                                avg = {
-                                    let mut sum_x = 0.0;
-                                    let mut sum_y = 0.0;
-                                    let mut sum_z = 0.0;
+                                // Glitches are dropped before they reach the filter, a single one
+                                // would otherwise widen the calibration for good.
+                                if lock_me.calibration_accept(Vector3 { x, y, z }) {
+                                    pool.push(value.update(Vector3 { x, y, z }));
+                                    sampled = true;
 
-                                    let len =
-                                        if lock_me.internal.state == MagSensorState::Calibrating {
-                                            if pool.len() < CALIBRATION_SAMPLES {
-                                                pool.len()
-                                            } else {
-                                                CALIBRATION_SAMPLES
-                                            }
-                                        } else {
-                                            pool.len()
-                                        };
-
-                                    for i in pool.len() - len..pool.len() {
-                                        sum_x += pool[i].x;
-                                        sum_y += pool[i].y;
-                                        sum_z += pool[i].z;
+                                    if pool.len() > MEASUREMENT_SAMPLES {
+                                        pool.remove(0);
                                     }
 
-                                    Vector3 {
-                                        x: sum_x / len as f32,
-                                        y: sum_y / len as f32,
-                                        z: sum_z / len as f32,
-                                    }
-                                };
+                                    avg = {
+                                        let mut sum_x = 0.0;
+                                        let mut sum_y = 0.0;
+                                        let mut sum_z = 0.0;
+
+                                        let len =
+                                            if lock_me.internal.state == MagSensorState::Calibrating {
+                                                if pool.len() < CALIBRATION_SAMPLES {
+                                                    pool.len()
+                                                } else {
+                                                    CALIBRATION_SAMPLES
+                                                }
+                                            } else {
+                                                pool.len()
+                                            };
+
+                                        for i in pool.len() - len..pool.len() {
+                                            sum_x += pool[i].x;
+                                            sum_y += pool[i].y;
+                                            sum_z += pool[i].z;
+                                        }
+
+                                        Vector3 {
+                                            x: sum_x / len as f32,
+                                            y: sum_y / len as f32,
+                                            z: sum_z / len as f32,
+                                        }
+                                    };
+                                }
                             }
 
                             let event = MagSensorEvent::RawChanged(avg);
@@ -208,100 +289,124 @@ impl MLX90393 {
                                 log::error!("Error sending event: {}", e);
                             }
 
-                            let parameters = lock_me.parameters.clone();
-
-                            let mut max_x = parameters.max_x.lock().unwrap();
-                            let mut min_x = parameters.min_x.lock().unwrap();
-                            let mut max_y = parameters.max_y.lock().unwrap();
-                            let mut min_y = parameters.min_y.lock().unwrap();
-                            let mut max_z = parameters.max_z.lock().unwrap();
-                            let mut min_z = parameters.min_z.lock().unwrap();
-
                             if lock_me.internal.state == MagSensorState::Calibrating {
-                                let mut changed = false;
-                                if avg.x > *max_x.get() {
-                                    if let Err(e) = max_x.set(avg.x) {
-                                        log::error!("Error setting max_x: {}", e);
-                                    }
-                                    changed = true;
-                                }
-                                if avg.x < *min_x.get() {
-                                    if let Err(e) = min_x.set(avg.x) {
-                                        log::error!("Error setting min_x: {}", e);
-                                    }
-                                    changed = true;
-                                }
-                                if avg.y > *max_y.get() {
-                                    if let Err(e) = max_y.set(avg.y) {
-                                        log::error!("Error setting max_y: {}", e);
-                                    }
-                                    changed = true;
-                                }
-                                if avg.y < *min_y.get() {
-                                    if let Err(e) = min_y.set(avg.y) {
-                                        log::error!("Error setting min_y: {}", e);
-                                    }
-                                    changed = true;
-                                }
-                                if avg.z > *max_z.get() {
-                                    if let Err(e) = max_z.set(avg.z) {
-                                        log::error!("Error setting max_z: {}", e);
-                                    }
-                                    changed = true;
-                                }
-                                if avg.z < *min_z.get() {
-                                    if let Err(e) = min_z.set(avg.z) {
-                                        log::error!("Error setting min_z: {}", e);
-                                    }
-                                    changed = true;
+                                lock_me.calibration_update(avg, sampled);
+                            } else if lock_me.internal.state == MagSensorState::Measuring {
+                                let parameters = lock_me.parameters.clone();
+                                let profile_index = parameters.active_profile_index();
+                                let profile = &parameters.profiles[profile_index];
+
+                                // Switching profiles invalidates what the estimator learned so far.
+                                if lock_me.internal.hard_iron_profile != profile_index {
+                                    lock_me.internal.hard_iron.reset();
+                                    lock_me.internal.hard_iron_profile = profile_index;
                                 }
 
-                                if changed {
-                                    let event = MagSensorEvent::CalibratedChanged(
-                                        (*max_x.get(), *min_x.get()),
-                                        (*max_y.get(), *min_y.get()),
-                                        (*max_z.get(), *min_z.get()),
-                                    );
-
-                                    if let Err(e) = lock_me.send_event(event) {
-                                        log::error!("Error sending event: {}", e);
+                                let raw = Vector3::new((x + avg.x) / 2.0, (y + avg.y) / 2.0, (z + avg.z) / 2.0);
+
+                                // An uncalibrated profile uses the raw field as is.
+                                let correction = lock_me
+                                    .hard_iron_correction()
+                                    .unwrap_or(HardIronCorrection::MinMax(Vector3::new(0.0, 0.0, 0.0)));
+                                let stored_offset = correction.offset();
+                                let corrected = correction.apply(raw);
+
+                                if *parameters.auto_calibration.lock().unwrap().get() != 0 {
+                                    match correction {
+                                        // A sphere fit of the raw field would fight the soft-iron distortion,
+                                        // the estimator tracks the offset left in the corrected field instead.
+                                        HardIronCorrection::Ellipsoid(fit) => {
+                                            if let Some(residual) = lock_me.internal.hard_iron.update(corrected, Vector3::new(0.0, 0.0, 0.0)) {
+                                                match fit.raw_offset(residual) {
+                                                    Some(offset) => {
+                                                        lock_me.commit_hard_iron(offset, stored_offset);
+                                                        // The corrected field moved under the estimate.
+                                                        lock_me.internal.hard_iron.reset();
+                                                    }
+                                                    None => log::warn!("Magnetometer: Singular soft-iron matrix, hard-iron update dropped"),
+                                                }
+                                            }
+                                        }
+                                        HardIronCorrection::MinMax(_) => {
+                                            if let Some(offset) = lock_me.internal.hard_iron.update(raw, stored_offset) {
+                                                lock_me.commit_hard_iron(offset, stored_offset);
+                                            }
+                                        }
                                     }
                                 }
-                            } else if lock_me.internal.state == MagSensorState::Measuring {
-                                let calc_x =
-                                    (x + avg.x) / 2.0 - ((*max_x.get() + *min_x.get()) / 2.0);
-                                let calc_y =
-                                    (y + avg.y) / 2.0 - ((*max_y.get() + *min_y.get()) / 2.0);
 
-                                let mut heading =
-                                    (calc_x.atan2(calc_y) * 180.0) / std::f32::consts::PI;
+                                // The chip already subtracted the on-chip offsets, the software hard-iron
+                                // step is skipped and only the soft-iron matrix is left. The result is the
+                                // same, what the offsets buy is headroom: the field stays centered in the
+                                // ADC range, a large hard-iron offset neither clips nor forces a wider range.
+                                let calibrated = if lock_me.onchip_offsets() {
+                                    correction.apply_centered(Vector3::new(raw.x - applied.x, raw.y - applied.y, raw.z - applied.z))
+                                } else {
+                                    corrected
+                                };
 
-                                if heading < 0.0 {
-                                    heading = heading + 360.0;
-                                }
+                                // Without an accelerometer, or without Z, the board is assumed to be level.
+                                let z_measured = lock_me.driver.mode_axes().contains(MLX90393AXIS::Z);
+                                let horizontal = match lock_me.accelerometer.as_ref().filter(|_| z_measured) {
+                                    Some(accelerometer) => {
+                                        match accelerometer.lock().unwrap().read_acceleration() {
+                                            Ok(accel) => tilt_compensate(calibrated, acceleration.update(accel)),
+                                            Err(e) => {
+                                                log::warn!("Error reading accelerometer: {}", e);
+                                                calibrated
+                                            }
+                                        }
+                                    }
+                                    None => calibrated,
+                                };
 
-                                let value = match measure_event.clone() {
-                                    MagSensorEvent::HeadingChanged(value) => value,
-                                    _ => 0,
+                                let sensor = (horizontal.x.atan2(horizontal.y) * 180.0) / std::f32::consts::PI;
+
+                                // Mounting offset aligns the sensor with the lubber line, the deviation
+                                // card then corrects the residual error of the installation.
+                                let compass = normalize_degrees(sensor + *profile.mounting_offset.lock().unwrap().get());
+                                let magnetic = normalize_degrees(
+                                    compass + deviation_at(profile.deviation.lock().unwrap().get(), compass),
+                                );
+
+                                let declination = *parameters.declination.lock().unwrap().get();
+                                let heading = Heading {
+                                    compass,
+                                    magnetic,
+                                    true_heading: normalize_degrees(magnetic + declination),
+                                    degraded: lock_me.internal.degraded,
                                 };
+                                lock_me.internal.last_heading = Some(heading);
 
-                                // Calculate the difference between the current heading and the previous heading in degrees
-                                let diff = if (value - heading.round() as i32).abs() > 180 {
-                                    360 - (value - heading.round() as i32).abs()
-                                } else {
-                                    (value - heading.round() as i32).abs()
+                                let last = match measure_event {
+                                    MagSensorEvent::HeadingChanged(last) => last,
+                                    _ => Heading::default(),
                                 };
 
-                                if diff > 2 {
-                                    measure_event =
-                                        MagSensorEvent::HeadingChanged(heading.round() as i32);
-                                    if let Err(e) = lock_me.send_event(measure_event.clone()) {
+                                // Report when either heading moved, so a declination change is propagated
+                                // even while the device is not rotating.
+                                if angle_difference(last.magnetic, heading.magnetic) > HEADING_CHANGE_THRESHOLD
+                                    || angle_difference(last.true_heading, heading.true_heading)
+                                        > HEADING_CHANGE_THRESHOLD
+                                {
+                                    measure_event = MagSensorEvent::HeadingChanged(heading);
+                                    if let Err(e) = lock_me.send_event(measure_event) {
                                         log::error!("Error sending event: {}", e);
                                     }
                                 }
                             }
                         }
-                        Err(e) => log::error!("Error reading measurement: {}", e),
+                        Err(e) => {
+                            log::error!("Error reading measurement: {}", e);
+
+                            lock_me.driver.health_mut().consecutive_failures += 1;
+                            if lock_me.driver.health().consecutive_failures >= RESET_AFTER_FAILURES {
+                                drop(lock_me);
+                                if let Err(e) = Self::reset_and_reconfigure(&shared_self) {
+                                    log::error!("Error resetting magnetometer: {}", e);
+                                }
+                            }
+                        }
                     }
                 }
             }
@@ -313,172 +418,396 @@ impl MLX90393 {
         Ok(())
     }
 
-    fn init_i2c(
-        i2c: impl Peripheral<P = impl I2c> + 'static,
-        config: Arc<Mutex<MLX90393Config>>,
-    ) -> Result<I2cDriver<'static>, Box<dyn std::error::Error>> {
-        let sda = unsafe { config.lock().unwrap().sda.clone_unchecked() };
-        let scl = unsafe { config.lock().unwrap().scl.clone_unchecked() };
-        let config = esp_idf_hal::i2c::I2cConfig::new().baudrate(Hertz(100000));
-        let i2c = I2cDriver::new(i2c, sda, scl, &config)?;
-        Ok(i2c)
-    }
-
-    fn configure(&self) -> Result<(), Box<dyn std::error::Error>> {
+    fn configure(shared: &Mutex<MLX90393Inner>) -> Result<(), Box<dyn std::error::Error>> {
         thread::sleep(std::time::Duration::from_millis(100));
-        if let Err(e) = self.exit_mode() {
+        if let Err(e) = shared.lock().unwrap().driver.exit_mode() {
             log::warn!("Error exiting mode: {}", e);
         }
         thread::sleep(std::time::Duration::from_millis(100));
-        if let Err(e) = self.reset() {
+        if let Err(e) = shared.lock().unwrap().driver.reset() {
             log::warn!("Error resetting magnetometer: {}", e);
         }
-        thread::sleep(std::time::Duration::from_millis(2000));
+        thread::sleep(RESET_TIME);
+
+        let mut inner = shared.lock().unwrap();
+
+        // The chip starts from the configuration stored by a previous run, only what
+        // differs is written and then stored again.
+        if let Err(e) = inner.driver.memory_recall() {
+            log::warn!("Error recalling memory: {}", e);
+        }
+
+        let compensation = inner.internal.temperature_compensation;
+        // The sensitivity tables depend on HALLCONF, it is always the configured one.
+        let hallconf = inner.internal.hallconf;
+
+        check_filter_oversampling::<EspError>(MLX90393FILTER::FILTER5, MLX90393OVERSAMPLING::OSR3)?;
+
+        let configuration = [
+            (MLX90393Field::HALLCONF, hallconf as u16),
+            (MLX90393Field::RES_X, MLX90393RESOLUTION::RES19 as u16),
+            (MLX90393Field::RES_Y, MLX90393RESOLUTION::RES19 as u16),
+            (MLX90393Field::RES_Z, MLX90393RESOLUTION::RES16 as u16),
+            (MLX90393Field::OSR, MLX90393OVERSAMPLING::OSR3 as u16),
+            (MLX90393Field::DIG_FILT, MLX90393FILTER::FILTER5 as u16),
+            (MLX90393Field::TCMP_EN, compensation as u16),
+        ];
+
+        let mut registers = inner.driver.read_registers()?;
+        let mut rewritten = 0;
+
+        for (field, value) in configuration {
+            if registers.field(field) != Some(value) {
+                log::debug!("Magnetometer: {:?} is {:?}, writing {}", field, registers.field(field), value);
+                registers.values[field.register as usize] = inner.driver.write_field(field, value)?;
+                rewritten += 1;
+            }
+        }
+
+        // Auto-ranging moves the gain in the volatile registers only, one stored with the
+        // offsets is set back without storing it again.
+        if registers.field(MLX90393Field::GAIN_SEL) != Some(MLX90393GAIN::GAIN1X as u16) {
+            log::debug!("Magnetometer: Gain is {:?}, resetting", registers.field(MLX90393Field::GAIN_SEL));
+            registers.values[MLX90393Field::GAIN_SEL.register as usize] =
+                inner.driver.write_field(MLX90393Field::GAIN_SEL, MLX90393GAIN::GAIN1X as u16)?;
+        }
+        inner.internal.range.reset();
+
+        inner.driver.load_registers(&registers)?;
+
+        if rewritten > 0 {
+            inner.driver.memory_store()?;
+            log::info!("Magnetometer: {} settings rewritten and stored", rewritten);
+        } else {
+            log::info!("Magnetometer: Stored configuration verified");
+        }
+
+        let reference = inner.driver.get_temperature_reference()?;
+        log::debug!("Magnetometer: TREF {}, temperature compensation {}", reference, compensation);
+
+        if compensation {
+            let offset = inner.driver.read_offsets()?;
+            log::debug!("Magnetometer: On-chip offsets {:?} LSB", offset);
+        }
 
-        self.set_gain(MLX90393GAIN::GAIN1X)?;
-        self.set_resolution(MLX90393AXIS::X, MLX90393RESOLUTION::RES19)?;
-        self.set_resolution(MLX90393AXIS::Y, MLX90393RESOLUTION::RES19)?;
-        self.set_resolution(MLX90393AXIS::Z, MLX90393RESOLUTION::RES16)?;
-        self.set_oversampling(MLX90393OVERSAMPLING::OSR3)?;
-        self.set_filter(MLX90393FILTER::FILTER5)?;
+        log::debug!("Magnetometer registers:\n{}", registers);
 
         Ok(())
     }
 
-    pub fn read_register(&self, register: MLX90393REG) -> Result<u16, Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().read_register(register)
+    /// Resets the chip after repeated failures, writes the configuration again and
+    /// resumes measuring or calibrating.
+    fn reset_and_reconfigure(shared: &Mutex<MLX90393Inner>) -> Result<(), Box<dyn std::error::Error>> {
+        let state = {
+            let mut inner = shared.lock().unwrap();
+            let health = inner.driver.health_mut();
+            health.resets += 1;
+            health.consecutive_failures = 0;
+            log::warn!("Magnetometer: Resetting after repeated failures, {:?}", inner.driver.health());
+            inner.internal.state
+        };
+
+        Self::configure(shared)?;
+
+        let mut inner = shared.lock().unwrap();
+        match state {
+            MagSensorState::Measuring => inner.start_measuring()?,
+            MagSensorState::Calibrating => inner.driver.start_burst_measurement_axes(MLX90393Axes::ALL)?,
+            MagSensorState::Idle => {}
+        }
+
+        Ok(())
+    }
+
+    /// Stores the on-chip offsets in the chip memory, see `MLX90393Inner::store_offsets`.
+    pub fn store_offsets(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().store_offsets()
+    }
+
+    /// Failure counters of this sensor.
+    pub fn health(&self) -> MLX90393Health {
+        self.inner.lock().unwrap().driver.health()
+    }
+
+    /// Stores the current registers in the chip, they are recalled at the next boot.
+    pub fn memory_store(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.memory_store()
+    }
+
+    /// Reloads the registers stored in the chip, dropping unsaved changes.
+    pub fn memory_recall(&self) -> Result<(), MLX90393Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.driver.memory_recall()?;
+        let registers = inner.driver.read_registers()?;
+        inner.driver.load_registers(&registers)
+    }
+
+    pub fn read_register(&self, register: MLX90393REG) -> Result<u16, MLX90393Error> {
+        self.inner.lock().unwrap().driver.read_register(register)
     }
 
     pub fn write_register(
         &self,
         register: MLX90393REG,
         value: u16,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().write_register(register, value)
+    ) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.write_register(register, value)
     }
 
-    pub fn read_measurement(&self) -> Result<[f32; 3], Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().read_measurement()
+    pub fn read_field(&self, field: MLX90393Field) -> Result<u16, MLX90393Error> {
+        self.inner.lock().unwrap().driver.read_field(field)
     }
 
-    pub fn set_gain(&self, new_gain: MLX90393GAIN) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().set_gain(new_gain)
+    pub fn write_field(&self, field: MLX90393Field, value: u16) -> Result<u16, MLX90393Error> {
+        self.inner.lock().unwrap().driver.write_field(field, value)
     }
 
-    pub fn get_gain(&self) -> Result<MLX90393GAIN, Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().get_gain()
+    /// Sets the wake-on-change thresholds, xy and z in µT and t in °C. They are persisted
+    /// and applied by the measurement thread.
+    pub fn set_wakeup_thresholds(&self, xy: f32, z: f32, t: f32) -> Result<(), Box<dyn std::error::Error>> {
+        for value in [xy, z, t] {
+            if !value.is_finite() || value <= 0.0 {
+                return Err(Box::new(MLX90393Error::invalid_config(format!("invalid wake-up threshold {}", value))));
+            }
+        }
+
+        let parameters = self.inner.lock().unwrap().parameters.clone();
+        parameters.wakeup_threshold_xy.lock().unwrap().set(xy)?;
+        parameters.wakeup_threshold_z.lock().unwrap().set(z)?;
+        parameters.wakeup_threshold_t.lock().unwrap().set(t)?;
+        Ok(())
+    }
+
+    pub fn wakeup_thresholds(&self) -> (f32, f32, f32) {
+        self.inner.lock().unwrap().configured_wakeup_thresholds()
+    }
+
+    /// Snapshot of all the user registers, its `Display` is a readable dump.
+    pub fn read_registers(&self) -> Result<MLX90393Registers, MLX90393Error> {
+        self.inner.lock().unwrap().driver.read_registers()
+    }
+
+    pub fn read_measurement(&self) -> Result<MLX90393Measurement, MLX90393Error> {
+        self.inner.lock().unwrap().driver.read_measurement()
+    }
+
+    pub fn set_temperature_compensation(&self, enabled: bool) -> Result<(), MLX90393Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.driver.set_temperature_compensation(enabled)?;
+        inner.internal.temperature_compensation = enabled;
+        Ok(())
+    }
+
+    pub fn set_hallconf(&self, new_hallconf: MLX90393HALLCONF) -> Result<(), MLX90393Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.driver.set_hallconf(new_hallconf)?;
+        inner.internal.hallconf = new_hallconf;
+        Ok(())
+    }
+
+    pub fn get_hallconf(&self) -> Result<MLX90393HALLCONF, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_hallconf()
+    }
+
+    pub fn set_gain(&self, new_gain: MLX90393GAIN) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.set_gain(new_gain)
+    }
+
+    pub fn get_gain(&self) -> Result<MLX90393GAIN, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_gain()
     }
 
     pub fn set_resolution(
         &self,
         axis: MLX90393AXIS,
         new_resolution: MLX90393RESOLUTION,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), MLX90393Error> {
         self.inner
             .lock()
             .unwrap()
+            .driver
             .set_resolution(axis, new_resolution)
     }
 
     pub fn get_resolution(
         &self,
         axis: MLX90393AXIS,
-    ) -> Result<MLX90393RESOLUTION, Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().get_resolution(axis)
+    ) -> Result<MLX90393RESOLUTION, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_resolution(axis)
     }
 
-    pub fn set_filter(&self, new_filter: MLX90393FILTER) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().set_filter(new_filter)
+    pub fn set_filter(&self, new_filter: MLX90393FILTER) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.set_filter(new_filter)
     }
 
-    pub fn get_filter(&self) -> Result<MLX90393FILTER, Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().get_filter()
+    pub fn get_filter(&self) -> Result<MLX90393FILTER, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_filter()
     }
 
     pub fn set_oversampling(
         &self,
         new_oversampling: MLX90393OVERSAMPLING,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+    ) -> Result<(), MLX90393Error> {
         self.inner
             .lock()
             .unwrap()
+            .driver
             .set_oversampling(new_oversampling)
     }
 
-    pub fn get_oversampling(&self) -> Result<MLX90393OVERSAMPLING, Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().get_oversampling()
+    pub fn get_oversampling(&self) -> Result<MLX90393OVERSAMPLING, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_oversampling()
+    }
+
+    /// Axes converted while measuring. Reading only X/Y shortens the conversion and the
+    /// transfer, Z is needed for tilt compensation. The heading needs X and Y, a selection
+    /// without them is rejected. Calibration always uses all axes.
+    pub fn set_axes(&self, axes: MLX90393Axes) -> Result<(), Box<dyn std::error::Error>> {
+        if !axes.contains(MLX90393AXIS::X) || !axes.contains(MLX90393AXIS::Y) {
+            return Err(Box::new(MLX90393Error::invalid_config("the heading needs the X and Y axes")));
+        }
+
+        let mut inner_lock = self.inner.lock().unwrap();
+        inner_lock.driver.set_axes(axes)?;
+        if inner_lock.internal.state == MagSensorState::Measuring {
+            inner_lock.start_measuring()?;
+        }
+        Ok(())
+    }
+
+    pub fn get_axes(&self) -> MLX90393Axes {
+        self.inner.lock().unwrap().driver.get_axes()
+    }
+
+    pub fn set_burst_data_rate(&self, interval: Duration) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.set_burst_data_rate(interval)
+    }
+
+    pub fn set_temperature_oversampling(&self, new_oversampling: MLX90393OVERSAMPLING) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.set_temperature_oversampling(new_oversampling)
     }
 
-    pub fn set_trigger_interval(&self, state: bool) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().set_trigger_interval(state)
+    /// Expected duration of a single measurement of `axes` with the current settings.
+    pub fn conversion_time(&self, axes: MLX90393Axes) -> Result<Duration, MLX90393Error> {
+        self.inner.lock().unwrap().driver.conversion_time(axes)
     }
 
-    pub fn start_single_measurement(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().start_single_measurement()
+    pub fn get_burst_data_rate(&self) -> Result<Duration, MLX90393Error> {
+        self.inner.lock().unwrap().driver.get_burst_data_rate()
     }
 
-    pub fn start_burst_measurement(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().start_burst_measurement()
+    pub fn set_trigger_interval(&self, state: bool) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.set_trigger_interval(state)
     }
 
-    pub fn start_wakeup_measurement(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().start_wakeup_measurement()
+    pub fn start_single_measurement(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.start_single_measurement()
     }
 
-    pub fn exit_mode(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().exit_mode()
+    pub fn start_burst_measurement(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.start_burst_measurement()
     }
 
-    pub fn reset(&self) -> Result<(), Box<dyn std::error::Error>> {
-        self.inner.lock().unwrap().reset()
+    pub fn start_wakeup_measurement(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.start_wakeup_measurement()
+    }
+
+    pub fn exit_mode(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.exit_mode()
+    }
+
+    pub fn reset(&self) -> Result<(), MLX90393Error> {
+        self.inner.lock().unwrap().driver.reset()
     }
 }
 
 impl MagSensor for MLX90393 {
     fn calibrate(&self, timeout: Duration) -> Result<(), Box<dyn std::error::Error>> {
-        {
-            let mut inner_lock = self.inner.lock().unwrap();
+        let mut inner_lock = self.inner.lock().unwrap();
 
-            if let Err(e) = inner_lock.exit_mode() {
-                log::warn!("Error exiting mode: {}", e);
-            }
+        if inner_lock.internal.state == MagSensorState::Calibrating {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: calibration already running")));
+        }
 
-            thread::sleep(Duration::from_millis(100));
+        if let Err(e) = inner_lock.driver.exit_mode() {
+            log::warn!("Error exiting mode: {}", e);
+        }
 
-            inner_lock.start_burst_measurement()?;
-            inner_lock.set_state(MagSensorState::Calibrating);
+        // The fit needs the three magnetic axes whatever is selected for measuring.
+        inner_lock.driver.start_burst_measurement_axes(MLX90393Axes::ALL)?;
+        inner_lock.start_calibration(timeout);
+        inner_lock.set_state(MagSensorState::Calibrating);
 
-            log::debug!("Magnetometer: Calibrating");
-        }
-        thread::sleep(timeout);
-        {
-            let mut inner_lock = self.inner.lock().unwrap();
-            if let Err(e) = inner_lock.exit_mode() {
-                log::warn!("Error exiting mode: {}", e);
-            }
-            thread::sleep(Duration::from_millis(100));
-            inner_lock.set_state(MagSensorState::Idle);
-            log::debug!("Magnetometer: Calibration complete");
-        }
+        log::debug!("Magnetometer: Calibrating");
         Ok(())
     }
 
+    fn cancel_calibration(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().cancel_calibration()
+    }
+
     fn add_handler(&self, handler: MagSensorHandlerPtr) -> Result<(), Box<dyn std::error::Error>> {
         self.inner.lock().unwrap().add_handler(handler)
     }
 
-    fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+    fn set_accelerometer(&self, accelerometer: AccelerometerPtr) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().accelerometer = Some(accelerometer);
+        log::debug!("Magnetometer: Tilt compensation enabled");
+        Ok(())
+    }
+
+    fn record_deviation(&self, true_heading: f32) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().record_deviation(true_heading)
+    }
+
+    fn settings(&self) -> Result<SensorSettings, Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().settings()
+    }
+
+    fn import_calibration(&self, blob: &CalibrationBlob) -> Result<(), Box<dyn std::error::Error>> {
+        self.inner.lock().unwrap().import_calibration(blob)
+    }
+
+    fn self_test(&self) -> Result<SelfTestResult, Box<dyn std::error::Error>> {
         let mut inner_lock = self.inner.lock().unwrap();
-        if let Err(e) = inner_lock.exit_mode() {
-            log::warn!("Error exiting mode: {}", e);
+
+        let state = inner_lock.internal.state;
+        if state == MagSensorState::Calibrating {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: calibration running")));
         }
-        thread::sleep(Duration::from_millis(100));
-        inner_lock.set_wakeup_comparator(true)?;
-        inner_lock.start_wakeup_measurement()?;
-        inner_lock.set_state(MagSensorState::Measuring);
 
-        log::debug!("Magnetometer: Measurement started");
-        Ok(())
+        if state == MagSensorState::Measuring {
+            if let Err(e) = inner_lock.driver.exit_mode() {
+                log::warn!("Error exiting mode: {}", e);
+            }
+        }
+
+        let result = inner_lock.self_test();
+
+        // A test that could not run is reported as failed, the sensor is not trusted.
+        let finished = match &result {
+            Ok(result) => *result,
+            Err(_) => SelfTestResult { passed: false, delta: Vector3::new(0.0, 0.0, 0.0) },
+        };
+        if let Err(e) = inner_lock.send_event(MagSensorEvent::SelfTestFinished(finished)) {
+            log::error!("Error sending event: {}", e);
+        }
+
+        if state == MagSensorState::Measuring {
+            inner_lock.start_measuring()?;
+        }
+
+        Ok(result?)
+    }
+
+    /// Runs the self-test before measuring. A failure leaves the sensor degraded but
+    /// still measuring.
+    fn start(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = self.self_test() {
+            log::error!("Error running self-test: {}", e);
+        }
+
+        self.inner.lock().unwrap().start_measuring()
     }
 }
 
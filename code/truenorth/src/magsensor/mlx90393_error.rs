@@ -0,0 +1,4 @@
+use esp_idf_sys::EspError;
+
+/// Errors of the MLX90393 driver with the ESP-IDF I2C and SPI drivers underneath.
+pub type MLX90393Error = mlx90393::MLX90393Error<EspError>;
@@ -1,97 +1,82 @@
 use std::sync::mpsc::{Receiver, Sender};
 use std::sync::{mpsc, Arc, Mutex};
-use std::{thread, time::Duration};
-
-use esp_idf_hal::delay::BLOCK;
-use esp_idf_hal::gpio::AnyIOPin;
-use esp_idf_hal::i2c::I2cDriver;
-
-use crate::magsensor::mlx90393_defs::*;
+use std::time::{Duration, Instant};
+
+use esp_idf_hal::delay::Delay;
+use mlx90393::defs::*;
+use mlx90393::{MLX90393AutoRange, MLX90393Driver, MLX90393Measurement};
+use compass::blob::CalibrationBlob;
+use compass::calibration::{fit_ellipsoid, CalibrationMode, CalibrationSession, EllipsoidFit, HardIronCorrection, HardIronEstimator};
+use compass::deviation::record_deviation;
+use compass::math::{normalize_degrees, signed_angle_difference, Vector3};
+
+use crate::accelerometer::AccelerometerPtr;
+use crate::calibration_blob::import_profile;
+use crate::magsensor::mlx90393_error::MLX90393Error;
+use crate::magsensor::mlx90393_transport::MLX90393TransportPtr;
 use crate::TrueNorthParameters;
 
-use super::{MagSensorEvent, MagSensorHandlerPtr, MagSensorState};
-
-// HALLCONF - 0x00
-// is the same table applying a scale factor of 98/75
-const GAIN_RES_CONVERSION: [[(f32, f32); 8];4] = [
-    // HALLCONF - 0xC
-    [
-        (0.751, 1.210),
-        (0.601, 0.968),
-        (0.451, 0.726),
-        (0.376, 0.605),
-        (0.300, 0.484),
-        (0.250, 0.403),
-        (0.200, 0.323),
-        (0.150, 0.242),
-    ],
-    [
-        (1.502, 2.420),
-        (1.202, 1.936),
-        (0.901, 1.452),
-        (0.751, 1.210),
-        (0.601, 0.968),
-        (0.501, 0.807),
-        (0.401, 0.645),
-        (0.300, 0.484),
-    ],
-    [
-        (3.004, 4.840),
-        (2.403, 3.872),
-        (1.803, 2.904),
-        (1.502, 2.420),
-        (1.202, 1.936),
-        (1.001, 1.613),
-        (0.801, 1.291),
-        (0.601, 0.968),
-    ],
-    [
-        (6.009, 9.680),
-        (4.840, 7.744),
-        (3.605, 5.808),
-        (3.004, 4.840),
-        (2.403, 3.872),
-        (2.003, 3.227),
-        (1.602, 2.581),
-        (1.202, 1.936),
-    ],
-];
+use super::{Heading, MagSensorEvent, MagSensorHandlerPtr, MagSensorState, SelfTestResult, SensorSettings};
+
+// Wait after a failed measurement restart, doubled after every further failure.
+const RESTART_BACKOFF: Duration = Duration::from_millis(500);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
 
 pub struct MLX90393Internal {
-    pub current_gain: Option<MLX90393GAIN>,
-    pub current_resolution: Option<u16>,
-    pub current_filter: Option<MLX90393FILTER>,
-    pub current_oversampling: Option<MLX90393OVERSAMPLING>,
     pub state: MagSensorState,
     pub last_state: MagSensorState,
     pub channel: Arc<Mutex<(Sender<bool>,Receiver<bool>)>>,
     pub handlers: Vec<Arc<Mutex<MagSensorHandlerPtr>>>,
+    pub calibration: Option<CalibrationSession>,
+    // Profile the running calibration is stored to, even if another one is selected meanwhile.
+    pub calibration_profile: usize,
+    pub hard_iron: HardIronEstimator,
+    // Profile the hard-iron estimator is tracking.
+    pub hard_iron_profile: usize,
+    // Latest heading, also when it was not reported.
+    pub last_heading: Option<Heading>,
+    // Configured HALLCONF and TCMP_EN, written again whenever the chip is configured.
+    pub hallconf: MLX90393HALLCONF,
+    pub temperature_compensation: bool,
+    // Set by a failed self-test.
+    pub degraded: bool,
+    pub range: MLX90393AutoRange,
+    // Failed measurement restarts in a row and when the next one may be tried.
+    pub restart_failures: u32,
+    pub restart_at: Option<Instant>,
 }
 
 impl Default for MLX90393Internal {
     fn default() -> Self {
         let (tx, rx) = mpsc::channel::<bool>();
         Self {
-            current_gain: None,
-            current_resolution: None,
-            current_filter: None,
-            current_oversampling: None,
             state: MagSensorState::Idle,
             last_state: MagSensorState::Idle,
             channel: Arc::new(Mutex::new((tx, rx))),
             handlers: Vec::new(),
+            calibration: None,
+            calibration_profile: 0,
+            hard_iron: HardIronEstimator::default(),
+            hard_iron_profile: 0,
+            last_heading: None,
+            hallconf: MLX90393HALLCONF::HALLCONF_C,
+            temperature_compensation: false,
+            degraded: false,
+            range: MLX90393AutoRange::new(),
+            restart_failures: 0,
+            restart_at: None,
         }
     }
 }
 
 pub struct MLX90393Inner {
-    pub i2c: Option<I2cDriver<'static>>,
-    pub int: AnyIOPin,
-    pub slave_address: u8,
+    pub driver: MLX90393Driver<MLX90393TransportPtr, Delay>,
     pub parameters: Arc<TrueNorthParameters>,
+    pub accelerometer: Option<AccelerometerPtr>,
     pub internal: MLX90393Internal,
 }
 
+
 impl MLX90393Inner {
 
     pub fn send_event(&mut self, event: MagSensorEvent) -> Result<(), Box<dyn std::error::Error>> {
@@ -107,341 +92,475 @@ impl MLX90393Inner {
         self.internal.state = state;
     }
 
-    #[allow(dead_code)]
-    pub fn read_register(&mut self, register: MLX90393REG) -> Result<u16, Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 2] = [MLX90393CMD::RR.into(), (register as u8) << 2];
-        let mut rx_buf: [u8; 3] = [0; 3];
+    pub fn start_calibration(&mut self, timeout: Duration) {
+        let parameters = self.parameters.clone();
+        let index = parameters.active_profile_index();
+        let profile = &parameters.profiles[index];
 
-        let slave_address = self.slave_address;
+        // Starting from the stored box keeps extending the previous calibration,
+        // a reset clears it first.
+        let min = Vector3::new(*profile.min_x.lock().unwrap().get(), *profile.min_y.lock().unwrap().get(), *profile.min_z.lock().unwrap().get());
+        let max = Vector3::new(*profile.max_x.lock().unwrap().get(), *profile.max_y.lock().unwrap().get(), *profile.max_z.lock().unwrap().get());
 
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
+        self.internal.calibration = Some(CalibrationSession::new(timeout, min, max));
+        self.internal.calibration_profile = index;
+    }
 
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        let ret = (rx_buf[1] as u16) << 8 | rx_buf[2] as u16;   
+    /// Feeds a filtered reading to the running calibration. `sampled` is true when the
+    /// reading is a new sample for the ellipsoid fit.
+    pub fn calibration_update(&mut self, reading: Vector3, sampled: bool) {
+        let mut events = Vec::new();
+        let mut complete = false;
+
+        if let Some(session) = self.internal.calibration.as_mut() {
+            if session.update_bounds(reading) {
+                events.push(MagSensorEvent::CalibratedChanged(
+                    (session.max.x, session.min.x),
+                    (session.max.y, session.min.y),
+                    (session.max.z, session.min.z),
+                ));
+            }
+
+            if sampled && session.add_sample(reading) && session.progress_due() {
+                events.push(MagSensorEvent::CalibrationProgress(session.assess()));
+                complete = session.is_complete();
+            }
+        }
 
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: read_register failed, status: {}", status))));
+        for event in events {
+            if let Err(e) = self.send_event(event) {
+                log::error!("Error sending event: {}", e);
+            }
         }
 
-        Ok(ret)
+        if complete {
+            log::debug!("Magnetometer: Calibration target reached");
+            if let Err(e) = self.finish_calibration() {
+                log::error!("Error finishing calibration: {}", e);
+            }
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn write_register(&mut self, register: MLX90393REG, value: u16) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 4] = [MLX90393CMD::WR.into(), ((value >> 8) & 0xFF) as u8, (value & 0xFF) as u8, (register as u8) << 2];
-        let mut rx_buf: [u8; 1] = [0; 1];
-
-        let slave_address = self.slave_address;
-
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
-
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: write_register failed, status: {}", status))));
+    /// Runs a raw reading through the outlier gate of the running calibration.
+    /// Always true when not calibrating.
+    pub fn calibration_accept(&mut self, raw: Vector3) -> bool {
+        match self.internal.calibration.as_mut() {
+            Some(session) => session.outliers.accept(raw),
+            None => true,
         }
-
-        Ok(())
     }
 
+    pub fn calibration_expired(&self) -> bool {
+        self.internal.calibration.as_ref().map(|session| session.is_expired()).unwrap_or(false)
+    }
 
-    #[allow(dead_code)]
-    pub fn read_measurement(&mut self) -> Result<[f32; 3], Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::RM as u8 | MLX90393AXIS::ALL as u8];
-        let mut rx_buf: [u8; 9] = [0; 9];
+    /// Stores the result of the running calibration and goes back to measuring.
+    pub fn finish_calibration(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let session = match self.internal.calibration.take() {
+            Some(session) => session,
+            None => return Ok(()),
+        };
 
-        let slave_address = self.slave_address;
+        if let Err(e) = self.driver.exit_mode() {
+            log::warn!("Error exiting mode: {}", e);
+        }
+        self.set_state(MagSensorState::Idle);
+
+        let fit = fit_ellipsoid(&session.samples);
+
+        let quality = session.quality(fit.as_ref());
+        log::debug!("Magnetometer: Calibration quality {:?}", quality);
+        log::info!(
+            "Magnetometer: {} calibration samples rejected ({} magnitude, {} jump)",
+            quality.rejected,
+            session.outliers.rejected_magnitude(),
+            session.outliers.rejected_jump()
+        );
+
+        let parameters = self.parameters.clone();
+        let profile = &parameters.profiles[self.internal.calibration_profile];
+
+        // An empty box means no sample was collected, keep the previous values.
+        if session.max.x >= session.min.x && session.max.y >= session.min.y && session.max.z >= session.min.z {
+            if let Err(e) = profile.max_x.lock().unwrap().set(session.max.x) {
+                log::error!("Error setting max_x: {}", e);
+            }
+            if let Err(e) = profile.min_x.lock().unwrap().set(session.min.x) {
+                log::error!("Error setting min_x: {}", e);
+            }
+            if let Err(e) = profile.max_y.lock().unwrap().set(session.max.y) {
+                log::error!("Error setting max_y: {}", e);
+            }
+            if let Err(e) = profile.min_y.lock().unwrap().set(session.min.y) {
+                log::error!("Error setting min_y: {}", e);
+            }
+            if let Err(e) = profile.max_z.lock().unwrap().set(session.max.z) {
+                log::error!("Error setting max_z: {}", e);
+            }
+            if let Err(e) = profile.min_z.lock().unwrap().set(session.min.z) {
+                log::error!("Error setting min_z: {}", e);
+            }
+        }
 
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
+        match fit {
+            Some(fit) => {
+                log::debug!("Magnetometer: Ellipsoid fit {:?}, residual {}", fit, fit.residual);
+
+                if let Err(e) = profile.offset.lock().unwrap().set(fit.offset_parameters()) {
+                    log::error!("Error setting offset: {}", e);
+                }
+                if let Err(e) = profile.soft_iron.lock().unwrap().set(fit.soft_iron_parameters()) {
+                    log::error!("Error setting soft_iron: {}", e);
+                }
+
+                if let Err(e) = self.send_event(MagSensorEvent::EllipsoidChanged(fit.offset, fit.soft_iron)) {
+                    log::error!("Error sending event: {}", e);
+                }
+            }
+            None => log::warn!("Magnetometer: Ellipsoid fit failed with {} samples, keeping min/max calibration", session.samples.len()),
+        }
 
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        let val = [
-            (rx_buf[3] as i16) << 8 | rx_buf[4] as i16,
-            (rx_buf[5] as i16) << 8 | rx_buf[6] as i16,
-            (rx_buf[7] as i16) << 8 | rx_buf[8] as i16,
-        ];
+        if let Err(e) = self.send_event(MagSensorEvent::CalibrationFinished(quality)) {
+            log::error!("Error sending event: {}", e);
+        }
 
-        let gain = self.get_gain()?;
-        let x_resolution = self.get_resolution(MLX90393AXIS::X)?;
-        let y_resolution = self.get_resolution(MLX90393AXIS::Y)?;
-        let z_resolution = self.get_resolution(MLX90393AXIS::Z)?;
+        // The background estimator must start over from the new calibration.
+        self.internal.hard_iron.reset();
 
-        let ret = [
-            val[0] as f32 * GAIN_RES_CONVERSION[x_resolution as usize][gain as usize].0,
-            val[1] as f32 * GAIN_RES_CONVERSION[y_resolution as usize][gain as usize].0,
-            val[2] as f32 * GAIN_RES_CONVERSION[z_resolution as usize][gain as usize].1,
-        ];
+        log::debug!("Magnetometer: Calibration complete");
 
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: read_measurement failed, status: {}", status))));
-        }
-
-        Ok(ret)
+        self.resume_measuring()
     }
 
-    #[allow(dead_code)]
-    pub fn set_gain(&mut self, new_gain: MLX90393GAIN) -> Result<(), Box<dyn std::error::Error>> {
-        let mut gain = self.read_register(MLX90393REG::CONF1)?;
-        gain &= !0x0070;
-        gain |= (new_gain as u16) << 4;
+    /// Drops the running calibration without touching the stored values.
+    pub fn cancel_calibration(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.internal.calibration.take().is_none() {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: no calibration running")));
+        }
 
-        self.write_register(MLX90393REG::CONF1, gain)?;
+        if let Err(e) = self.send_event(MagSensorEvent::CalibrationCancelled) {
+            log::error!("Error sending event: {}", e);
+        }
 
-        self.internal.current_gain = Some(new_gain);
+        log::debug!("Magnetometer: Calibration cancelled");
 
-        Ok(())
+        self.resume_measuring()
     }
 
-    #[allow(dead_code)]
-    pub fn get_gain(&mut self) -> Result<MLX90393GAIN, Box<dyn std::error::Error>> {
+    /// Moves the stored hard-iron offset to `offset`. The min/max box is shifted and, when
+    /// present, the ellipsoid offset too, so both calibration modes stay consistent.
+    pub fn commit_hard_iron(&mut self, offset: Vector3, stored_offset: Vector3) {
+        let shift = Vector3::new(offset.x - stored_offset.x, offset.y - stored_offset.y, offset.z - stored_offset.z);
+        let parameters = self.parameters.clone();
+        let profile = &parameters.profiles[self.internal.hard_iron_profile];
+
+        // Without a calibrated box the estimated sphere becomes the new box.
+        let calibrated = *profile.max_x.lock().unwrap().get() >= *profile.min_x.lock().unwrap().get();
+        let radius = self.internal.hard_iron.radius();
+
+        for (parameter, fallback, delta) in [
+            (&profile.max_x, offset.x + radius, shift.x),
+            (&profile.min_x, offset.x - radius, shift.x),
+            (&profile.max_y, offset.y + radius, shift.y),
+            (&profile.min_y, offset.y - radius, shift.y),
+            (&profile.max_z, offset.z + radius, shift.z),
+            (&profile.min_z, offset.z - radius, shift.z),
+        ] {
+            let mut parameter = parameter.lock().unwrap();
+            let value = if calibrated { *parameter.get() + delta } else { fallback };
+            if let Err(e) = parameter.set(value) {
+                log::error!("Error setting calibration bound: {}", e);
+            }
+        }
 
-        if self.internal.current_gain.is_some() {
-            return Ok(self.internal.current_gain.unwrap());
+        let current = profile.offset.lock().unwrap().get().clone();
+        if current.len() == 3 {
+            if let Err(e) = profile.offset.lock().unwrap().set(vec![current[0] + shift.x, current[1] + shift.y, current[2] + shift.z]) {
+                log::error!("Error setting offset: {}", e);
+            }
         }
 
-        let mut gain = self.read_register(MLX90393REG::CONF1)?;
-        gain &= 0x0070;
+        log::info!("Magnetometer: Hard-iron offset updated by {:?} ({} outliers rejected)", shift, self.internal.hard_iron.rejected());
 
-        Ok(MLX90393GAIN::from((gain >> 4) as u8))
+        if let Err(e) = self.send_event(MagSensorEvent::HardIronUpdated(offset)) {
+            log::error!("Error sending event: {}", e);
+        }
+    }
+
+    pub fn settings(&mut self) -> Result<SensorSettings, Box<dyn std::error::Error>> {
+        Ok(SensorSettings {
+            gain: self.driver.get_gain()?.into(),
+            resolution: [
+                self.driver.get_resolution(MLX90393AXIS::X)?.into(),
+                self.driver.get_resolution(MLX90393AXIS::Y)?.into(),
+                self.driver.get_resolution(MLX90393AXIS::Z)?.into(),
+            ],
+            filter: self.driver.get_filter()?.into(),
+            oversampling: self.driver.get_oversampling()?.into(),
+        })
     }
 
-    #[allow(dead_code)]
-    pub fn set_resolution(&mut self, axis: MLX90393AXIS, new_resolution: MLX90393RESOLUTION) -> Result<(), Box<dyn std::error::Error>> {
-        let mut resolution = self.read_register(MLX90393REG::CONF3)?;
-
-        match axis {
-            MLX90393AXIS::X => {
-                resolution &= !0x0060;
-                resolution |= (new_resolution as u16) << 5;
-            },
-            MLX90393AXIS::Y => {
-                resolution &= !0x0180;
-                resolution |= (new_resolution as u16) << 7;
-            },
-            MLX90393AXIS::Z => {
-                resolution &= !0x0600;
-                resolution |= (new_resolution as u16) << 9;
-            },
-            MLX90393AXIS::ALL => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: set_resolution failed, axis ALL not allowed here."))),
+    /// Writes `blob` into the active profile. A running calibration would overwrite it
+    /// when it finishes, so it is refused meanwhile.
+    pub fn import_calibration(&mut self, blob: &CalibrationBlob) -> Result<(), Box<dyn std::error::Error>> {
+        if self.internal.state == MagSensorState::Calibrating {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: calibration running")));
         }
 
-        self.write_register(MLX90393REG::CONF3, resolution)?;
+        let settings = self.settings()?;
+        import_profile(blob, &self.parameters, settings)?;
 
-        self.internal.current_resolution = Some(resolution);
+        // The estimate was tracking the replaced offset.
+        self.internal.hard_iron.reset();
 
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn get_resolution(&mut self, axis: MLX90393AXIS) -> Result<MLX90393RESOLUTION, Box<dyn std::error::Error>> {
-        let resolution = if self.internal.current_resolution.is_some() {
-            self.internal.current_resolution.unwrap()
-        } else {
-            self.read_register(MLX90393REG::CONF3)?
+    /// Stores the deviation of the current heading given the known `true_heading`.
+    pub fn record_deviation(&mut self, true_heading: f32) -> Result<(), Box<dyn std::error::Error>> {
+        if self.internal.state != MagSensorState::Measuring {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: not measuring")));
+        }
+
+        let heading = match self.internal.last_heading {
+            Some(heading) => heading,
+            None => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: no heading yet"))),
         };
 
-        Ok(MLX90393RESOLUTION::from(match axis {
-            MLX90393AXIS::X => (((resolution & 0x0060) >> 5) & 0x03) as u8,
-            MLX90393AXIS::Y => (((resolution & 0x0180) >> 7) & 0x03) as u8,
-            MLX90393AXIS::Z => (((resolution & 0x0600) >> 9) & 0x03) as u8,
-            MLX90393AXIS::ALL => return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: get_resolution failed, axis ALL not allowed here."))),
-        }))
-    }
+        let parameters = self.parameters.clone();
+        let magnetic = normalize_degrees(true_heading - *parameters.declination.lock().unwrap().get());
+        let profile = parameters.profile();
 
-    #[allow(dead_code)]
-    pub fn set_filter(&mut self, new_filter: MLX90393FILTER) -> Result<(), Box<dyn std::error::Error>> {
-        let mut filter = self.read_register(MLX90393REG::CONF3)?;
-        filter &= !0x1C;
-        filter |= (new_filter as u16) << 2;
-        self.write_register(MLX90393REG::CONF3, filter)?;
+        let table = record_deviation(profile.deviation.lock().unwrap().get(), heading.compass, magnetic)?;
+        profile.deviation.lock().unwrap().set(table)?;
 
-        self.internal.current_filter = Some(new_filter);
+        log::info!("Magnetometer: Deviation at compass heading {:.1} recorded as {:.1}", heading.compass, signed_angle_difference(magnetic, heading.compass));
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn get_filter(&mut self) -> Result<MLX90393FILTER, Box<dyn std::error::Error>> {
-        if self.internal.current_filter.is_some() {
-            return Ok(self.internal.current_filter.unwrap());
-        }
+    /// Correction of the active profile, the one the measurement applies. `None` until
+    /// the profile is calibrated.
+    pub fn hard_iron_correction(&self) -> Option<HardIronCorrection> {
+        let parameters = self.parameters.clone();
+        let profile = parameters.profile();
 
-        let filter = self.read_register(MLX90393REG::CONF3)?;
-        Ok(MLX90393FILTER::from(((filter & 0x1C) >> 2) as u8))
-    }
+        let mode = CalibrationMode::from(*parameters.calibration_mode.lock().unwrap().get());
+        let fit = EllipsoidFit::from_parameters(profile.offset.lock().unwrap().get(), profile.soft_iron.lock().unwrap().get());
+        let min = Vector3::new(*profile.min_x.lock().unwrap().get(), *profile.min_y.lock().unwrap().get(), *profile.min_z.lock().unwrap().get());
+        let max = Vector3::new(*profile.max_x.lock().unwrap().get(), *profile.max_y.lock().unwrap().get(), *profile.max_z.lock().unwrap().get());
 
-    #[allow(dead_code)]
-    pub fn set_oversampling(&mut self, new_oversampling: MLX90393OVERSAMPLING) -> Result<(), Box<dyn std::error::Error>> {
-        let mut oversampling = self.read_register(MLX90393REG::CONF3)?;
-        oversampling &= !0x03;
-        oversampling |= new_oversampling as u16;
-        self.write_register(MLX90393REG::CONF3, oversampling)?;
+        HardIronCorrection::select(mode, fit, min, max)
+    }
 
-        self.internal.current_oversampling = Some(new_oversampling);
-        Ok(())
+    /// The hard-iron offset is programmed into OFFSET_X/Y/Z. Needs temperature
+    /// compensation, the chip ignores the offsets otherwise.
+    pub fn onchip_offsets(&self) -> bool {
+        self.internal.temperature_compensation && *self.parameters.onchip_offsets.lock().unwrap().get() != 0
     }
 
-    #[allow(dead_code)]
-    pub fn get_oversampling(&mut self) -> Result<MLX90393OVERSAMPLING, Box<dyn std::error::Error>> {
-        if self.internal.current_oversampling.is_some() {
-            return Ok(self.internal.current_oversampling.unwrap());
-        }
+    // OFFSET_X/Y/Z the chip should hold for the active profile.
+    fn target_chip_offset(&mut self) -> Result<[i16; 3], MLX90393Error> {
+        let offset = match self.hard_iron_correction() {
+            Some(correction) if self.onchip_offsets() => correction.offset(),
+            _ => return Ok([0; 3]),
+        };
 
-        let oversampling = self.read_register(MLX90393REG::CONF3)?;
-        Ok(MLX90393OVERSAMPLING::from((oversampling & 0x03) as u8))
+        let to_lsb = |value: f32, sensitivity: f32| (value / sensitivity).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+
+        Ok([
+            to_lsb(offset.x, self.driver.sensitivity(MLX90393AXIS::X)?),
+            to_lsb(offset.y, self.driver.sensitivity(MLX90393AXIS::Y)?),
+            to_lsb(offset.z, self.driver.sensitivity(MLX90393AXIS::Z)?),
+        ])
     }
 
-    #[allow(dead_code)]
-    pub fn set_trigger_interval(&mut self, state: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let mut trigger = self.read_register(MLX90393REG::CONF2)?;
-        trigger &= !0x8000;
-        if state {
-            trigger |= 0x8000;
+    /// True when the OFFSET registers no longer match the active profile, gain and resolution.
+    pub fn offsets_changed(&mut self) -> bool {
+        if !matches!(self.driver.get_temperature_compensation(), Ok(true)) {
+            return false;
         }
-        self.write_register(MLX90393REG::CONF2, trigger)?;
 
-        Ok(())
+        match self.target_chip_offset() {
+            Ok(target) => self.driver.chip_offset() != Some(target),
+            Err(_) => false,
+        }
     }
 
-    #[allow(dead_code)]
-    pub fn start_single_measurement(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::SM as u8 | MLX90393AXIS::ALL as u8];
-        let mut rx_buf: [u8; 1] = [0; 1];
-
-        let slave_address = self.slave_address;
+    /// Writes the hard-iron offset of the active profile to OFFSET_X/Y/Z, zero when on-chip
+    /// offsets are disabled. Only the volatile registers, see `store_offsets`. The chip must
+    /// be idle.
+    pub fn sync_offsets(&mut self) -> Result<(), MLX90393Error> {
+        if !self.offsets_changed() {
+            return Ok(());
+        }
 
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
+        let offset = self.target_chip_offset()?;
+        self.driver.write_offsets(offset)?;
 
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        let sm_mode = status & 0x20;
+        log::info!("Magnetometer: On-chip offsets {:?} LSB written", offset);
+        Ok(())
+    }
 
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_single_measurement failed, status: {}", status))));
+    /// Stores the registers, on-chip offsets included, in the chip memory with HS so
+    /// they are back after a power cycle. The memory takes a limited number of writes,
+    /// this only runs when asked for. A measurement is restarted.
+    pub fn store_offsets(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let state = self.internal.state;
+        if state == MagSensorState::Calibrating {
+            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, "MLX90393: calibration running")));
         }
 
-        if sm_mode == 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_single_measurement failed, status: {}", status))));
+        if let Err(e) = self.driver.exit_mode() {
+            log::warn!("Error exiting mode: {}", e);
         }
+        self.sync_offsets()?;
+        self.driver.memory_store()?;
+        log::info!("Magnetometer: On-chip offsets {:?} LSB stored", self.driver.chip_offset());
 
+        if state == MagSensorState::Measuring {
+            self.start_measuring()?;
+        }
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn start_burst_measurement(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::SB as u8 | MLX90393AXIS::ALL as u8];
-        let mut rx_buf: [u8; 1] = [0; 1];
-
-        let slave_address = self.slave_address;
-
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
-
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        let bm_mode = status & 0x80;
-
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_burst_measurement failed, status: {}", status))));
-        }
+    /// Field the chip subtracts from its output, in µT. Zero without TCMP_EN.
+    pub fn applied_offset(&mut self) -> Result<Vector3, MLX90393Error> {
+        let [x, y, z] = self.driver.applied_offset()?;
+        Ok(Vector3::new(x, y, z))
+    }
 
-        if bm_mode == 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_burst_measurement failed, status: {}", status))));
+    /// Starts the wake-on-change measurement. The chip is idle once EX is acknowledged,
+    /// nothing waits here with the sensor locked.
+    pub fn start_measuring(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = self.driver.exit_mode() {
+            log::warn!("Error exiting mode: {}", e);
         }
-
+        self.sync_offsets()?;
+        let (xy, z, t) = self.configured_wakeup_thresholds();
+        self.driver.set_wakeup_thresholds(xy, z, t)?;
+        self.driver.set_wakeup_comparator(true)?;
+        self.driver.start_wakeup_measurement()?;
+        self.set_state(MagSensorState::Measuring);
+        self.internal.restart_failures = 0;
+        self.internal.restart_at = None;
+
+        log::debug!("Magnetometer: Measurement started");
         Ok(())
     }
 
-    pub fn set_wakeup_comparator(&mut self, comparator: bool) -> Result<(), Box<dyn std::error::Error>> {
-        let mut comparator_register = self.read_register(MLX90393REG::CONF2)?;
-        if comparator {
-            comparator_register |= 0x10;
-        } else {
-            comparator_register &= !0x10;
+    /// Goes back to measuring after a calibration. If the chip does not start, the
+    /// measurement thread keeps trying, see `restart_measuring`.
+    pub fn resume_measuring(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Err(e) = self.start_measuring() {
+            self.set_state(MagSensorState::Measuring);
+            self.restart_failed(&*e);
+            return Err(e);
         }
-        self.write_register(MLX90393REG::CONF2, comparator_register)?;
         Ok(())
     }
 
-    #[allow(dead_code)]
-    pub fn start_wakeup_measurement(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::SW as u8 | MLX90393AXIS::ALL as u8];
-        let mut rx_buf: [u8; 1] = [0; 1];
-
-        let slave_address = self.slave_address;
-
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
-
-        let status = rx_buf[0];
-        let error = status & 0x10;
-        let wu_mode = status & 0x40;
-
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_wakeup_measurement failed, status: {}", status))));
+    /// Restarts the measurement to apply new thresholds or offsets, or after a failed
+    /// start. A failed restart is tried again later, waiting twice as long after every failure.
+    pub fn restart_measuring(&mut self) {
+        if self.internal.restart_at.is_some_and(|at| Instant::now() < at) {
+            return;
         }
 
-        if wu_mode == 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: start_wakeup_measurement failed, status: {}", status))));
+        if let Err(e) = self.start_measuring() {
+            self.restart_failed(&*e);
         }
+    }
 
-        Ok(())
+    /// True while a failed start waits for its next try.
+    pub fn restart_pending(&self) -> bool {
+        self.internal.restart_at.is_some()
     }
 
-    #[allow(dead_code)]
-    pub fn exit_mode(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::EX as u8];
-        let mut rx_buf: [u8; 1] = [0; 1];
+    fn restart_failed(&mut self, e: &dyn std::error::Error) {
+        self.internal.restart_failures += 1;
+        let backoff = RESTART_BACKOFF
+            .saturating_mul(1 << (self.internal.restart_failures - 1).min(16))
+            .min(RESTART_BACKOFF_MAX);
+        self.internal.restart_at = Some(Instant::now() + backoff);
+        log::error!("Error starting measurement ({} in a row), next try in {:?}: {}", self.internal.restart_failures, backoff, e);
+    }
 
-        let slave_address = self.slave_address;
+    /// Thresholds configured in the parameters: xy and z in µT, t in °C.
+    pub fn configured_wakeup_thresholds(&self) -> (f32, f32, f32) {
+        let parameters = self.parameters.clone();
+        let xy = *parameters.wakeup_threshold_xy.lock().unwrap().get();
+        let z = *parameters.wakeup_threshold_z.lock().unwrap().get();
+        let t = *parameters.wakeup_threshold_t.lock().unwrap().get();
+        (xy, z, t)
+    }
+
+    pub fn wakeup_thresholds_changed(&self) -> bool {
+        self.driver.wakeup_thresholds() != Some(self.configured_wakeup_thresholds())
+    }
+
+    /// Steps the gain when the readings stay near full scale or tiny. The measurement
+    /// mode is restarted, so thresholds and offsets follow the new sensitivity, and the
+    /// µT output stays continuous. The gain is only changed in the volatile registers.
+    /// Only while measuring.
+    pub fn auto_range(&mut self, measurement: &MLX90393Measurement) -> Result<(), Box<dyn std::error::Error>> {
+        if *self.parameters.auto_range.lock().unwrap().get() == 0 || self.internal.state != MagSensorState::Measuring {
+            return Ok(());
+        }
 
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
+        let usage = self.driver.range_usage(measurement)?;
+        let gain = self.driver.get_gain()?;
 
-        let status = rx_buf[0];
-        let error = status & 0x10;
+        let new_gain = match self.internal.range.update(usage, gain) {
+            Some(new_gain) => new_gain,
+            None => return Ok(()),
+        };
 
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: exit_mode failed, status: {}", status))));
+        self.driver.exit_mode()?;
+        if let Err(e) = self.driver.set_gain(new_gain).map_err(Box::from).and_then(|_| self.start_measuring()) {
+            // Back to the range that was measuring, a half done step leaves the chip idle.
+            log::error!("Magnetometer: Range {:?} failed, restoring {:?}", new_gain, gain);
+            if let Err(e) = self.driver.exit_mode() {
+                log::warn!("Error exiting mode: {}", e);
+            }
+            self.driver.set_gain(gain)?;
+            self.start_measuring()?;
+            return Err(e);
         }
 
+        let sensitivity = self.driver.sensitivity(MLX90393AXIS::X)?;
+        log::info!("Magnetometer: Range {:?} at {:.0}% of full scale, now {} uT/LSB", new_gain, usage * 100.0, sensitivity);
+
+        self.send_event(MagSensorEvent::RangeChanged(new_gain as u8, sensitivity))?;
         Ok(())
     }
 
-    pub fn reset(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let tx_buf: [u8; 1] = [MLX90393CMD::RT as u8];
-        let mut rx_buf: [u8; 1] = [0; 1];
-
-        let slave_address = self.slave_address;
-
-        self.i2c.as_mut().unwrap().write(slave_address, &tx_buf, BLOCK)?;
-        thread::sleep(Duration::from_millis(10));
-        self.i2c.as_mut().unwrap().read(slave_address, &mut rx_buf, BLOCK)?;
+    /// Built-in self-test, see `MLX90393Driver::self_test`. A failure, or a test that
+    /// could not run, marks the sensor degraded. The chip must be idle.
+    pub fn self_test(&mut self) -> Result<SelfTestResult, MLX90393Error> {
+        let result = match self.driver.self_test() {
+            Ok(result) => result,
+            Err(e) => {
+                self.internal.degraded = true;
+                log::error!("Magnetometer: Self-test could not run: {}", e);
+                return Err(e);
+            }
+        };
+        let delta = Vector3::new(result.delta[0], result.delta[1], result.delta[2]);
 
-        let status = rx_buf[0];
-        let error = status & 0x10;
+        self.internal.degraded = !result.passed;
 
-        if error != 0 {
-            return Err(Box::new(std::io::Error::new(std::io::ErrorKind::Other, format!("MLX90393: reset failed, status: {}", status))));
+        if result.passed {
+            log::info!("Magnetometer: Self-test passed, delta {:?}", delta);
+        } else {
+            log::error!("Magnetometer: Self-test failed, delta {:?}", delta);
         }
 
-        Ok(())
+        Ok(SelfTestResult { passed: result.passed, delta })
     }
 
+
     pub fn add_handler(&mut self, handler: MagSensorHandlerPtr) -> Result<(), Box<dyn std::error::Error>> {
         self.internal.handlers.push(Arc::new(Mutex::new(handler)));
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,97 @@
+use esp_idf_svc::hal::delay::Delay;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::spi::{config, SpiDeviceDriver, SpiDriver, SPI2};
+use esp_idf_svc::hal::units::FromValueType;
+use esp_idf_sys::EspError;
+use mlx90393::defs::{MLX90393Status, MLX90393CMD};
+use mlx90393::{I2cInterface, MLX90393Interface, SpiInterface};
+
+use crate::i2c_bus::{I2cBus, I2cDevice};
+use crate::magsensor::mlx90393_error::MLX90393Error;
+
+// A0/A1 strapping selects one of these addresses.
+pub const MLX90393_I2C_ADDRESSES: std::ops::RangeInclusive<u8> = 0x0C..=0x0F;
+
+// The chip takes up to 10 MHz, stay well below it for the board wiring.
+const SPI_BAUDRATE_MHZ: u32 = 5;
+
+/// Interface of the driver, the bus errors are reported as `EspError` whatever the bus.
+pub type MLX90393TransportPtr = Box<dyn MLX90393Interface<Error = EspError> + Send>;
+
+/// Bus the sensor is wired to.
+pub enum MLX90393Bus {
+    I2c(I2cDevice),
+    Spi {
+        spi: SPI2,
+        sclk: AnyIOPin,
+        sdo: AnyIOPin,
+        sdi: AnyIOPin,
+        cs: AnyIOPin,
+    },
+}
+
+impl MLX90393Bus {
+    pub fn into_transport(self) -> Result<MLX90393TransportPtr, MLX90393Error> {
+        Ok(match self {
+            MLX90393Bus::I2c(device) => Box::new(MLX90393I2c::new(device)),
+            MLX90393Bus::Spi { spi, sclk, sdo, sdi, cs } => Box::new(MLX90393Spi::new(spi, sclk, sdo, sdi, cs)?),
+        })
+    }
+}
+
+/// Addresses on `bus` answering a NOP with a valid status byte, in ascending order.
+pub fn scan_mlx90393(bus: &I2cBus) -> Vec<u8> {
+    bus.scan(MLX90393_I2C_ADDRESSES, |device| {
+        let mut transport = MLX90393I2c::new(device.clone());
+        let mut status = [0u8; 1];
+        // A missing device NACKs the command, the error bit rules out other chips.
+        transport.transfer(&[MLX90393CMD::NOP as u8], &mut status).is_ok() && !MLX90393Status(status[0]).error()
+    })
+}
+
+/// The I2C bus may be shared with other devices, it is held for the whole command.
+pub struct MLX90393I2c {
+    device: I2cDevice,
+}
+
+impl MLX90393I2c {
+    pub fn new(device: I2cDevice) -> Self {
+        Self { device }
+    }
+}
+
+impl MLX90393Interface for MLX90393I2c {
+    type Error = EspError;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error> {
+        let mut driver = self.device.lock();
+        let mut interface = I2cInterface::new(&mut *driver, self.device.address(), Delay::new_default());
+        interface.transfer(tx_buf, rx_buf).map_err(|e| e.map_bus(|e| e.cause()))
+    }
+
+    fn recover(&mut self) -> Result<(), EspError> {
+        self.device.recover_bus()
+    }
+}
+
+pub struct MLX90393Spi {
+    interface: SpiInterface<SpiDeviceDriver<'static, SpiDriver<'static>>>,
+}
+
+impl MLX90393Spi {
+    pub fn new(spi: SPI2, sclk: AnyIOPin, sdo: AnyIOPin, sdi: AnyIOPin, cs: AnyIOPin) -> Result<Self, MLX90393Error> {
+        let driver = SpiDriver::new(spi, sclk, sdo, Some(sdi), &config::DriverConfig::new())?;
+        let config = config::Config::new().baudrate(SPI_BAUDRATE_MHZ.MHz().into()).data_mode(config::MODE_3);
+        let spi = SpiDeviceDriver::new(driver, Some(cs), &config)?;
+
+        Ok(Self { interface: SpiInterface::new(spi) })
+    }
+}
+
+impl MLX90393Interface for MLX90393Spi {
+    type Error = EspError;
+
+    fn transfer(&mut self, tx_buf: &[u8], rx_buf: &mut [u8]) -> Result<(), MLX90393Error> {
+        self.interface.transfer(tx_buf, rx_buf).map_err(|e| e.map_bus(|e| e.cause()))
+    }
+}
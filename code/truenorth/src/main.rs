@@ -3,7 +3,10 @@
 pub mod motor;
 pub mod smartvar;
 pub mod magsensor;
-pub mod math;
+pub mod accelerometer;
+pub mod calibration_blob;
+pub mod console;
+pub mod i2c_bus;
 
 use crate::motor::Motor;
 use crate::smartvar::SmartVar;
@@ -21,31 +24,232 @@ use esp32_nimble::utilities::BleUuid;
 use esp32_nimble::NimbleProperties;
 use esp32_nimble::{BLEDevice, BLEAdvertisementData, BLECharacteristic, enums::{ConnMode, DiscMode, AuthReq, SecurityIOCap}};
 use esp_idf_svc::hal::prelude::*;
+use esp_idf_svc::hal::gpio::AnyIOPin;
+use esp_idf_svc::hal::i2c::I2cConfig;
 
+use accelerometer::adxl345::ADXL345;
+use calibration_blob::export_profile;
+use compass::blob::CalibrationBlob;
+use compass::calibration::{CalibrationMode, CalibrationStatus};
+use console::{encode_hex, setup_console, ConsoleCommand};
+use i2c_bus::I2cBus;
 use magsensor::mlx90393::MLX90393Config;
+use magsensor::mlx90393_transport::{scan_mlx90393, MLX90393Bus};
 use magsensor::mlx90393::MLX90393;
-use magsensor::{MagSensor, MagSensorEvent};
+use magsensor::{MagSensor, MagSensorEvent, SelfTestStatus};
 
 thread_local! {
     #[allow(clippy::thread_local_initializer_can_be_made_const)]
     static TAG_NAMESPACE:RefCell<&'static str> =  RefCell::new("truenorth");
-    static TAG_DECLINATION:RefCell<&'static str> =  RefCell::new("declination");
+    static TAG_DECLINATION:RefCell<&'static str> =  RefCell::new("declination_deg");
     static TAG_MAX_X:RefCell<&'static str> =  RefCell::new("max_x");
     static TAG_MAX_Y:RefCell<&'static str> =  RefCell::new("max_y");
     static TAG_MAX_Z:RefCell<&'static str> =  RefCell::new("max_z");
     static TAG_MIN_X:RefCell<&'static str> =  RefCell::new("min_x");
     static TAG_MIN_Y:RefCell<&'static str> =  RefCell::new("min_y");
     static TAG_MIN_Z:RefCell<&'static str> =  RefCell::new("min_z");
+    static TAG_CALIBRATION_MODE:RefCell<&'static str> =  RefCell::new("cal_mode");
+    static TAG_OFFSET:RefCell<&'static str> =  RefCell::new("offset");
+    static TAG_SOFT_IRON:RefCell<&'static str> =  RefCell::new("soft_iron");
+    static TAG_AUTO_CALIBRATION:RefCell<&'static str> =  RefCell::new("auto_cal");
+    static TAG_ACTIVE_PROFILE:RefCell<&'static str> =  RefCell::new("profile");
+    static TAG_PROFILE_NAME:RefCell<&'static str> =  RefCell::new("name");
+    static TAG_PROFILE_NAMESPACE:RefCell<&'static str> =  RefCell::new("tn_profile");
+    static TAG_MOUNTING_OFFSET:RefCell<&'static str> =  RefCell::new("mount_offset");
+    static TAG_DEVIATION:RefCell<&'static str> =  RefCell::new("deviation");
+    static TAG_WAKEUP_THRESHOLD_XY:RefCell<&'static str> =  RefCell::new("woc_xy");
+    static TAG_WAKEUP_THRESHOLD_Z:RefCell<&'static str> =  RefCell::new("woc_z");
+    static TAG_WAKEUP_THRESHOLD_T:RefCell<&'static str> =  RefCell::new("woc_t");
+    static TAG_ONCHIP_OFFSETS:RefCell<&'static str> =  RefCell::new("onchip_offsets");
+    static TAG_AUTO_RANGE:RefCell<&'static str> =  RefCell::new("auto_range");
 }
 
-pub struct TrueNorthParameters {
-    pub declination: Arc<Mutex<SmartVar<i32>>>,
+// Number of calibration profiles. Profile 0 lives in the main namespace so a calibration
+// stored before profiles existed is loaded as the first profile.
+pub const PROFILE_COUNT: u8 = 4;
+
+// Upper bound for a calibration, it usually ends earlier once the coverage target is reached.
+const CALIBRATION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// Calibration data of one mounting location, persisted in its own NVS namespace.
+pub struct CalibrationProfile {
+    pub name: Arc<Mutex<SmartVar<String>>>,
     pub max_x: Arc<Mutex<SmartVar<f32>>>,
     pub max_y: Arc<Mutex<SmartVar<f32>>>,
     pub max_z: Arc<Mutex<SmartVar<f32>>>,
     pub min_x: Arc<Mutex<SmartVar<f32>>>,
     pub min_y: Arc<Mutex<SmartVar<f32>>>,
-    pub min_z: Arc<Mutex<SmartVar<f32>>>
+    pub min_z: Arc<Mutex<SmartVar<f32>>>,
+    // Ellipsoid fit: hard-iron offset (x, y, z) and row-major 3x3 soft-iron matrix.
+    // Empty until a successful fit.
+    pub offset: Arc<Mutex<SmartVar<Vec<f32>>>>,
+    pub soft_iron: Arc<Mutex<SmartVar<Vec<f32>>>>,
+    // Lubber-line offset in degrees added to the sensor heading.
+    pub mounting_offset: Arc<Mutex<SmartVar<f32>>>,
+    // Deviation card, see compass::deviation. Empty until the first entry is recorded.
+    pub deviation: Arc<Mutex<SmartVar<Vec<f32>>>>,
+}
+
+impl CalibrationProfile {
+    pub fn new(name: String) -> Self {
+        Self {
+            name: SmartVar::new(name),
+            max_x: SmartVar::new(f32::MIN), //0xFFFF7FFF
+            max_y: SmartVar::new(f32::MIN), //0xFFFF7FFF
+            max_z: SmartVar::new(f32::MIN), //0xFFFF7FFF
+            min_x: SmartVar::new(f32::MAX), //0xFFFF7F7F
+            min_y: SmartVar::new(f32::MAX), //0xFFFF7F7F
+            min_z: SmartVar::new(f32::MAX),
+            offset: SmartVar::new(Vec::new()),
+            soft_iron: SmartVar::new(Vec::new()),
+            mounting_offset: SmartVar::new(0.0),
+            deviation: SmartVar::new(Vec::new()),
+        }
+    }
+
+    pub fn add_endables(&self, endable: &mut EndableHandler) {
+        endable.add(self.name.clone());
+        endable.add(self.max_x.clone());
+        endable.add(self.max_y.clone());
+        endable.add(self.max_z.clone());
+        endable.add(self.min_x.clone());
+        endable.add(self.min_y.clone());
+        endable.add(self.min_z.clone());
+        endable.add(self.offset.clone());
+        endable.add(self.soft_iron.clone());
+        endable.add(self.mounting_offset.clone());
+        endable.add(self.deviation.clone());
+    }
+
+    pub fn setup_storage(&self, namespace: &str) {
+        if let Err(err) = self.name.lock().unwrap().setup_storage(namespace.to_string(), TAG_PROFILE_NAME.with_borrow(|tag| tag.to_string())) {
+            log::error!("Error setting up {} name storage: {}", namespace, err);
+        }
+
+        for (var, tag) in [
+            (&self.max_x, &TAG_MAX_X),
+            (&self.max_y, &TAG_MAX_Y),
+            (&self.max_z, &TAG_MAX_Z),
+            (&self.min_x, &TAG_MIN_X),
+            (&self.min_y, &TAG_MIN_Y),
+            (&self.min_z, &TAG_MIN_Z),
+        ] {
+            let tag = tag.with_borrow(|tag| tag.to_string());
+            if let Err(err) = var.lock().unwrap().setup_storage(namespace.to_string(), tag.clone()) {
+                log::error!("Error setting up {} {} storage: {}", namespace, tag, err);
+            }
+        }
+
+        if let Err(err) = self.offset.lock().unwrap().setup_storage(namespace.to_string(), TAG_OFFSET.with_borrow(|tag| tag.to_string())) {
+            log::error!("Error setting up {} offset storage: {}", namespace, err);
+        }
+
+        if let Err(err) = self.soft_iron.lock().unwrap().setup_storage(namespace.to_string(), TAG_SOFT_IRON.with_borrow(|tag| tag.to_string())) {
+            log::error!("Error setting up {} soft_iron storage: {}", namespace, err);
+        }
+
+        if let Err(err) = self.mounting_offset.lock().unwrap().setup_storage(namespace.to_string(), TAG_MOUNTING_OFFSET.with_borrow(|tag| tag.to_string())) {
+            log::error!("Error setting up {} mounting_offset storage: {}", namespace, err);
+        }
+
+        if let Err(err) = self.deviation.lock().unwrap().setup_storage(namespace.to_string(), TAG_DEVIATION.with_borrow(|tag| tag.to_string())) {
+            log::error!("Error setting up {} deviation storage: {}", namespace, err);
+        }
+    }
+
+    /// Forget the calibration, the name, mounting offset and deviation card are kept.
+    pub fn reset(&self) {
+        for (var, value) in [
+            (&self.max_x, f32::MIN),
+            (&self.max_y, f32::MIN),
+            (&self.max_z, f32::MIN),
+            (&self.min_x, f32::MAX),
+            (&self.min_y, f32::MAX),
+            (&self.min_z, f32::MAX),
+        ] {
+            if let Err(err) = var.lock().unwrap().set(value) {
+                log::error!("Error resetting calibration bounds: {}", err);
+            }
+        }
+        if let Err(err) = self.offset.lock().unwrap().set(Vec::new()) {
+            log::error!("Error setting offset: {}", err);
+        }
+        if let Err(err) = self.soft_iron.lock().unwrap().set(Vec::new()) {
+            log::error!("Error setting soft_iron: {}", err);
+        }
+    }
+}
+
+pub struct TrueNorthParameters {
+    pub declination: Arc<Mutex<SmartVar<f32>>>,
+    pub calibration_mode: Arc<Mutex<SmartVar<u8>>>,
+    pub profiles: Vec<CalibrationProfile>,
+    pub active_profile: Arc<Mutex<SmartVar<u8>>>,
+    // Background hard-iron estimation while measuring, 0 disabled, 1 enabled.
+    pub auto_calibration: Arc<Mutex<SmartVar<u8>>>,
+    // Wake-on-change thresholds, xy and z in µT, t in °C.
+    pub wakeup_threshold_xy: Arc<Mutex<SmartVar<f32>>>,
+    pub wakeup_threshold_z: Arc<Mutex<SmartVar<f32>>>,
+    pub wakeup_threshold_t: Arc<Mutex<SmartVar<f32>>>,
+    // Hard-iron offset programmed into the magnetometer, 0 disabled, 1 enabled. Needs the
+    // on-chip temperature compensation.
+    pub onchip_offsets: Arc<Mutex<SmartVar<u8>>>,
+    // Magnetometer gain stepped to keep the readings away from full scale, 0 disabled, 1 enabled.
+    pub auto_range: Arc<Mutex<SmartVar<u8>>>,
+    // Not persisted, reports the calibration progress over BLE.
+    pub calibration_status: Arc<Mutex<SmartVar<CalibrationStatus>>>,
+    // Not persisted, latest calibration export served over BLE.
+    pub calibration_blob: Arc<Mutex<SmartVar<Vec<u8>>>>,
+    // Not persisted, result of the last magnetometer self-test.
+    pub self_test_status: Arc<Mutex<SmartVar<SelfTestStatus>>>,
+}
+
+impl TrueNorthParameters {
+    pub fn new() -> Self {
+        Self {
+            declination: SmartVar::new(0.0),
+            calibration_mode: SmartVar::new(CalibrationMode::Ellipsoid.into()),
+            profiles: (0..PROFILE_COUNT).map(|index| CalibrationProfile::new(format!("Profile {}", index + 1))).collect(),
+            active_profile: SmartVar::new(0),
+            auto_calibration: SmartVar::new(0),
+            wakeup_threshold_xy: SmartVar::new(2.0),
+            wakeup_threshold_z: SmartVar::new(2.0),
+            wakeup_threshold_t: SmartVar::new(2.0),
+            onchip_offsets: SmartVar::new(0),
+            auto_range: SmartVar::new(0),
+            calibration_status: SmartVar::new(CalibrationStatus::Idle),
+            calibration_blob: SmartVar::new(Vec::new()),
+            self_test_status: SmartVar::new(SelfTestStatus::NotRun),
+        }
+    }
+
+    pub fn add_endables(&self, endable: &mut EndableHandler) {
+        endable.add(self.declination.clone());
+        endable.add(self.calibration_mode.clone());
+        for profile in self.profiles.iter() {
+            profile.add_endables(endable);
+        }
+        endable.add(self.active_profile.clone());
+        endable.add(self.auto_calibration.clone());
+        endable.add(self.wakeup_threshold_xy.clone());
+        endable.add(self.wakeup_threshold_z.clone());
+        endable.add(self.wakeup_threshold_t.clone());
+        endable.add(self.onchip_offsets.clone());
+        endable.add(self.auto_range.clone());
+        endable.add(self.calibration_status.clone());
+        endable.add(self.calibration_blob.clone());
+        endable.add(self.self_test_status.clone());
+    }
+
+    /// Index of the active profile, falls back to the first one if the stored index is out of range.
+    pub fn active_profile_index(&self) -> usize {
+        let index = *self.active_profile.lock().unwrap().get() as usize;
+        if index < self.profiles.len() { index } else { 0 }
+    }
+
+    pub fn profile(&self) -> &CalibrationProfile {
+        &self.profiles[self.active_profile_index()]
+    }
 }
 
 pub trait Endable {
@@ -88,23 +292,8 @@ fn main() {
 
     let mut endable = EndableHandler::new();
 
-    let parameters = Arc::new(TrueNorthParameters {
-        declination: SmartVar::new(0),
-        max_x: SmartVar::new(f32::MIN), //0xFFFF7FFF
-        max_y: SmartVar::new(f32::MIN), //0xFFFF7FFF
-        max_z: SmartVar::new(f32::MIN), //0xFFFF7FFF
-        min_x: SmartVar::new(f32::MAX), //0xFFFF7F7F
-        min_y: SmartVar::new(f32::MAX), //0xFFFF7F7F
-        min_z: SmartVar::new(f32::MAX)
-    });
-
-    endable.add(parameters.clone().declination.clone());
-    endable.add(parameters.clone().max_x.clone());
-    endable.add(parameters.clone().max_y.clone());
-    endable.add(parameters.clone().max_z.clone());
-    endable.add(parameters.clone().min_x.clone());
-    endable.add(parameters.clone().min_y.clone());
-    endable.add(parameters.clone().min_z.clone());
+    let parameters = Arc::new(TrueNorthParameters::new());
+    parameters.add_endables(&mut endable);
 
     #[allow(unused)]
 
@@ -122,9 +311,32 @@ fn main() {
 
     endable.add(motor.clone());
 
-    let config = MLX90393Config::new(parameters.clone(), 0x0C, pins.gpio8.into(), pins.gpio9.into(), pins.gpio1.into());
+    let i2c_config = I2cConfig::new().baudrate(100.kHz().into());
+    let i2c = match I2cBus::new(peripherals.i2c0, AnyIOPin::from(pins.gpio8), AnyIOPin::from(pins.gpio9), &i2c_config) {
+        Ok(i2c) => i2c,
+        Err(error) => {
+            log::error!("Error setting up i2c: {}", error);
+            halt_system(&mut endable);
+            return;
+        }
+    };
+
+    // Up to two sensors share the bus, each with its own INT line. The first one found
+    // drives the compass.
+    let addresses = scan_mlx90393(&i2c);
+    log::info!("MLX90393 found at {:02x?}", addresses);
+    let Some(&address) = addresses.first() else {
+        log::error!("No MLX90393 found");
+        halt_system(&mut endable);
+        return;
+    };
+
+    let bus = MLX90393Bus::I2c(i2c.device(address));
+    let config = MLX90393Config::new(parameters.clone(), bus, pins.gpio1.into());
+    // Outdoor units see large temperature swings, let the chip compensate them.
+    config.lock().unwrap().set_temperature_compensation(true);
     
-    let mag = match MLX90393::new(peripherals.i2c0, config) {
+    let mag = match MLX90393::new(config) {
         Ok(mag) => Arc::new(Mutex::new(mag)),
         Err(_error) => {
             halt_system(&mut endable);
@@ -134,14 +346,104 @@ fn main() {
 
     endable.add(mag.clone());
 
-    if let Err(err) = mag.lock().unwrap().add_handler(Box::new(|event| {
+    // The second sensor only reports its heading. It gets its own parameters, not persisted,
+    // so its calibration does not mix with the compass one.
+    if let Some(&address) = addresses.get(1) {
+        let reference_parameters = Arc::new(TrueNorthParameters::new());
+        reference_parameters.add_endables(&mut endable);
+
+        let bus = MLX90393Bus::I2c(i2c.device(address));
+        let config = MLX90393Config::new(reference_parameters, bus, pins.gpio2.into());
+        config.lock().unwrap().set_temperature_compensation(true);
+
+        match MLX90393::new(config) {
+            Ok(reference) => {
+                let reference = Arc::new(Mutex::new(reference));
+                endable.add(reference.clone());
+
+                let result = reference.lock().unwrap().add_handler(Box::new(move |event| {
+                    if let MagSensorEvent::HeadingChanged(heading) = event {
+                        log::debug!("MLX90393 {:02x}: heading {:.1}", address, heading.magnetic);
+                    }
+                }));
+                if let Err(err) = result {
+                    log::error!("Error adding handler: {}", err);
+                }
+
+                let result = reference.lock().unwrap().start();
+                if let Err(err) = result {
+                    log::error!("Error starting MLX90393 at {:02x}: {}", address, err);
+                }
+            }
+            Err(err) => log::error!("Error setting up MLX90393 at {:02x}: {}", address, err),
+        }
+    }
+
+    // The accelerometer is optional, without it the heading is not tilt compensated.
+    match ADXL345::new(i2c.device(0x53)) {
+        Ok(accel) => {
+            let accel = Arc::new(Mutex::new(accel));
+            endable.add(accel.clone());
+            if let Err(err) = mag.lock().unwrap().set_accelerometer(accel) {
+                log::error!("Error setting accelerometer: {}", err);
+            }
+        }
+        Err(err) => log::warn!("Accelerometer not available: {}", err),
+    }
+
+    let calibration_status = parameters.calibration_status.clone();
+    let self_test_status = parameters.self_test_status.clone();
+
+    if let Err(err) = mag.lock().unwrap().add_handler(Box::new(move |event| {
         match event {
             MagSensorEvent::CalibratedChanged((max_x, min_x), (max_y, min_y), (max_z, min_z)) => {
                 log::debug!("Calibrated: {:?}, {:?}, {:?}", (max_x, min_x), (max_y, min_y), (max_z, min_z));
             }
+            MagSensorEvent::EllipsoidChanged(offset, soft_iron) => {
+                log::debug!("Ellipsoid: offset {:?}, soft iron {:?}", offset, soft_iron);
+            }
+            MagSensorEvent::CalibrationProgress(quality) => {
+                log::debug!("Calibration progress: {:?}", quality);
+                if let Err(err) = calibration_status.lock().unwrap().set(CalibrationStatus::Running(quality)) {
+                    log::error!("Error setting calibration status: {}", err);
+                }
+            }
+            MagSensorEvent::CalibrationFinished(quality) => {
+                log::info!("Calibration finished, score: {}, rejected samples: {}", quality.score, quality.rejected);
+                if let Err(err) = calibration_status.lock().unwrap().set(CalibrationStatus::Finished(quality)) {
+                    log::error!("Error setting calibration status: {}", err);
+                }
+            }
+            MagSensorEvent::CalibrationCancelled => {
+                log::info!("Calibration cancelled");
+                if let Err(err) = calibration_status.lock().unwrap().set(CalibrationStatus::Cancelled) {
+                    log::error!("Error setting calibration status: {}", err);
+                }
+            }
+            MagSensorEvent::HardIronUpdated(offset) => {
+                log::info!("Hard-iron offset updated: {:?}", offset);
+            }
             MagSensorEvent::HeadingChanged(heading) => {
-                log::debug!("Heading: {:?}", heading);
+                if heading.degraded {
+                    log::warn!("Heading from a degraded magnetometer: compass {:.1}, magnetic {:.1}, true {:.1}", heading.compass, heading.magnetic, heading.true_heading);
+                } else {
+                    log::debug!("Heading: compass {:.1}, magnetic {:.1}, true {:.1}", heading.compass, heading.magnetic, heading.true_heading);
+                }
             },
+            MagSensorEvent::TemperatureChanged(temperature) => {
+                log::debug!("Magnetometer temperature: {:.1} C", temperature);
+            }
+            MagSensorEvent::SelfTestFinished(result) => {
+                if !result.passed {
+                    log::error!("Magnetometer self-test failed, headings are not reliable");
+                }
+                if let Err(err) = self_test_status.lock().unwrap().set(result.into()) {
+                    log::error!("Error setting self-test status: {}", err);
+                }
+            }
+            MagSensorEvent::RangeChanged(gain, sensitivity) => {
+                log::info!("Magnetometer range changed: gain {}, {} uT/LSB", gain, sensitivity);
+            }
             MagSensorEvent::RawChanged(_reading) => {}
         }
     })) {
@@ -166,34 +468,62 @@ fn main() {
 
         This will ensure that all loaded values from NVS partition will be sended to all handlers.
     */
-    if let Err(err) = parameters.clone().declination.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_DECLINATION.take().to_string()) {
+    let namespace = TAG_NAMESPACE.with_borrow(|tag| tag.to_string());
+
+    if let Err(err) = parameters.clone().declination.lock().unwrap().setup_storage(namespace.clone(), TAG_DECLINATION.with_borrow(|tag| tag.to_string())) {
         log::error!("Error setting up declination storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().max_x.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MAX_X.take().to_string()) {
-        log::error!("Error setting up max_x storage: {}", err);
+    if let Err(err) = parameters.clone().calibration_mode.lock().unwrap().setup_storage(namespace.clone(), TAG_CALIBRATION_MODE.with_borrow(|tag| tag.to_string())) {
+        log::error!("Error setting up calibration_mode storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().max_y.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MAX_Y.take().to_string()) {
-        log::error!("Error setting up max_y storage: {}", err);
+    for (index, profile) in parameters.profiles.iter().enumerate() {
+        if index == 0 {
+            profile.setup_storage(&namespace);
+        } else {
+            profile.setup_storage(&format!("{}{}", TAG_PROFILE_NAMESPACE.with_borrow(|tag| tag.to_string()), index));
+        }
+    }
+
+    if let Err(err) = parameters.clone().active_profile.lock().unwrap().setup_storage(namespace.clone(), TAG_ACTIVE_PROFILE.with_borrow(|tag| tag.to_string())) {
+        log::error!("Error setting up active_profile storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().max_z.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MAX_Z.take().to_string()) {
-        log::error!("Error setting up max_z storage: {}", err);
+    if let Err(err) = parameters.clone().auto_calibration.lock().unwrap().setup_storage(namespace.clone(), TAG_AUTO_CALIBRATION.with_borrow(|tag| tag.to_string())) {
+        log::error!("Error setting up auto_calibration storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().min_x.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MIN_X.take().to_string()) {
-        log::error!("Error setting up min_x storage: {}", err);
+    if let Err(err) = parameters.clone().onchip_offsets.lock().unwrap().setup_storage(namespace.clone(), TAG_ONCHIP_OFFSETS.with_borrow(|tag| tag.to_string())) {
+        log::error!("Error setting up onchip_offsets storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().min_y.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MIN_Y.take().to_string()) {
-        log::error!("Error setting up min_y storage: {}", err);
+    if let Err(err) = parameters.clone().auto_range.lock().unwrap().setup_storage(namespace.clone(), TAG_AUTO_RANGE.with_borrow(|tag| tag.to_string())) {
+        log::error!("Error setting up auto_range storage: {}", err);
     }
 
-    if let Err(err) = parameters.clone().min_z.lock().unwrap().setup_storage(TAG_NAMESPACE.take().to_string(), TAG_MIN_Z.take().to_string()) {
-        log::error!("Error setting up min_z storage: {}", err);
+    for (var, tag) in [
+        (&parameters.wakeup_threshold_xy, &TAG_WAKEUP_THRESHOLD_XY),
+        (&parameters.wakeup_threshold_z, &TAG_WAKEUP_THRESHOLD_Z),
+        (&parameters.wakeup_threshold_t, &TAG_WAKEUP_THRESHOLD_T),
+    ] {
+        let tag = tag.with_borrow(|tag| tag.to_string());
+        if let Err(err) = var.lock().unwrap().setup_storage(namespace.clone(), tag.clone()) {
+            log::error!("Error setting up {} storage: {}", tag, err);
+        }
     }
 
+    log::info!("Active calibration profile: {} ({})", parameters.active_profile_index(), parameters.profile().name.lock().unwrap().get());
+
+    // The serial console is optional, BLE covers everything it does.
+    let console_receiver = match setup_console() {
+        Ok(receiver) => Some(receiver),
+        Err(err) => {
+            log::error!("Error setting up console: {}", err);
+            None
+        }
+    };
+
     if let Err(err) = mag.lock().unwrap().start() {
         log::error!("Error starting mag: {}", err);
         halt_system(&mut endable);
@@ -207,31 +537,112 @@ fn main() {
         if let Ok(command) = bt_receiver.try_recv() {
             match command {
                 BluetoothCommand::ResetCalibrationData => {
-                    if let Err(err) = parameters.clone().max_x.lock().unwrap().set(f32::MIN) {
-                        log::error!("Error setting max_x: {}", err);
+                    parameters.profile().reset();
+                }
+                BluetoothCommand::Calibrate => {
+                    if let Err(err) = mag.lock().unwrap().calibrate(CALIBRATION_TIMEOUT) {
+                        log::error!("Error calibrating mag: {}", err);
                     }
-                    if let Err(err) = parameters.clone().max_y.lock().unwrap().set(f32::MIN) {
-                        log::error!("Error setting max_y: {}", err);
+                }
+                BluetoothCommand::CancelCalibration => {
+                    if let Err(err) = mag.lock().unwrap().cancel_calibration() {
+                        log::error!("Error cancelling calibration: {}", err);
                     }
-                    if let Err(err) = parameters.clone().max_z.lock().unwrap().set(f32::MIN) {
-                        log::error!("Error setting max_z: {}", err);
+                }
+                BluetoothCommand::EnableAutoCalibration => {
+                    if let Err(err) = parameters.clone().auto_calibration.lock().unwrap().set(1) {
+                        log::error!("Error setting auto_calibration: {}", err);
                     }
-                    if let Err(err) = parameters.clone().min_x.lock().unwrap().set(f32::MAX) {
-                        log::error!("Error setting min_x: {}", err);
+                }
+                BluetoothCommand::DisableAutoCalibration => {
+                    if let Err(err) = parameters.clone().auto_calibration.lock().unwrap().set(0) {
+                        log::error!("Error setting auto_calibration: {}", err);
                     }
-                    if let Err(err) = parameters.clone().min_y.lock().unwrap().set(f32::MAX) {
-                        log::error!("Error setting min_y: {}", err);
+                }
+                BluetoothCommand::SelectProfile(index) => {
+                    if index as usize >= parameters.profiles.len() {
+                        log::error!("Invalid profile: {}", index);
+                    } else {
+                        // Bound first, profile() locks active_profile again.
+                        let result = parameters.active_profile.lock().unwrap().set(index);
+                        match result {
+                            Ok(()) => log::info!("Calibration profile {} ({}) selected", index, parameters.profile().name.lock().unwrap().get()),
+                            Err(err) => log::error!("Error setting active_profile: {}", err),
+                        }
                     }
-                    if let Err(err) = parameters.clone().min_z.lock().unwrap().set(f32::MAX) {
-                        log::error!("Error setting min_z: {}", err);
+                }
+                BluetoothCommand::SetMountingOffset(offset) => {
+                    if !offset.is_finite() || offset.abs() > 180.0 {
+                        log::error!("Invalid mounting offset: {}", offset);
+                    } else if let Err(err) = parameters.profile().mounting_offset.lock().unwrap().set(offset) {
+                        log::error!("Error setting mounting_offset: {}", err);
                     }
                 }
-                BluetoothCommand::Calibrate => {
-                    if let Err(err) = mag.lock().unwrap().calibrate(std::time::Duration::from_secs(60)) {
-                        log::error!("Error calibrating mag: {}", err);
+                BluetoothCommand::RecordDeviation(true_heading) => {
+                    if !true_heading.is_finite() || !(0.0..=360.0).contains(&true_heading) {
+                        log::error!("Invalid heading: {}", true_heading);
+                    } else if let Err(err) = mag.lock().unwrap().record_deviation(true_heading) {
+                        log::error!("Error recording deviation: {}", err);
+                    }
+                }
+                BluetoothCommand::ClearDeviation => {
+                    if let Err(err) = parameters.profile().deviation.lock().unwrap().set(Vec::new()) {
+                        log::error!("Error setting deviation: {}", err);
+                    }
+                }
+                BluetoothCommand::ExportCalibration => {
+                    match export_calibration(&parameters, &*mag.lock().unwrap()) {
+                        Ok(blob) => {
+                            if let Err(err) = parameters.calibration_blob.lock().unwrap().set(blob) {
+                                log::error!("Error setting calibration_blob: {}", err);
+                            }
+                        }
+                        Err(err) => log::error!("Error exporting calibration: {}", err),
+                    }
+                }
+                BluetoothCommand::ImportCalibration(data) => {
+                    if let Err(err) = import_calibration(&parameters, &*mag.lock().unwrap(), &data) {
+                        log::error!("Error importing calibration: {}", err);
+                    }
+                }
+                BluetoothCommand::SelfTest => {
+                    if let Err(err) = mag.lock().unwrap().self_test() {
+                        log::error!("Error running self-test: {}", err);
+                    }
+                }
+                BluetoothCommand::EnableOnchipOffsets => {
+                    if let Err(err) = parameters.clone().onchip_offsets.lock().unwrap().set(1) {
+                        log::error!("Error setting onchip_offsets: {}", err);
                     }
-                    if let Err(err) = mag.lock().unwrap().start() {
-                        log::error!("Error starting mag: {}", err);
+                }
+                BluetoothCommand::DisableOnchipOffsets => {
+                    if let Err(err) = parameters.clone().onchip_offsets.lock().unwrap().set(0) {
+                        log::error!("Error setting onchip_offsets: {}", err);
+                    }
+                }
+                BluetoothCommand::StoreOffsets => {
+                    if let Err(err) = mag.lock().unwrap().store_offsets() {
+                        log::error!("Error storing on-chip offsets: {}", err);
+                    }
+                }
+                BluetoothCommand::EnableAutoRange => {
+                    if let Err(err) = parameters.clone().auto_range.lock().unwrap().set(1) {
+                        log::error!("Error setting auto_range: {}", err);
+                    }
+                }
+                BluetoothCommand::DisableAutoRange => {
+                    if let Err(err) = parameters.clone().auto_range.lock().unwrap().set(0) {
+                        log::error!("Error setting auto_range: {}", err);
+                    }
+                }
+                BluetoothCommand::RenameProfile(index, name) => {
+                    match parameters.profiles.get(index as usize) {
+                        Some(profile) => {
+                            if let Err(err) = profile.name.lock().unwrap().set(name) {
+                                log::error!("Error setting profile name: {}", err);
+                            }
+                        }
+                        None => log::error!("Invalid profile: {}", index),
                     }
                 }
                 _ => {
@@ -239,10 +650,40 @@ fn main() {
                 }
             }
         }
+
+        if let Some(Ok(command)) = console_receiver.as_ref().map(|receiver| receiver.try_recv()) {
+            match command {
+                ConsoleCommand::ExportCalibration => {
+                    match export_calibration(&parameters, &*mag.lock().unwrap()) {
+                        Ok(blob) => println!("calibration {}", encode_hex(&blob)),
+                        Err(err) => println!("error {}", err),
+                    }
+                }
+                ConsoleCommand::ImportCalibration(data) => {
+                    match import_calibration(&parameters, &*mag.lock().unwrap(), &data) {
+                        Ok(()) => println!("ok"),
+                        Err(err) => println!("error {}", err),
+                    }
+                }
+            }
+        }
         thread::sleep(std::time::Duration::from_millis(1000));
     }
 }
 
+fn export_calibration(parameters: &TrueNorthParameters, mag: &dyn MagSensor) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let blob = export_profile(parameters, mag.settings()?);
+    log::info!("Exporting calibration profile {}", blob.name);
+    Ok(blob.to_bytes())
+}
+
+fn import_calibration(parameters: &TrueNorthParameters, mag: &dyn MagSensor, data: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+    let blob = CalibrationBlob::from_bytes(data)?;
+    mag.import_calibration(&blob)?;
+    log::info!("Imported calibration profile {} into profile {}", blob.name, parameters.active_profile_index());
+    Ok(())
+}
+
 #[allow(unused)]
 fn halt_system(endable: &mut EndableHandler) {
     endable.end_all();
@@ -253,16 +694,63 @@ fn halt_system(endable: &mut EndableHandler) {
 }
 
 enum BluetoothCommand {
-    Unknown = 0x00,
-    ResetCalibrationData = 0x01,
-    Calibrate = 0x02,
+    Unknown,
+    ResetCalibrationData,
+    Calibrate,
+    CancelCalibration,
+    EnableAutoCalibration,
+    DisableAutoCalibration,
+    // Payload: profile index.
+    SelectProfile(u8),
+    // Payload: profile index followed by the UTF-8 name.
+    RenameProfile(u8, String),
+    // Payload: little-endian f32 in degrees.
+    SetMountingOffset(f32),
+    // Payload: little-endian f32, the known true heading in degrees.
+    RecordDeviation(f32),
+    ClearDeviation,
+    // Publishes the active profile on the calibration blob characteristic.
+    ExportCalibration,
+    // Sent by a write to the calibration blob characteristic.
+    ImportCalibration(Vec<u8>),
+    // Result on the self-test characteristic.
+    SelfTest,
+    EnableOnchipOffsets,
+    DisableOnchipOffsets,
+    // Keeps the on-chip offsets over a power cycle, writes the magnetometer memory.
+    StoreOffsets,
+    EnableAutoRange,
+    DisableAutoRange,
 }
 
-impl From<u8> for BluetoothCommand {
-    fn from(value: u8) -> Self {
-        match value {
-            0x01 => BluetoothCommand::ResetCalibrationData,
-            0x02 => BluetoothCommand::Calibrate,
+// Maximum profile name length in bytes.
+const PROFILE_NAME_MAX_LEN: usize = 32;
+
+impl From<&[u8]> for BluetoothCommand {
+    fn from(data: &[u8]) -> Self {
+        match data {
+            [0x01, ..] => BluetoothCommand::ResetCalibrationData,
+            [0x02, ..] => BluetoothCommand::Calibrate,
+            [0x03, ..] => BluetoothCommand::CancelCalibration,
+            [0x04, ..] => BluetoothCommand::EnableAutoCalibration,
+            [0x05, ..] => BluetoothCommand::DisableAutoCalibration,
+            [0x06, index, ..] => BluetoothCommand::SelectProfile(*index),
+            [0x07, index, name @ ..] if !name.is_empty() && name.len() <= PROFILE_NAME_MAX_LEN => {
+                match std::str::from_utf8(name) {
+                    Ok(name) => BluetoothCommand::RenameProfile(*index, name.to_string()),
+                    Err(_) => BluetoothCommand::Unknown,
+                }
+            }
+            [0x08, a, b, c, d, ..] => BluetoothCommand::SetMountingOffset(f32::from_le_bytes([*a, *b, *c, *d])),
+            [0x09, a, b, c, d, ..] => BluetoothCommand::RecordDeviation(f32::from_le_bytes([*a, *b, *c, *d])),
+            [0x0A, ..] => BluetoothCommand::ClearDeviation,
+            [0x0B, ..] => BluetoothCommand::ExportCalibration,
+            [0x0C, ..] => BluetoothCommand::SelfTest,
+            [0x0D, ..] => BluetoothCommand::EnableOnchipOffsets,
+            [0x0E, ..] => BluetoothCommand::DisableOnchipOffsets,
+            [0x0F, ..] => BluetoothCommand::StoreOffsets,
+            [0x10, ..] => BluetoothCommand::EnableAutoRange,
+            [0x11, ..] => BluetoothCommand::DisableAutoRange,
             _ => BluetoothCommand::Unknown,
         }
     }
@@ -273,11 +761,61 @@ impl From<BluetoothCommand> for u8 {
         match value {
             BluetoothCommand::ResetCalibrationData => 0x01,
             BluetoothCommand::Calibrate => 0x02,
+            BluetoothCommand::CancelCalibration => 0x03,
+            BluetoothCommand::EnableAutoCalibration => 0x04,
+            BluetoothCommand::DisableAutoCalibration => 0x05,
+            BluetoothCommand::SelectProfile(_) => 0x06,
+            BluetoothCommand::RenameProfile(_, _) => 0x07,
+            BluetoothCommand::SetMountingOffset(_) => 0x08,
+            BluetoothCommand::RecordDeviation(_) => 0x09,
+            BluetoothCommand::ClearDeviation => 0x0A,
+            BluetoothCommand::ExportCalibration => 0x0B,
+            BluetoothCommand::SelfTest => 0x0C,
+            BluetoothCommand::EnableOnchipOffsets => 0x0D,
+            BluetoothCommand::DisableOnchipOffsets => 0x0E,
+            BluetoothCommand::StoreOffsets => 0x0F,
+            BluetoothCommand::EnableAutoRange => 0x10,
+            BluetoothCommand::DisableAutoRange => 0x11,
             _ => 0x00,
         }
     }
 }
 
+// A notification carries at most 20 bytes with the default ATT MTU. Longer values are
+// notified in chunks led by the chunk index and count, a read still returns them whole.
+const BLE_NOTIFY_PAYLOAD: usize = 20;
+const BLE_CHUNK_HEADER: usize = 2;
+
+fn notify_chunked(characteristic: &mut BLECharacteristic, value: &[u8]) {
+    let chunks: Vec<&[u8]> = value.chunks(BLE_NOTIFY_PAYLOAD - BLE_CHUNK_HEADER).collect();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let mut frame = vec![index as u8, chunks.len() as u8];
+        frame.extend_from_slice(chunk);
+        characteristic.set_value(&frame).notify();
+    }
+    characteristic.set_value(value);
+}
+
+// Profile list characteristic value: the active profile index and the profile count,
+// then every name led by its length in bytes.
+fn profile_list(active: u8, names: &[String]) -> Vec<u8> {
+    let mut value = vec![active, names.len() as u8];
+    for name in names {
+        value.push(name.len() as u8);
+        value.extend_from_slice(name.as_bytes());
+    }
+    value
+}
+
+// Names of all profiles. A handler runs with its SmartVar locked, so the name being
+// changed is passed in instead of read back.
+fn profile_names(parameters: &TrueNorthParameters, changed: Option<(usize, &String)>) -> Vec<String> {
+    parameters.profiles.iter().enumerate().map(|(index, profile)| match changed {
+        Some((changed, name)) if changed == index => name.clone(),
+        _ => profile.name.lock().unwrap().get().clone(),
+    }).collect()
+}
+
 fn setup_bt_server(parameters: Arc<TrueNorthParameters>) -> Result<Receiver<BluetoothCommand>, Box<dyn std::error::Error>> {
 
     let (sender, receiver) = mpsc::channel::<BluetoothCommand>();
@@ -327,7 +865,17 @@ fn setup_bt_server(parameters: Arc<TrueNorthParameters>) -> Result<Receiver<Blue
             declination_characteristic.lock().on_write(move|value| {
                 let data = value.recv_data();
                 log::debug!("Correction received: {:?}", data);
-                if let Err(err) = declination_parameter.lock().unwrap().set(((data[3] as i32) << 24 | (data[2] as i32) << 16 | (data[1] as i32) << 8 | (data[0] as i32)) as i32) {
+                // Declination is sent as a little-endian f32 in degrees, east positive.
+                if data.len() < 4 {
+                    log::error!("Invalid declination size: {}", data.len());
+                    return;
+                }
+                let declination = f32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                if !declination.is_finite() || declination.abs() > 180.0 {
+                    log::error!("Invalid declination: {}", declination);
+                    return;
+                }
+                if let Err(err) = declination_parameter.lock().unwrap().set(declination) {
                     log::error!("Error setting declination: {}", err);
                 }
                 log::debug!("Correction set to: {}", declination_parameter.lock().unwrap().get());
@@ -335,6 +883,93 @@ fn setup_bt_server(parameters: Arc<TrueNorthParameters>) -> Result<Receiver<Blue
             });                
         }
 
+        let calibration_mode_characteristic = truenorth_service.lock().create_characteristic(
+            BleUuid::from_uuid16(0x1002),
+            NimbleProperties::READ | NimbleProperties::WRITE | NimbleProperties::NOTIFY);
+
+        {
+            let calibration_mode_parameter = parameters.calibration_mode.clone();
+
+            calibration_mode_characteristic.lock().on_write(move|value| {
+                let data = value.recv_data();
+                log::debug!("Calibration mode received: {:?}", data);
+                if data.is_empty() {
+                    log::error!("Invalid calibration mode size");
+                    return;
+                }
+                let mode = CalibrationMode::from(data[0]);
+                if let Err(err) = calibration_mode_parameter.lock().unwrap().set(mode.into()) {
+                    log::error!("Error setting calibration mode: {}", err);
+                }
+                log::debug!("Calibration mode set to: {:?}", mode);
+                value.notify();
+            });
+        }
+
+        let calibration_status_characteristic = truenorth_service.lock().create_characteristic(
+            BleUuid::from_uuid16(0x1003),
+            NimbleProperties::READ | NimbleProperties::NOTIFY);
+
+        calibration_status_characteristic.lock().set_value(&CalibrationStatus::Idle.to_bytes());
+
+        let calibration_blob_characteristic = truenorth_service.lock().create_characteristic(
+            BleUuid::from_uuid16(0x1004),
+            NimbleProperties::READ | NimbleProperties::WRITE | NimbleProperties::NOTIFY);
+
+        {
+            let sender = sender.clone();
+
+            calibration_blob_characteristic.lock().on_write(move|value| {
+                let data = value.recv_data();
+                log::debug!("Calibration blob received: {} bytes", data.len());
+                // Validated and applied by the main loop, which owns the sensor.
+                sender.send(BluetoothCommand::ImportCalibration(data.to_vec())).unwrap();
+            });
+        }
+
+        let self_test_characteristic = truenorth_service.lock().create_characteristic(
+            BleUuid::from_uuid16(0x1008),
+            NimbleProperties::READ | NimbleProperties::NOTIFY);
+
+        self_test_characteristic.lock().set_value(&SelfTestStatus::NotRun.to_bytes());
+
+        let profiles_characteristic = truenorth_service.lock().create_characteristic(
+            BleUuid::from_uuid16(0x1009),
+            NimbleProperties::READ | NimbleProperties::NOTIFY);
+
+        profiles_characteristic.lock().set_value(&profile_list(parameters.active_profile_index() as u8, &profile_names(&parameters, None)));
+
+        // Wake-on-change thresholds, little-endian f32 each.
+        let wakeup_threshold_characteristics = [
+            (0x1005, parameters.wakeup_threshold_xy.clone(), "xy"),
+            (0x1006, parameters.wakeup_threshold_z.clone(), "z"),
+            (0x1007, parameters.wakeup_threshold_t.clone(), "t"),
+        ].map(|(uuid, parameter, name)| {
+            let characteristic = truenorth_service.lock().create_characteristic(
+                BleUuid::from_uuid16(uuid),
+                NimbleProperties::READ | NimbleProperties::WRITE | NimbleProperties::NOTIFY);
+
+            characteristic.lock().on_write(move|value| {
+                let data = value.recv_data();
+                log::debug!("Wake-up threshold {} received: {:?}", name, data);
+                if data.len() < 4 {
+                    log::error!("Invalid wake-up threshold size: {}", data.len());
+                    return;
+                }
+                let threshold = f32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                if !threshold.is_finite() || threshold <= 0.0 {
+                    log::error!("Invalid wake-up threshold: {}", threshold);
+                    return;
+                }
+                if let Err(err) = parameter.lock().unwrap().set(threshold) {
+                    log::error!("Error setting wake-up threshold {}: {}", name, err);
+                }
+                value.notify();
+            });
+
+            characteristic
+        });
+
         let command_characteristic = truenorth_service.lock().create_characteristic(
             BleUuid::from_uuid16(0x1001),
             NimbleProperties::WRITE | NimbleProperties::NOTIFY);
@@ -342,14 +977,9 @@ fn setup_bt_server(parameters: Arc<TrueNorthParameters>) -> Result<Receiver<Blue
         command_characteristic.lock().on_write(move|_value| {
             let data = _value.recv_data();
             log::debug!("Command received: {:?}", data);
-            match BluetoothCommand::from(data[0]) {
-                BluetoothCommand::ResetCalibrationData => {
-                    sender.send(BluetoothCommand::ResetCalibrationData).unwrap();
-                }
-                BluetoothCommand::Calibrate => {
-                    sender.send(BluetoothCommand::Calibrate).unwrap();
-                }
-                _ => log::debug!("Unknown command"),
+            match BluetoothCommand::from(data) {
+                BluetoothCommand::Unknown => log::debug!("Unknown command"),
+                command => sender.send(command).unwrap(),
             }
         });
 
@@ -390,6 +1020,115 @@ fn setup_bt_server(parameters: Arc<TrueNorthParameters>) -> Result<Receiver<Blue
             
         }
 
+        {
+            let calibration_mode_parameter = parameters.calibration_mode.clone();
+
+            calibration_mode_parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                if let Some(dc) = dc {
+                    dc.lock().set_value(&[*value]).notify();
+                    log::debug!("BleCallback: Calibration mode SmartVar changed to: {}", value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([("characteristic".to_string(), Box::new(calibration_mode_characteristic.clone()) as Box<dyn Any + Send>)]));
+        }
+
+        {
+            let calibration_status_parameter = parameters.calibration_status.clone();
+
+            calibration_status_parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                if let Some(dc) = dc {
+                    dc.lock().set_value(&value.to_bytes()).notify();
+                    log::debug!("BleCallback: Calibration status SmartVar changed to: {:?}", value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([("characteristic".to_string(), Box::new(calibration_status_characteristic.clone()) as Box<dyn Any + Send>)]));
+        }
+
+        for (parameter, characteristic) in [
+            &parameters.wakeup_threshold_xy,
+            &parameters.wakeup_threshold_z,
+            &parameters.wakeup_threshold_t,
+        ].into_iter().zip(wakeup_threshold_characteristics.iter()) {
+            parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                if let Some(dc) = dc {
+                    dc.lock().set_value(value.to_le_bytes().to_vec().as_slice()).notify();
+                    log::debug!("BleCallback: Wake-up threshold SmartVar changed to: {}", value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([("characteristic".to_string(), Box::new(characteristic.clone()) as Box<dyn Any + Send>)]));
+        }
+
+        {
+            let calibration_blob_parameter = parameters.calibration_blob.clone();
+
+            calibration_blob_parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                if let Some(dc) = dc {
+                    notify_chunked(&mut dc.lock(), value);
+                    log::debug!("BleCallback: Calibration blob SmartVar changed, {} bytes", value.len());
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([("characteristic".to_string(), Box::new(calibration_blob_characteristic.clone()) as Box<dyn Any + Send>)]));
+        }
+
+        {
+            let self_test_parameter = parameters.self_test_status.clone();
+
+            self_test_parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                if let Some(dc) = dc {
+                    dc.lock().set_value(&value.to_bytes()).notify();
+                    log::debug!("BleCallback: Self-test SmartVar changed to: {:?}", value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([("characteristic".to_string(), Box::new(self_test_characteristic.clone()) as Box<dyn Any + Send>)]));
+        }
+
+        {
+            let active_profile_parameter = parameters.active_profile.clone();
+
+            active_profile_parameter.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                let tn = parameters.get("parameters").unwrap().downcast_ref::<Arc<TrueNorthParameters>>();
+                if let (Some(dc), Some(tn)) = (dc, tn) {
+                    notify_chunked(&mut dc.lock(), &profile_list(*value, &profile_names(tn, None)));
+                    log::debug!("BleCallback: Active profile SmartVar changed to: {}", value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([
+                ("characteristic".to_string(), Box::new(profiles_characteristic.clone()) as Box<dyn Any + Send>),
+                ("parameters".to_string(), Box::new(parameters.clone()) as Box<dyn Any + Send>),
+            ]));
+        }
+
+        for (index, profile) in parameters.profiles.iter().enumerate() {
+            profile.name.lock().unwrap().add_handler(Box::new(|value, parameters| {
+                let dc = parameters.get("characteristic").unwrap().downcast_ref::<Arc<esp32_nimble::utilities::mutex::Mutex<BLECharacteristic>>>();
+                let tn = parameters.get("parameters").unwrap().downcast_ref::<Arc<TrueNorthParameters>>();
+                let index = parameters.get("index").unwrap().downcast_ref::<usize>();
+                if let (Some(dc), Some(tn), Some(index)) = (dc, tn, index) {
+                    let active = *tn.active_profile.lock().unwrap().get();
+                    notify_chunked(&mut dc.lock(), &profile_list(active, &profile_names(tn, Some((*index, value)))));
+                    log::debug!("BleCallback: Profile {} name SmartVar changed to: {}", index, value);
+                } else {
+                    log::error!("BleCallback:Characteristic not found");
+                }
+            }), HashMap::from([
+                ("characteristic".to_string(), Box::new(profiles_characteristic.clone()) as Box<dyn Any + Send>),
+                ("parameters".to_string(), Box::new(parameters.clone()) as Box<dyn Any + Send>),
+                ("index".to_string(), Box::new(index) as Box<dyn Any + Send>),
+            ]));
+        }
+
         loop {
             thread::sleep(std::time::Duration::from_secs(1));
         }
@@ -1,4 +1,4 @@
-use std::{any::{self, Any}, collections::HashMap, sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex, MutexGuard}, thread};
+use std::{any::{self, Any}, collections::HashMap, sync::{mpsc::{self, Receiver, Sender}, Arc, Mutex, MutexGuard, Once, Weak}, thread};
 
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, EspNvsPartition, NvsDefault};
 
@@ -18,6 +18,46 @@ pub struct SmartVarHandlerPtr<T> {
     parameters: Arc<Mutex<HashMap<String, Box<dyn any::Any + Send>>>>,
 }
 
+// Every SmartVar is polled by one shared updater thread instead of a thread per variable.
+static UPDATER: Once = Once::new();
+static VARS: Mutex<Vec<Weak<Mutex<dyn Dispatch + Send>>>> = Mutex::new(Vec::new());
+
+// Type erased side of a SmartVar seen by the updater thread.
+trait Dispatch {
+    // Runs the handlers of the pending changes, returns false once the variable has ended.
+    fn dispatch(&mut self) -> bool;
+}
+
+fn register(var: Arc<Mutex<dyn Dispatch + Send>>) {
+    VARS.lock().unwrap().push(Arc::downgrade(&var));
+
+    UPDATER.call_once(|| {
+        if let Err(e) = thread::Builder::new().spawn(|| loop {
+            // Work on a copy so new variables can register while the handlers run.
+            let vars = VARS.lock().unwrap().clone();
+            let mut ended = Vec::new();
+
+            for var in vars.iter() {
+                let alive = match var.upgrade() {
+                    Some(var) => var.lock().unwrap().dispatch(),
+                    None => false,
+                };
+                if !alive {
+                    ended.push(var.clone());
+                }
+            }
+
+            if !ended.is_empty() {
+                VARS.lock().unwrap().retain(|var| !ended.iter().any(|end| end.ptr_eq(var)));
+            }
+
+            thread::sleep(std::time::Duration::from_millis(100));
+        }) {
+            log::error!("SmartVar:updater error: {}", e);
+        }
+    });
+}
+
 #[allow(dead_code)]
 pub struct SmartVar<T: Send> {
     namespace: Option<String>,
@@ -34,51 +74,11 @@ impl<T: Clone +Send + 'static> SmartVar<T> {
         let (tx_end, rx_end) = mpsc::channel::<bool>(); 
         let me = Arc::new(Mutex::new(Self { namespace: Option::None, storage_name: Option::None, value, handlers: Vec::new(), channel: (tx, rx), end_channel: (tx_end, rx_end) }));
         
-        // Lock the mutex and call setup
-        {
-            let mut smart_var = me.lock().unwrap();
-            smart_var.setup(me.clone());
-        }
+        register(me.clone());
         
         me
     }
 
-    fn setup(&mut self, me: Arc<Mutex<Self>>) {
-        let me_shared = me.clone();
-        if let Err(e) = thread::Builder::new().spawn(move || {
-            'main_loop: loop {
-
-                {
-                    let mut lock_me = me_shared.lock().unwrap();
-
-                    if let Ok(end) = lock_me.end_channel.1.try_recv() {
-                        if end {
-                            break 'main_loop;
-                        }
-                    }
-
-                    if let Ok(event) = lock_me.channel.1.try_recv() {
-                        match event {
-                            SmartVarEvent::Changed(value) => {
-                                //log::debug!("SmartVar:Changed");
-                                for handler in lock_me.handlers.iter_mut() {
-                                    handler.handler.lock().unwrap()(&value, handler.parameters.lock().unwrap());
-                                }                    
-                            }
-                        }
-                    }
-                }
-
-                thread::sleep(std::time::Duration::from_millis(100));
-            }
-
-            log::info!("SmartVar:updater thread ended");
-
-        }) {
-            log::error!("SmartVar:updater error: {}", e);
-        }
-    }
-
     pub fn setup_storage(&mut self, namespace: String, storage_name: String) -> Result<(), Box<dyn std::error::Error>> {
         self.namespace = Option::Some(namespace);
         self.storage_name = Option::Some(storage_name);
@@ -182,12 +182,25 @@ impl<T: Clone +Send + 'static> SmartVar<T> {
                     *s = f32::from_le_bytes(value.try_into().unwrap());
                 }
             }
+        } else if std::any::TypeId::of::<T>() == std::any::TypeId::of::<Vec<f32>>() {
+            let size = nvs.lock().unwrap().blob_len(self.storage_name.as_ref().unwrap())?;
+            if let Some(size) = size {
+                let mut buffer = vec![0u8; size];
+                let value = nvs.lock().unwrap().get_raw(self.storage_name.as_ref().unwrap(), &mut buffer)?;
+                if let Some(value) = value {
+                    if let Some(s) = (&mut self.value as &mut dyn Any).downcast_mut::<Vec<f32>>() {
+                        *s = value.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect();
+                    }
+                }
+            }
         } else if std::any::TypeId::of::<T>() == std::any::TypeId::of::<String>() {
-            if let Ok(size) = nvs.lock().unwrap().str_len(self.storage_name.as_ref().unwrap()) {
+            // One guard for both calls, a second lock() inside the if let would never return.
+            let nvs = nvs.lock().unwrap();
+            if let Ok(size) = nvs.str_len(self.storage_name.as_ref().unwrap()) {
                 if let Some(size) = size {
                     log::debug!("SmartVar: load string of size: {}", size);
                     let mut buffer = vec![0u8; size];
-                    let ret = nvs.lock().unwrap().get_str(self.storage_name.as_ref().unwrap(), &mut buffer);
+                    let ret = nvs.get_str(self.storage_name.as_ref().unwrap(), &mut buffer);
                     if let Ok(value) = ret {
                         if let Some(value) = value {
                             if let Some(s) = (&mut self.value as &mut dyn Any).downcast_mut::<String>() {
@@ -263,6 +276,13 @@ impl<T: Clone +Send + 'static> SmartVar<T> {
                     return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("SmartVar: Error saving storage value: {}", err))));
                 }
             }
+        } else if std::any::TypeId::of::<T>() == std::any::TypeId::of::<Vec<f32>>() {
+            if let Some(s) = (&mut self.value as &mut dyn Any).downcast_mut::<Vec<f32>>() {
+                let buffer: Vec<u8> = s.iter().flat_map(|value| value.to_le_bytes()).collect();
+                if let Err(err) = nvs.lock().unwrap().set_raw(self.storage_name.as_ref().unwrap(), &buffer) {
+                    return Err(Box::new(std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("SmartVar: Error saving storage value: {}", err))));
+                }
+            }
         } else if std::any::TypeId::of::<T>() == std::any::TypeId::of::<String>() {
             if let Some(s) = (&mut self.value as &mut dyn Any).downcast_mut::<String>() {
                 if let Err(err) = nvs.lock().unwrap().set_str(self.storage_name.as_ref().unwrap(), s) {
@@ -298,6 +318,26 @@ impl<T: Clone +Send + 'static> SmartVar<T> {
     }
 }
 
+impl<T: Clone + Send + 'static> Dispatch for SmartVar<T> {
+    fn dispatch(&mut self) -> bool {
+        if let Ok(true) = self.end_channel.1.try_recv() {
+            return false;
+        }
+
+        while let Ok(event) = self.channel.1.try_recv() {
+            match event {
+                SmartVarEvent::Changed(value) => {
+                    for handler in self.handlers.iter_mut() {
+                        handler.handler.lock().unwrap()(&value, handler.parameters.lock().unwrap());
+                    }
+                }
+            }
+        }
+
+        true
+    }
+}
+
 impl<T: Clone +Send + 'static> Endable for SmartVar<T> {
     fn end(&self) {
         self.end_channel.0.send(true).unwrap();